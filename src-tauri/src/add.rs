@@ -1,141 +1,1361 @@
 #![feature(file_buffered)]
-use crate::appstate::AppState;
-use crate::output::Output;
-use crate::db::PGConnection;
-use crate::gdal_utils::{generic_to_postgis_layer, generic_to_gpkg};
-use postgres::{Client, NoTls};
-use std::collections::HashMap;
+use crate::appstate::{AppState, Database};
+use crate::command::{ArgError, ArgKind, ArgSpec, Arguments, Command, CommandSchema};
+use crate::options::{OptionKind, OptionSpec, Options};
+use crate::output::{ErrorCode, Output, OutputResult};
+use crate::db::{PGConnection, POOL_SIZE};
+use crate::events::EventSink;
+use crate::gdal_utils::{
+    encoding_for_label, generic_to_gpkg, generic_to_postgis_layer, plan_columns,
+    plan_geometry_tables, postgis_layer_to_gpkg, raster_srid, raster_to_postgis,
+    shapefile_encoding, GeometryPlan, GeometryTable, LayerSrs, WriteMode,
+};
+use crate::metadata::{find_sidecar, read_metadata, store_metadata};
+use crate::registry::Context;
+use encoding_rs::Encoding;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{self, StreamExt};
 use std::fs;
-use std::path::Path;
-use tauri::{Emitter, State};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::runtime::Handle;
 use tokio::sync::Mutex;
-use gdal::Dataset;
+use tokio_postgres::Client;
+use gdal::{Dataset, DatasetOptions, GdalOpenFlags};
 use gdal::vector::LayerAccess;
 
+/// How the file given to `add` is read.
+#[derive(Clone, Copy, PartialEq)]
+enum Source {
+    /// Any vector dataset GDAL can open.
+    Layer,
+    /// Delimited text with coordinates in X/Y or WKT columns.
+    Csv,
+    /// Delimited text without geometries, imported as a plain table.
+    Table,
+}
+
+/// Opens a delimited text file with the GDAL CSV driver, which guesses the type of each
+/// column from a sample of the file instead of reading everything as text.
+fn open_delimited(path: &str, source: Source, options: &Options) -> Result<Dataset, String> {
+    let mut open_options = vec![
+        "AUTODETECT_TYPE=YES".to_string(),
+        format!("AUTODETECT_SIZE_LIMIT={}", options.integer("sample").unwrap_or(1000000)),
+        "EMPTY_STRING_AS_NULL=YES".to_string(),
+    ];
+    if let Some(separator) = options.text("separator") {
+        open_options.push(format!("SEPARATOR={}", separator.to_uppercase()));
+    }
+    if source == Source::Csv {
+        match (options.text("x"), options.text("y"), options.text("wkt")) {
+            (Some(x), Some(y), None) => {
+                open_options.push(format!("X_POSSIBLE_NAMES={}", x));
+                open_options.push(format!("Y_POSSIBLE_NAMES={}", y));
+            }
+            (None, None, Some(wkt)) => {
+                open_options.push(format!("GEOM_POSSIBLE_NAMES={}", wkt));
+                open_options.push("KEEP_GEOM_COLUMNS=NO".to_string());
+            }
+            _ => return Err("Give the coordinate columns with `x=` and `y=`, or `wkt=`.".into()),
+        }
+    }
+    let open_options = open_options.iter().map(String::as_str).collect::<Vec<&str>>();
+
+    // The driver only picks up .csv, .tsv and .psv files by itself
+    let extension = Path::new(path)
+        .extension()
+        .map(|val| val.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let path = match extension.as_str() {
+        "csv" | "tsv" | "psv" => path.to_string(),
+        _ => format!("CSV:{}", path),
+    };
+
+    match Dataset::open_ex(
+        Path::new(&path),
+        DatasetOptions {
+            open_flags: GdalOpenFlags::GDAL_OF_VECTOR,
+            allowed_drivers: Some(&["CSV"]),
+            open_options: Some(&open_options),
+            ..Default::default()
+        },
+    ) {
+        Ok(val) => Ok(val),
+        Err(_) => Err("File is not a valid delimited text file.".to_string()),
+    }
+}
+
 pub async fn add_layer(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
+    dataset_path: &str,
+    options: &Options,
+    state: &Mutex<AppState>,
+) -> Result<Output, ()> {
+    match is_archive(dataset_path) {
+        true => add_batch(dataset_path, options, state).await,
+        false => add_dataset(dataset_path, Source::Layer, options, state).await,
+    }
+}
+
+pub async fn add_folder(
+    dir: &str,
+    options: &Options,
+    state: &Mutex<AppState>,
+) -> Result<Output, ()> {
+    if !Path::new(dir).is_dir() {
+        let mut output = Output::default();
+        output.error(ErrorCode::Io, format!("ERROR! '{}' is not a folder.", dir));
+        return Ok(output);
+    }
+    add_batch(dir, options, state).await
+}
+
+pub async fn add_csv(
+    dataset_path: &str,
+    options: &Options,
+    state: &Mutex<AppState>,
+) -> Result<Output, ()> {
+    add_dataset(dataset_path, Source::Csv, options, state).await
+}
+
+pub async fn add_table(
+    dataset_path: &str,
+    options: &Options,
+    state: &Mutex<AppState>,
+) -> Result<Output, ()> {
+    add_dataset(dataset_path, Source::Table, options, state).await
+}
+
+/// Vector formats `add folder` picks up when it isn't given a `pattern=`.
+const VECTOR_EXTENSIONS: &[&str] = &["shp", "gpkg", "geojson", "json", "fgb", "kml", "gml", "tab"];
+
+fn is_archive(path: &str) -> bool {
+    path.to_lowercase().ends_with(".zip")
+}
+
+fn is_shapefile(path: &str) -> bool {
+    let path = path.to_lowercase();
+    path.ends_with(".shp") || path.ends_with(".dbf")
+}
+
+/// Number of undecodable rows listed by FID before the rest are only counted.
+const MAX_LISTED_ROWS: usize = 10;
+
+/// Most overviews `add raster` builds.
+const MAX_OVERVIEWS: i64 = 10;
+
+/// The datasets in a folder, or in a zip archive through GDAL's `/vsizip/`, that match
+/// `pattern`, or have the extension of a vector format if there's none. Archives are always
+/// searched all the way down.
+fn batch_files(path: &str, pattern: Option<&str>, recursive: bool) -> Result<Vec<String>, String> {
+    let pattern = match pattern {
+        Some(pattern) => match glob::Pattern::new(pattern) {
+            Ok(val) => Some(val),
+            Err(err) => return Err(format!("Invalid pattern '{}': {}", pattern, err)),
+        },
+        None => None,
+    };
+
+    let files = match is_archive(path) {
+        true => {
+            let archive = PathBuf::from(format!("/vsizip/{}", path));
+            match gdal::vsi::read_dir(&archive, true) {
+                Ok(entries) => entries
+                    .into_iter()
+                    .map(|entry| archive.join(entry))
+                    .collect::<Vec<PathBuf>>(),
+                Err(err) => return Err(format!("Couldn't read the archive '{}': {}", path, err)),
+            }
+        }
+        false => {
+            let search = match recursive {
+                true => Path::new(path).join("**").join("*"),
+                false => Path::new(path).join("*"),
+            };
+            match glob::glob(&search.to_string_lossy()) {
+                Ok(paths) => paths.filter_map(Result::ok).collect::<Vec<PathBuf>>(),
+                Err(err) => return Err(format!("Couldn't search '{}': {}", path, err)),
+            }
+        }
+    };
+
+    let mut files = files
+        .into_iter()
+        .filter(|file| match &pattern {
+            Some(pattern) => file
+                .file_name()
+                .is_some_and(|name| pattern.matches(&name.to_string_lossy())),
+            None => file.extension().is_some_and(|extension| {
+                VECTOR_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str())
+            }),
+        })
+        .map(|file| file.to_string_lossy().to_string())
+        .collect::<Vec<String>>();
+    files.sort();
+    Ok(files)
+}
+
+/// Imports every dataset in a folder or zip archive, several at a time, and sums up how each
+/// one went in a table. A dataset that fails doesn't stop the others.
+async fn add_batch(
+    path: &str,
+    options: &Options,
+    state: &Mutex<AppState>,
 ) -> Result<Output, ()> {
     let mut output = Output {
         errors: vec![],
         results: vec![],
     };
 
-    let state = state.lock().await;
-    let dataset_path = ast["args"][1].to_string();
-
-    let _ = state.app_handle.emit("loading", 10);
-    if state.pgsql_connection == PGConnection::default() {
-        output
-            .errors
-            .push("ERROR! You must connect to a database before adding a layer.".to_string());
-        let _ = state.app_handle.emit("loading", 0);
+    let files = match batch_files(path, options.text("pattern"), options.boolean("recursive")) {
+        Ok(val) => val,
+        Err(err) => {
+            output.error(ErrorCode::Io, format!("ERROR! {}", err));
+            return Ok(output);
+        }
+    };
+    if files.is_empty() {
+        output.error(ErrorCode::Dataset, format!("ERROR! Found no datasets in '{}'.", path));
         return Ok(output);
     }
 
-    if ast["args"].len() == 1 {
-        output
-            .errors
-            .push("ERROR! You must provide a path to the layer you want to add.".to_string());
-        let _ = state.app_handle.emit("loading", 0);
+    let (events, database, connection) = {
+        let state = state.lock().await;
+        (state.events.clone(), state.database(), state.pgsql_connection.clone())
+    };
+    if connection == PGConnection::default() {
+        output.error(
+            ErrorCode::NotConnected,
+            "ERROR! You must connect to a database before adding a layer.",
+        );
         return Ok(output);
     }
+    events.emit("loading", 10);
+
+    // Each dataset is imported on a connection of the pool, off the async runtime since GDAL
+    // blocks. Imports hold theirs to the end, so half the pool is left for everything else
+    let parallel = (options.integer("parallel").unwrap_or(4).max(1) as usize).min(POOL_SIZE / 2);
+    let total = files.len();
+    let mut imports = stream::iter(files.iter().cloned())
+        .map(|file| {
+            // With several files, `name` is used as a prefix so the tables don't collide
+            let name = match (options.text("name"), total) {
+                (Some(name), 1) => Some(name.to_string()),
+                (Some(prefix), _) => Some(format!(
+                    "{}_{}",
+                    prefix,
+                    Path::new(&file)
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().to_string())
+                        .unwrap_or_default()
+                )),
+                (None, _) => None,
+            };
+            let options = options.clone();
+            let (connection, database) = (connection.clone(), database.clone());
+            let events = events.clone();
+            tokio::task::spawn_blocking(move || {
+                Handle::current().block_on(ingest_dataset(
+                    &file,
+                    Source::Layer,
+                    name.as_deref(),
+                    &options,
+                    &connection,
+                    &database,
+                    &events,
+                    &|_| (),
+                ))
+            })
+        })
+        .buffered(parallel);
 
-    if !fs::exists(dataset_path.clone()).unwrap() {
-        output
+    let mut rows = vec![];
+    let mut failed = 0;
+    for file in &files {
+        let (mut file_output, file_rows) = match imports.next().await {
+            Some(Ok(val)) => val,
+            _ => {
+                let mut file_output = Output::default();
+                file_output.error(ErrorCode::Dataset, "ERROR! The import stopped unexpectedly.");
+                (file_output, 0)
+            }
+        };
+        store_sidecar_metadata(file, &mut file_output, &database).await;
+        events.emit("loading", 10 + 80 * (rows.len() as u64 + 1) / total as u64);
+
+        let layers = file_output
+            .results
+            .iter()
+            .filter(|result| matches!(result, OutputResult::Layer { .. }))
+            .count();
+        let errors = file_output
             .errors
-            .push("ERROR! Path to layer does not exist on disk.".to_string());
-        let _ = state.app_handle.emit("loading", 0);
-        return Ok(output);
+            .iter()
+            .map(|diagnostic| diagnostic.message.clone())
+            .collect::<Vec<String>>();
+        if file_output.failed() {
+            failed += 1;
+        }
+
+        // Paths are shown relative to the folder or archive
+        let shown = file
+            .trim_start_matches("/vsizip/")
+            .trim_start_matches(path)
+            .trim_start_matches(['/', '\\']);
+        rows.push(vec![
+            serde_json::Value::from(shown),
+            serde_json::Value::from(match file_output.failed() {
+                true => "failed",
+                false => "ok",
+            }),
+            serde_json::Value::from(layers as u64),
+            serde_json::Value::from(file_rows),
+            serde_json::Value::from(errors.join(" ")),
+        ]);
+        output.results.extend(
+            file_output
+                .results
+                .into_iter()
+                .filter(|result| matches!(result, OutputResult::Layer { .. })),
+        );
     }
 
-    let pgsql_client = match Client::connect(&state.pgsql_connection.pg_string(), NoTls) {
-        Ok(val) => val,
+    output.results.push(OutputResult::Table {
+        name: "add".to_string(),
+        columns: ["file", "status", "layers", "rows", "messages"]
+            .iter()
+            .map(|column| column.to_string())
+            .collect(),
+        rows,
+    });
+    output.message(format!("Added {} of {} datasets.", total - failed, total));
+    if failed > 0 {
+        output.warning(
+            ErrorCode::Dataset,
+            format!(
+                "WARNING! {} of {} datasets failed. See the summary for why.",
+                failed, total
+            ),
+        );
+    }
+    events.emit("loading", 0);
+    Ok(output)
+}
+
+async fn add_dataset(
+    dataset_path: &str,
+    source: Source,
+    options: &Options,
+    state: &Mutex<AppState>,
+) -> Result<Output, ()> {
+    let (events, database, connection) = {
+        let state = state.lock().await;
+        (state.events.clone(), state.database(), state.pgsql_connection.clone())
+    };
+
+    // GDAL blocks, so the import runs off the async runtime like the ones of `add_batch`
+    events.emit("loading", 10);
+    let path = dataset_path.to_string();
+    let options = options.clone();
+    let import_events = events.clone();
+    let import_database = database.clone();
+    let import = tokio::task::spawn_blocking(move || {
+        Handle::current().block_on(ingest_dataset(
+            &path,
+            source,
+            options.text("name"),
+            &options,
+            &connection,
+            &import_database,
+            &import_events,
+            &|percent| import_events.emit("loading", percent),
+        ))
+    });
+    let mut output = match import.await {
+        Ok((output, _)) => output,
         Err(_) => {
+            let mut output = Output::default();
+            output.error(ErrorCode::Dataset, "ERROR! The import stopped unexpectedly.");
             output
-                .errors
-                .push("ERROR! Lost connection to database.".to_string());
-            let _ = state.app_handle.emit("loading", 0);
-            return Ok(output);
         }
     };
+    store_sidecar_metadata(dataset_path, &mut output, &database).await;
+    events.emit("loading", 0);
+    Ok(output)
+}
 
-    let dataset = match Dataset::open(Path::new(dataset_path.as_str())) {
+/// Stores the `.qmd` or ISO 19139 sidecar of `dataset_path`, if it has one, as the metadata
+/// of every table `output` says was made from the dataset.
+async fn store_sidecar_metadata(dataset_path: &str, output: &mut Output, database: &Database) {
+    let metadata = match find_sidecar(dataset_path) {
+        Some(sidecar) => match read_metadata(&sidecar) {
+            Ok(val) if !val.is_empty() => val,
+            Ok(_) => return,
+            Err(err) => {
+                output.warning(
+                    ErrorCode::Dataset,
+                    format!("WARNING! Ignored the metadata in '{}': {}", sidecar.display(), err),
+                );
+                return;
+            }
+        },
+        None => return,
+    };
+
+    let tables = output
+        .results
+        .iter()
+        .filter_map(|result| match result {
+            OutputResult::Layer { schema, name } => Some((schema.clone(), name.clone())),
+            _ => None,
+        })
+        .collect::<Vec<(String, String)>>();
+    if tables.is_empty() {
+        return;
+    }
+
+    let client = match database.client().await {
         Ok(val) => val,
-        Err(_) => {
-            output
-                .errors
-                .push("ERROR! File is not a valid dataset.".to_string());
-            let _ = state.app_handle.emit("loading", 0);
-            return Ok(output);
+        Err(err) => {
+            output.warning(
+                ErrorCode::Connection,
+                format!("WARNING! Couldn't store the metadata: {}", err),
+            );
+            return;
         }
     };
+    for (schema, name) in tables {
+        if let Err(err) = store_metadata(&client, &schema, &name, &metadata).await {
+            output.warning(ErrorCode::Query, format!("WARNING! {}", err));
+        }
+    }
+}
 
-    let mut name = match dataset.layer(0) {
-        Ok(val) => val.name(),
-        Err(_) => {
-            output
-                .errors
-                .push("ERROR! Dataset has no layers.".to_string());
-            let _ = state.app_handle.emit("loading", 0);
-            return Ok(output);
+/// Imports the layers of one dataset, returning the output and the number of rows written.
+/// `name` names the table, or prefixes the tables if the dataset has several layers.
+/// `loading` is called with the progress of the import, in percent. GDAL blocks, so it's
+/// run with [`Handle::block_on`] on a blocking thread rather than awaited on the runtime.
+#[allow(clippy::too_many_arguments)]
+async fn ingest_dataset(
+    dataset_path: &str,
+    source: Source,
+    name: Option<&str>,
+    options: &Options,
+    connection: &PGConnection,
+    database: &Database,
+    events: &Arc<dyn EventSink>,
+    loading: &dyn Fn(u64),
+) -> (Output, u64) {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
+
+    if *connection == PGConnection::default() {
+        output.error(
+            ErrorCode::NotConnected,
+            "ERROR! You must connect to a database before adding a layer.",
+        );
+        return (output, 0);
+    }
+
+    // Files inside archives only exist for GDAL, e.g. /vsizip/roads.zip/roads.shp
+    if !dataset_path.starts_with("/vsi") && !fs::exists(dataset_path).unwrap_or(false) {
+        output.error(ErrorCode::Io, "ERROR! Path to layer does not exist on disk.");
+        return (output, 0);
+    }
+
+    let mut pgsql_client = match database.client().await {
+        Ok(val) => val,
+        Err(err) => {
+            output.error(ErrorCode::Connection, format!("ERROR! {}", err));
+            return (output, 0);
         }
     };
 
-    name.make_ascii_lowercase();
+    let schema = options.text("schema").unwrap_or("public");
+    if let Err(err) = check_schema(&pgsql_client, schema).await {
+        output.error(ErrorCode::Query, format!("ERROR! {}", err));
+        return (output, 0);
+    }
 
-    let _ = fs::create_dir("/tmp/tigre");
-    let _ = state.app_handle.emit("loading", 85);
+    let mode = options.text("mode").unwrap_or("create");
+    let mode = match WriteMode::parse(mode, options.text("key")) {
+        Ok(val) => val,
+        Err(err) => {
+            output.error(ErrorCode::Parse, format!("ERROR! {}", err));
+            return (output, 0);
+        }
+    };
 
-    let _ = match generic_to_gpkg(dataset).await {
-        Ok(_) => (),
-        Err(_) => {
-            output
-                .errors
-                .push("ERROR! Failed to write layer to gpkg.".to_string());
-            let _ = state.app_handle.emit("loading", 0);
-            return Ok(output);
+    let encoding = match options.text("encoding") {
+        Some(label) => match encoding_for_label(label) {
+            Some(val) => Some(val),
+            None => {
+                output.error(ErrorCode::Parse, format!("ERROR! Unknown encoding '{}'.", label));
+                return (output, 0);
+            }
+        },
+        None => None,
+    };
+
+    // Shapefiles are opened without recoding, so their text is decoded here and rows with
+    // bytes that don't fit their encoding can be reported
+    let shapefile = source == Source::Layer && is_shapefile(dataset_path);
+    if encoding.is_some() && !shapefile {
+        output.warning(
+            ErrorCode::Args,
+            format!(
+                "WARNING! '{}' isn't a shapefile, so GDAL decodes its text and `encoding=` \
+                is ignored.",
+                dataset_path
+            ),
+        );
+    }
+    let opened = match source {
+        Source::Layer if shapefile => Dataset::open_ex(
+            Path::new(dataset_path),
+            DatasetOptions {
+                open_flags: GdalOpenFlags::GDAL_OF_VECTOR,
+                open_options: Some(&["ENCODING="]),
+                ..Default::default()
+            },
+        )
+        .map_err(|_| "File is not a valid dataset.".to_string()),
+        Source::Layer => Dataset::open(Path::new(dataset_path))
+            .map_err(|_| "File is not a valid dataset.".to_string()),
+        _ => open_delimited(dataset_path, source, options),
+    };
+    let dataset = match opened {
+        Ok(val) => val,
+        Err(err) => {
+            output.error(ErrorCode::Dataset, format!("ERROR! {}", err));
+            return (output, 0);
         }
     };
-    let _ = state.app_handle.emit("add-vector-layer", [name.clone(), "public".to_string()]);
 
-    generic_to_postgis_layer(
-        Dataset::open(Path::new(dataset_path.as_str())).unwrap(),  // TODO: I hate this. I want to use a reference, but I can't send a reference to a dataset between threads.
-        pgsql_client,
-        &name,
-    )
-    .await;
+    // The CSV driver quietly reads the file without geometries if the columns aren't there
+    let has_geometry = dataset
+        .layers()
+        .next()
+        .is_some_and(|layer| layer.defn().geom_fields().count() > 0);
+    if source == Source::Csv && !has_geometry {
+        let columns = match options.text("wkt") {
+            Some(wkt) => format!("'{}'", wkt),
+            None => format!(
+                "'{}' and '{}'",
+                options.text("x").unwrap_or_default(),
+                options.text("y").unwrap_or_default()
+            ),
+        };
+        output.error(
+            ErrorCode::Dataset,
+            format!("ERROR! Couldn't find the columns {} in '{}'.", columns, dataset_path),
+        );
+        return (output, 0);
+    }
 
-    output.results.push(format!("Done."));
-    let _ = state.app_handle.emit("loading", 0);
-    Ok(output)
+    let layer_names = dataset.layers().map(|layer| layer.name()).collect::<Vec<String>>();
+    if layer_names.is_empty() {
+        output.error(ErrorCode::Dataset, "ERROR! Dataset has no layers.");
+        return (output, 0);
+    }
+
+    let selected = match options.text("layer") {
+        Some(layer) => match select_layer(&layer_names, layer) {
+            Some(val) => vec![val],
+            None => {
+                output.error(
+                    ErrorCode::Dataset,
+                    format!(
+                        "ERROR! Dataset has no layer '{}'. Its layers are: {}.",
+                        layer,
+                        layer_names.join(", ")
+                    ),
+                );
+                return (output, 0);
+            }
+        },
+        None => (0..layer_names.len()).collect::<Vec<usize>>(),
+    };
+
+    let _ = fs::create_dir("/tmp/tigre");
+
+    let mut added = 0;
+    let mut rows = 0;
+    let mut recache = vec![];
+    for (i, mut layer) in dataset.layers().enumerate() {
+        if !selected.contains(&i) {
+            continue;
+        }
+
+        // With several layers, `name` is used as a prefix so the tables don't collide
+        let name = match (name, selected.len()) {
+            (Some(name), 1) => table_name(name),
+            (Some(prefix), _) => table_name(&format!("{}_{}", prefix, layer.name())),
+            (None, _) => table_name(&layer.name()),
+        };
+
+        let srs = match LayerSrs::new(&layer, options.integer("srid"), options.text("t_srs")) {
+            Ok(val) => val,
+            Err(err) => {
+                output.error(ErrorCode::Dataset, format!("ERROR! {}", err));
+                continue;
+            }
+        };
+        // Plain tables have no geometries to reproject, so their SRS doesn't matter
+        let spatial = source != Source::Table;
+        if spatial && srs.spatial_ref.is_none() {
+            output.warning(
+                ErrorCode::Dataset,
+                format!(
+                    "WARNING! Layer '{}' has no SRS, so it's stored with SRID 0. \
+                    Use `srid=` to set one.",
+                    layer.name()
+                ),
+            );
+        } else if spatial && srs.srid == 0 {
+            output.warning(
+                ErrorCode::Dataset,
+                format!(
+                    "WARNING! Couldn't find an EPSG code for the SRS of layer '{}', so it's \
+                    stored with SRID 0. Use `srid=` or `t_srs=` to set one.",
+                    layer.name()
+                ),
+            );
+        }
+
+        let encoding = match (encoding, shapefile) {
+            (Some(encoding), true) => encoding,
+            (None, true) => match shapefile_encoding(&layer) {
+                Some(label) => encoding_for_label(&label).unwrap_or_else(|| {
+                    output.warning(
+                        ErrorCode::Dataset,
+                        format!(
+                            "WARNING! Unknown encoding '{}' in the .cpg of layer '{}', so its \
+                            text is read as UTF-8. Use `encoding=` to set one.",
+                            label,
+                            layer.name()
+                        ),
+                    );
+                    encoding_rs::UTF_8
+                }),
+                // Like GDAL, shapefiles that don't say are read as ISO-8859-1, here as
+                // Windows-1252, which encoding_rs uses for that label as browsers do
+                None => encoding_rs::WINDOWS_1252,
+            },
+            (_, false) => encoding_rs::UTF_8,
+        };
+
+        let (columns, mut warnings) = plan_columns(&layer, encoding);
+        let plan = match spatial {
+            true => plan_geometry_tables(&mut layer, options.boolean("split")),
+            false => GeometryPlan {
+                tables: vec![GeometryTable::attributes_only(&layer)],
+                warnings: vec![],
+            },
+        };
+        warnings.extend(plan.warnings);
+        for warning in warnings {
+            output.warning(ErrorCode::Dataset, format!("WARNING! {}", warning));
+        }
+
+        // Each layer gets an equal share of the loading bar, filled as its rows are copied
+        let slot = selected.iter().position(|val| *val == i).unwrap();
+        let start = 10 + 80 * slot / selected.len();
+        let end = 10 + 80 * (slot + 1) / selected.len();
+
+        let mut failed = false;
+        for (k, table) in plan.tables.iter().enumerate() {
+            let name = match &table.suffix {
+                Some(suffix) => format!("{}_{}", name, suffix),
+                None => name.clone(),
+            };
+
+            let table_start = start + (end - start) * k / plan.tables.len();
+            let table_end = start + (end - start) * (k + 1) / plan.tables.len();
+            let mut on_progress = |copied: u64, total: u64| {
+                let share = (table_end - table_start) as u64 * copied / total.max(1);
+                loading(table_start as u64 + share);
+            };
+            let counts = match generic_to_postgis_layer(
+                &mut layer,
+                &mut pgsql_client,
+                schema,
+                &name,
+                &columns,
+                table,
+                &srs,
+                &mode,
+                &mut on_progress,
+            )
+            .await
+            {
+                Ok(val) => val,
+                Err(err) => {
+                    output.error(
+                        ErrorCode::Query,
+                        format!("ERROR! Couldn't add layer '{}': {}", layer.name(), err),
+                    );
+                    failed = true;
+                    continue;
+                }
+            };
+            rows += counts.inserted + counts.updated;
+            if !counts.undecodable.is_empty() {
+                output.warning(
+                    ErrorCode::Dataset,
+                    undecodable_warning(&layer.name(), encoding, &counts.undecodable),
+                );
+            }
+            match &mode {
+                WriteMode::Upsert(_) => output.message(format!(
+                    "Inserted {} and updated {} rows in {}.{}.",
+                    counts.inserted, counts.updated, schema, name
+                )),
+                WriteMode::Append => output.message(format!(
+                    "Inserted {} rows into {}.{}.",
+                    counts.inserted, schema, name
+                )),
+                _ if spatial => output.message(format!(
+                    "Copied {} rows into {}.{} (SRID {}).",
+                    counts.inserted, schema, name, srs.srid
+                )),
+                _ => output.message(format!(
+                    "Copied {} rows into {}.{}.",
+                    counts.inserted, schema, name
+                )),
+            }
+
+            // Appended and upserted tables hold more than the layer, so they're cached from
+            // PostGIS once the dataset is closed
+            match &mode {
+                WriteMode::Append | WriteMode::Upsert(_) if spatial => recache.push(name.clone()),
+                _ if spatial => {
+                    let cached =
+                        generic_to_gpkg(&mut layer, schema, &name, &columns, table, &srs);
+                    match cached {
+                        Ok(_) => {
+                            events.emit("add-vector-layer", [name.clone(), schema.to_string()])
+                        }
+                        Err(err) => output.warning(
+                            ErrorCode::Io,
+                            format!(
+                                "WARNING! Couldn't show {}.{} on the map: {}",
+                                schema, name, err
+                            ),
+                        ),
+                    }
+                }
+                _ => (),
+            }
+
+            output.results.push(OutputResult::Layer {
+                schema: schema.to_string(),
+                name,
+            });
+        }
+
+        if !failed {
+            added += 1;
+        }
+    }
+
+    for name in recache {
+        match postgis_layer_to_gpkg(&name, schema, connection.gdal_string()) {
+            Ok(_) => events.emit("add-vector-layer", [name, schema.to_string()]),
+            Err(err) => output.warning(
+                ErrorCode::Io,
+                format!(
+                    "WARNING! Couldn't show {}.{} on the map: {}",
+                    schema,
+                    name,
+                    connection.redact(&err)
+                ),
+            ),
+        }
+    }
+
+    match selected.len() {
+        1 if added == 1 => output.message("Done."),
+        total => output.message(format!("Added {} of {} layers.", added, total)),
+    }
+    (output, rows)
 }
 
-pub async fn add(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
+pub async fn add_raster(
+    dataset_path: &str,
+    options: &Options,
+    state: &Mutex<AppState>,
 ) -> Result<Output, ()> {
     let mut output = Output {
         errors: vec![],
         results: vec![],
     };
 
-    if ast["args"].is_empty() {
-        output
-            .errors
-            .push("ERROR! No arguments provided for command 'add'.".to_string())
-    } else {
-        match ast["args"][0] {
-            "layer" => {
-                let add_layer_output = add_layer(ast, state).await.unwrap();
-                output.errors.extend(add_layer_output.errors);
-                output.results.extend(add_layer_output.results);
-            }
-            &_ => output
-                .errors
-                .push("ERROR! Found unknown argument.".to_string()),
+    let (events, database, connection) = {
+        let state = state.lock().await;
+        (state.events.clone(), state.database(), state.pgsql_connection.clone())
+    };
+
+    events.emit("loading", 10);
+    if connection == PGConnection::default() {
+        output.error(
+            ErrorCode::NotConnected,
+            "ERROR! You must connect to a database before adding a raster.",
+        );
+        events.emit("loading", 0);
+        return Ok(output);
+    }
+
+    if !fs::exists(dataset_path).unwrap_or(false) {
+        output.error(ErrorCode::Io, "ERROR! Path to raster does not exist on disk.");
+        events.emit("loading", 0);
+        return Ok(output);
+    }
+
+    let tile_size = options.integer("tile_size").unwrap_or(256);
+    if !(16..=4096).contains(&tile_size) {
+        output.error(ErrorCode::Args, "ERROR! `tile_size` must be between 16 and 4096.");
+        events.emit("loading", 0);
+        return Ok(output);
+    }
+
+    // Each overview halves the resolution of the one before, so past 10 they'd be under a
+    // thousandth of it
+    let overviews = options.integer("overviews").unwrap_or(3);
+    if !(0..=MAX_OVERVIEWS).contains(&overviews) {
+        output.error(
+            ErrorCode::Args,
+            format!("ERROR! `overviews` must be between 0 and {}.", MAX_OVERVIEWS),
+        );
+        events.emit("loading", 0);
+        return Ok(output);
+    }
+    let overviews = (1..=overviews as u32)
+        .map(|level| 2usize.pow(level))
+        .collect::<Vec<usize>>();
+
+    // GDAL blocks, so the raster is imported off the async runtime
+    let dataset_path = dataset_path.to_string();
+    let options = options.clone();
+    let import_events = events.clone();
+    let import = tokio::task::spawn_blocking(move || {
+        Handle::current().block_on(ingest_raster(
+            &dataset_path,
+            &options,
+            tile_size as usize,
+            &overviews,
+            &database,
+            &import_events,
+        ))
+    });
+    match import.await {
+        Ok(val) => Ok(val),
+        Err(_) => {
+            output.error(ErrorCode::Dataset, "ERROR! The import stopped unexpectedly.");
+            events.emit("loading", 0);
+            Ok(output)
         }
     }
+}
 
-    Ok(output)
+/// Tiles a raster into a PostGIS table with `overviews`, for `add raster`. Like
+/// `ingest_dataset`, it's run on a blocking thread.
+async fn ingest_raster(
+    dataset_path: &str,
+    options: &Options,
+    tile_size: usize,
+    overviews: &[usize],
+    database: &Database,
+    events: &Arc<dyn EventSink>,
+) -> Output {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
+
+    let mut pgsql_client = match database.client().await {
+        Ok(val) => val,
+        Err(err) => {
+            output.error(ErrorCode::Connection, format!("ERROR! {}", err));
+            events.emit("loading", 0);
+            return output;
+        }
+    };
+
+    let schema = options.text("schema").unwrap_or("public");
+    if let Err(err) = check_schema(&pgsql_client, schema).await {
+        output.error(ErrorCode::Query, format!("ERROR! {}", err));
+        events.emit("loading", 0);
+        return output;
+    }
+
+    let dataset = match Dataset::open_ex(
+        Path::new(dataset_path),
+        DatasetOptions {
+            open_flags: GdalOpenFlags::GDAL_OF_RASTER,
+            ..Default::default()
+        },
+    ) {
+        Ok(val) => val,
+        Err(_) => {
+            output.error(ErrorCode::Dataset, "ERROR! File is not a valid raster.");
+            events.emit("loading", 0);
+            return output;
+        }
+    };
+
+    let name = match options.text("name") {
+        Some(name) => table_name(name),
+        None => table_name(
+            &Path::new(dataset_path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        ),
+    };
+
+    let srid = match options.integer("srid") {
+        Some(srid) => srid as i32,
+        None => raster_srid(&dataset),
+    };
+    if srid == 0 {
+        output.warning(
+            ErrorCode::Dataset,
+            "WARNING! Couldn't find an EPSG code for the raster, so it's stored with SRID 0 \
+            and can't be drawn on the map. Use `srid=` to set one.",
+        );
+    }
+
+    let mut on_progress = |copied: u64, total: u64| {
+        events.emit("loading", 10 + 80 * copied / total.max(1));
+    };
+    match raster_to_postgis(
+        &dataset,
+        &mut pgsql_client,
+        schema,
+        &name,
+        srid,
+        tile_size,
+        overviews,
+        &mut on_progress,
+    )
+    .await
+    {
+        Ok(tiles) => output.message(format!(
+            "Copied {} tiles into {}.{} (SRID {}), with {} overviews.",
+            tiles,
+            schema,
+            name,
+            srid,
+            overviews.len()
+        )),
+        Err(err) => {
+            output.error(ErrorCode::Query, format!("ERROR! Couldn't add raster: {}", err));
+            events.emit("loading", 0);
+            return output;
+        }
+    }
+
+    events.emit("add-raster-layer", [name.clone(), schema.to_string()]);
+    output.results.push(OutputResult::Layer {
+        schema: schema.to_string(),
+        name,
+    });
+    events.emit("loading", 0);
+    output
+}
+
+/// Warning for the rows of a layer with text that isn't valid in `encoding`, listing the
+/// first of their FIDs.
+fn undecodable_warning(layer: &str, encoding: &'static Encoding, fids: &[u64]) -> String {
+    let mut listed = fids
+        .iter()
+        .take(MAX_LISTED_ROWS)
+        .map(u64::to_string)
+        .collect::<Vec<String>>();
+    if fids.len() > MAX_LISTED_ROWS {
+        listed.push(format!("and {} more", fids.len() - MAX_LISTED_ROWS));
+    }
+    format!(
+        "WARNING! {} rows of layer '{}' had text that isn't valid {}, so the bytes that \
+        couldn't be decoded were replaced (FIDs {}). Use `encoding=` to read it as another \
+        encoding.",
+        fids.len(),
+        layer,
+        encoding.name(),
+        listed.join(", ")
+    )
+}
+
+/// Checks that the schema data is added to exists, since the tables are created in it.
+async fn check_schema(pgsql_client: &Client, schema: &str) -> Result<(), String> {
+    match pgsql_client.query_one(
+        "SELECT EXISTS (SELECT 1 FROM pg_namespace WHERE nspname = $1)",
+        &[&schema],
+    ).await {
+        Ok(row) if row.get::<usize, bool>(0) => Ok(()),
+        Ok(_) => Err(format!(
+            "Schema '{}' doesn't exist. Create it with `db schema create {}`.",
+            schema, schema
+        )),
+        Err(err) => Err(format!("Failed to look up schema '{}': {}", schema, err)),
+    }
+}
+
+/// Finds a layer by name, or else by its index in the dataset.
+fn select_layer(layer_names: &[String], layer: &str) -> Option<usize> {
+    match layer_names.iter().position(|name| name == layer) {
+        Some(val) => Some(val),
+        None => layer.parse::<usize>().ok().filter(|i| *i < layer_names.len()),
+    }
+}
+
+/// Turns a layer name into a lowercase table name, e.g. `My Places` into `my_places`.
+fn table_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '_',
+        })
+        .collect()
+}
+
+const LAYER_OPTION: OptionSpec = OptionSpec {
+    name: "layer",
+    kind: OptionKind::Text,
+    default: None,
+    description: "Name or index of the only layer to add. Defaults to every layer.",
+};
+
+const NAME_OPTION: OptionSpec = OptionSpec {
+    name: "name",
+    kind: OptionKind::Text,
+    default: None,
+    description:
+        "Name of the table to create, or a prefix for the tables if there are several. \
+        Defaults to the name of the source layer.",
+};
+
+const SRID_OPTION: OptionSpec = OptionSpec {
+    name: "srid",
+    kind: OptionKind::Integer,
+    default: None,
+    description: "EPSG code of the source, when it has no .prj or a wrong one.",
+};
+
+const T_SRS_OPTION: OptionSpec = OptionSpec {
+    name: "t_srs",
+    kind: OptionKind::Text,
+    default: None,
+    description: "SRS to reproject into, e.g. EPSG:3857. Defaults to the SRS of the source.",
+};
+
+const SPLIT_OPTION: OptionSpec = OptionSpec {
+    name: "split",
+    kind: OptionKind::Boolean,
+    default: Some("false"),
+    description:
+        "Write layers mixing geometry types to one table per type, e.g. <name>_point, \
+        instead of a generic geometry column.",
+};
+
+const SCHEMA_OPTION: OptionSpec = OptionSpec {
+    name: "schema",
+    kind: OptionKind::Text,
+    default: Some("public"),
+    description: "Schema to create the table in.",
+};
+
+const MODE_OPTION: OptionSpec = OptionSpec {
+    name: "mode",
+    kind: OptionKind::Text,
+    default: Some("create"),
+    description:
+        "What to do with the table: create it, append to it, overwrite it, or upsert into it \
+        by `key=`.",
+};
+
+const KEY_OPTION: OptionSpec = OptionSpec {
+    name: "key",
+    kind: OptionKind::Text,
+    default: None,
+    description: "Field matching features to the rows they update with `mode=upsert`.",
+};
+
+const PATTERN_OPTION: OptionSpec = OptionSpec {
+    name: "pattern",
+    kind: OptionKind::Text,
+    default: None,
+    description:
+        "Only import the files matching this pattern, e.g. *.shp. Defaults to every file \
+        in a vector format.",
+};
+
+const ENCODING_OPTION: OptionSpec = OptionSpec {
+    name: "encoding",
+    kind: OptionKind::Text,
+    default: None,
+    description:
+        "Encoding of the text attributes of shapefiles, e.g. UTF-8, CP1252 or ISO-8859-1. \
+        Defaults to their .cpg. GDAL decodes the text of other formats itself.",
+};
+
+const PARALLEL_OPTION: OptionSpec = OptionSpec {
+    name: "parallel",
+    kind: OptionKind::Integer,
+    default: Some("4"),
+    description: "Number of datasets imported at the same time, at most 4.",
+};
+
+const SEPARATOR_OPTION: OptionSpec = OptionSpec {
+    name: "separator",
+    kind: OptionKind::Text,
+    default: None,
+    description:
+        "Field separator: comma, semicolon, tab, space or pipe. Detected from the file by \
+        default.",
+};
+
+const SAMPLE_OPTION: OptionSpec = OptionSpec {
+    name: "sample",
+    kind: OptionKind::Integer,
+    default: Some("1000000"),
+    description: "Number of bytes read to guess the column types, or 0 for the whole file.",
+};
+
+pub const ADD_RASTER_OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "name",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Name of the table to create. Defaults to the name of the file.",
+    },
+    SCHEMA_OPTION,
+    OptionSpec {
+        name: "srid",
+        kind: OptionKind::Integer,
+        default: None,
+        description: "EPSG code of the raster, when it has no SRS or a wrong one.",
+    },
+    OptionSpec {
+        name: "tile_size",
+        kind: OptionKind::Integer,
+        default: Some("256"),
+        description: "Width and height of the tiles the raster is cut into, in pixels.",
+    },
+    OptionSpec {
+        name: "overviews",
+        kind: OptionKind::Integer,
+        default: Some("3"),
+        description:
+            "Number of overviews to build, up to 10, each at half the resolution of the last.",
+    },
+];
+
+pub const ADD_LAYER_OPTIONS: &[OptionSpec] = &[
+    LAYER_OPTION,
+    NAME_OPTION,
+    SCHEMA_OPTION,
+    SRID_OPTION,
+    T_SRS_OPTION,
+    SPLIT_OPTION,
+    MODE_OPTION,
+    KEY_OPTION,
+    ENCODING_OPTION,
+    PATTERN_OPTION,
+    PARALLEL_OPTION,
+];
+
+pub const ADD_FOLDER_OPTIONS: &[OptionSpec] = &[
+    PATTERN_OPTION,
+    OptionSpec {
+        name: "recursive",
+        kind: OptionKind::Boolean,
+        default: Some("false"),
+        description: "Also look for datasets in the subfolders.",
+    },
+    PARALLEL_OPTION,
+    LAYER_OPTION,
+    NAME_OPTION,
+    SCHEMA_OPTION,
+    SRID_OPTION,
+    T_SRS_OPTION,
+    SPLIT_OPTION,
+    MODE_OPTION,
+    KEY_OPTION,
+    ENCODING_OPTION,
+];
+
+pub const ADD_CSV_OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "x",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Column holding the X coordinate or longitude.",
+    },
+    OptionSpec {
+        name: "y",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Column holding the Y coordinate or latitude.",
+    },
+    OptionSpec {
+        name: "wkt",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Column holding the geometries as WKT, instead of `x=` and `y=`.",
+    },
+    NAME_OPTION,
+    SCHEMA_OPTION,
+    SRID_OPTION,
+    T_SRS_OPTION,
+    SPLIT_OPTION,
+    MODE_OPTION,
+    KEY_OPTION,
+    ENCODING_OPTION,
+    SEPARATOR_OPTION,
+    SAMPLE_OPTION,
+];
+
+pub const ADD_TABLE_OPTIONS: &[OptionSpec] = &[
+    NAME_OPTION,
+    SCHEMA_OPTION,
+    MODE_OPTION,
+    KEY_OPTION,
+    ENCODING_OPTION,
+    SEPARATOR_OPTION,
+    SAMPLE_OPTION,
+];
+
+static ADD_SCHEMA: CommandSchema = CommandSchema {
+    name: "add",
+    summary: "Add data to the connected database.",
+    args: &[],
+    options: &[],
+    subcommands: &[
+        CommandSchema {
+            name: "layer",
+            summary: "Import the layers of a vector dataset into PostGIS and the map.",
+            args: &[ArgSpec {
+                name: "path",
+                kind: ArgKind::Path,
+                required: true,
+                description: "Path to any dataset GDAL can open, or a zip archive of datasets.",
+            }],
+            options: ADD_LAYER_OPTIONS,
+            subcommands: &[],
+            examples: &[
+                "add layer `test_data/rivers.shp` ? name=rivers",
+                "add layer `test_data/city.gpkg`",
+                "add layer `test_data/city.gpkg` ? layer=parks name=city_parks",
+                "add layer `deliveries/roads.shp` ? schema=staging",
+                "add layer `deliveries/parcels.zip`",
+                "add layer `test_data/us_rivers.shp` ? t_srs=EPSG:4326",
+                "add layer `deliveries/parcels.shp` ? name=parcels mode=overwrite",
+                "add layer `deliveries/parcels_fix.shp` ? name=parcels mode=upsert key=parcel_id",
+            ],
+        },
+        CommandSchema {
+            name: "folder",
+            summary: "Import every dataset in a folder, several at a time.",
+            args: &[ArgSpec {
+                name: "dir",
+                kind: ArgKind::Path,
+                required: true,
+                description: "Folder to look for datasets in.",
+            }],
+            options: ADD_FOLDER_OPTIONS,
+            subcommands: &[],
+            examples: &[
+                "add folder `deliveries`",
+                "add folder `deliveries` ? pattern=*.shp recursive=true schema=staging",
+            ],
+        },
+        CommandSchema {
+            name: "csv",
+            summary: "Import a CSV or TSV file with X/Y or WKT columns into PostGIS and the map.",
+            args: &[ArgSpec {
+                name: "path",
+                kind: ArgKind::Path,
+                required: true,
+                description: "Path to the delimited text file.",
+            }],
+            options: ADD_CSV_OPTIONS,
+            subcommands: &[],
+            examples: &[
+                "add csv `test_data/wells.csv` ? x=lon y=lat srid=4326",
+                "add csv `test_data/parcels.tsv` ? wkt=geometry_col srid=2154 name=parcels",
+            ],
+        },
+        CommandSchema {
+            name: "raster",
+            summary: "Import a raster into a tiled PostGIS raster table with overviews.",
+            args: &[ArgSpec {
+                name: "path",
+                kind: ArgKind::Path,
+                required: true,
+                description: "Path to a GeoTIFF or any other raster GDAL can open.",
+            }],
+            options: ADD_RASTER_OPTIONS,
+            subcommands: &[],
+            examples: &[
+                "add raster `test_data/dem.tif`",
+                "add raster `test_data/ortho.tif` ? name=ortho tile_size=512 overviews=5",
+            ],
+        },
+        CommandSchema {
+            name: "table",
+            summary: "Import a CSV or TSV file without geometries as a plain table.",
+            args: &[ArgSpec {
+                name: "path",
+                kind: ArgKind::Path,
+                required: true,
+                description: "Path to the delimited text file.",
+            }],
+            options: ADD_TABLE_OPTIONS,
+            subcommands: &[],
+            examples: &[
+                "add table `test_data/census.csv`",
+                "add table `test_data/owners.txt` ? separator=tab name=owners",
+            ],
+        },
+    ],
+    examples: &[],
+};
+
+pub struct AddCommand;
+
+impl Command for AddCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &ADD_SCHEMA
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            Ok(match args.subcommand() {
+                Some("layer") => add_layer(args.text("path")?, &args.options, &ctx.state)
+                    .await
+                    .unwrap(),
+                Some("folder") => add_folder(args.text("dir")?, &args.options, &ctx.state)
+                    .await
+                    .unwrap(),
+                Some("csv") => add_csv(args.text("path")?, &args.options, &ctx.state)
+                    .await
+                    .unwrap(),
+                Some("raster") => add_raster(args.text("path")?, &args.options, &ctx.state)
+                    .await
+                    .unwrap(),
+                Some("table") => add_table(args.text("path")?, &args.options, &ctx.state)
+                    .await
+                    .unwrap(),
+                _ => return Err(args.unknown_subcommand()),
+            })
+        }
+        .boxed()
+    }
 }
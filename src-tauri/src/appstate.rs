@@ -1,11 +1,62 @@
 use crate::db::PGConnection;
-use postgres::{Client, Error};
-use tauri::AppHandle;
-use tauri::async_runtime::JoinHandle;
+use crate::events::EventSink;
+use deadpool_postgres::{Object, Pool};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
 pub struct AppState {
-    pub app_handle: AppHandle,
+    pub events: Arc<dyn EventSink>,
     pub pgsql_connection: PGConnection,
-    pub pgsql_client: Result<Client, Error>,
+    /// Connections to `pgsql_connection`, shared by every command and HyTigre. `None` until
+    /// `db connect` succeeds.
+    pub pgsql_pool: Option<Pool>,
     pub hytigre: Option<JoinHandle<Result<(), std::io::Error>>>,
 }
+
+pub type SharedState = Arc<Mutex<AppState>>;
+
+impl AppState {
+    pub fn new(events: Arc<dyn EventSink>) -> SharedState {
+        Arc::new(Mutex::new(AppState {
+            events,
+            pgsql_connection: PGConnection::default(),
+            pgsql_pool: None,
+            hytigre: None,
+        }))
+    }
+
+    /// The pool of the current database, to take out of the lock before connecting.
+    pub fn database(&self) -> Database {
+        Database {
+            connection: self.pgsql_connection.clone(),
+            pool: self.pgsql_pool.clone(),
+        }
+    }
+}
+
+/// The pool of the current database, cloned out of [`AppState`] so its lock isn't held while
+/// waiting for a connection or a query. Clones share the same connections.
+#[derive(Clone)]
+pub struct Database {
+    connection: PGConnection,
+    pool: Option<Pool>,
+}
+
+impl Database {
+    /// A connection from the pool. Idle connections are checked with a round trip before
+    /// they're handed out, and ones the server dropped are replaced with new ones, so a
+    /// restarted server is reconnected to without another `db connect`.
+    pub async fn client(&self) -> Result<Object, String> {
+        let pool = match &self.pool {
+            Some(val) => val,
+            None => return Err("Not connected to a database.".to_string()),
+        };
+        pool.get().await.map_err(|err| {
+            format!(
+                "Couldn't reach the database: {}",
+                self.connection.redact(&err.to_string())
+            )
+        })
+    }
+}
@@ -0,0 +1,114 @@
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tigre_lib::appstate::AppState;
+use tigre_lib::events::EventSink;
+use tigre_lib::output::Output;
+use tigre_lib::registry::{Context, Registry};
+use tigre_lib::repl::run_line_and_record;
+use tigre_lib::script::run_script;
+
+const USAGE: &str = "Usage: tigre-cli [-c <command> | -f <script.tigre>]
+
+Runs the TIGRE REPL without the desktop app.
+
+  -c <command>   Run a single command and exit
+  -f <file>      Run every command in a script file and exit
+  -h, --help     Print this message";
+
+/// Prints the events the desktop app would have drawn on the map.
+struct ConsoleEvents;
+
+impl EventSink for ConsoleEvents {
+    fn emit_json(&self, event: &str, payload: serde_json::Value) {
+        match event {
+            "add-vector-layer" | "add-raster-layer" => {
+                let parts = payload
+                    .as_array()
+                    .map(|parts| {
+                        parts
+                            .iter()
+                            .rev()
+                            .filter_map(|part| part.as_str())
+                            .collect::<Vec<&str>>()
+                            .join(".")
+                    })
+                    .unwrap_or_default();
+                eprintln!("Added layer {}", parts);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Prints results to stdout and diagnostics to stderr, returning true if there were no errors.
+fn print_output(output: &Output) -> bool {
+    for result in &output.results {
+        println!("{}", result);
+    }
+    for diagnostic in &output.errors {
+        eprintln!("{}", diagnostic);
+    }
+    !output.failed()
+}
+
+async fn interactive(ctx: &Context) -> ExitCode {
+    let mut editor = match DefaultEditor::new() {
+        Ok(val) => val,
+        Err(err) => {
+            eprintln!("ERROR! Couldn't start the REPL: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!("Welcome to TIGRE! Press Ctrl-D to exit.");
+    loop {
+        match editor.readline("tigre> ") {
+            Ok(line) => {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let _ = editor.add_history_entry(line.as_str());
+                print_output(&run_line_and_record(&line, ctx).await);
+            }
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return ExitCode::SUCCESS,
+            Err(err) => {
+                eprintln!("ERROR! {}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let ctx = Context::new(AppState::new(Arc::new(ConsoleEvents)), Registry::builtin());
+
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .as_slice()
+    {
+        [] => interactive(&ctx).await,
+        ["-c", cmd] => match print_output(&run_line_and_record(cmd, &ctx).await) {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        },
+        ["-f", path] => match print_output(&run_script(path, &ctx).await) {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        },
+        ["-h"] | ["--help"] => {
+            println!("{}", USAGE);
+            ExitCode::SUCCESS
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
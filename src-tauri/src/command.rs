@@ -0,0 +1,629 @@
+use crate::options::{OptionSpec, Options};
+use crate::output::{ErrorCode, Output};
+use crate::parser::{tokenize, ParseError, Span, Token};
+use crate::registry::{Context, Registry};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+/// A value parsed from REPL input, along with where it was found.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub span: Span,
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Spanned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+/// A point given to `inspect` as `x, y`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Location, String> {
+        let coords = s
+            .split(',')
+            .map(|coord| coord.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| format!("Expected a location in the form `x, y`, found '{}'.", s))?;
+
+        match coords.as_slice() {
+            [x, y] => Ok(Location { x: *x, y: *y }),
+            _ => Err(format!(
+                "Expected a location in the form `x, y`, found '{}'.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}", self.x, self.y)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    Text,
+    /// A file or directory on disk.
+    Path,
+    /// A table in the connected database, optionally qualified with its schema.
+    Layer,
+    /// The name of a registered command.
+    Command,
+    /// Text kept out of the history, e.g. a password.
+    Secret,
+    Integer,
+    Number,
+    Port,
+    Json,
+    Location,
+}
+
+impl fmt::Display for ArgKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgKind::Text => write!(f, "text"),
+            ArgKind::Path => write!(f, "a path"),
+            ArgKind::Layer => write!(f, "a layer, e.g. public.rivers"),
+            ArgKind::Command => write!(f, "a command"),
+            ArgKind::Secret => write!(f, "text"),
+            ArgKind::Integer => write!(f, "an integer"),
+            ArgKind::Number => write!(f, "a number"),
+            ArgKind::Port => write!(f, "a port number"),
+            ArgKind::Json => write!(f, "JSON"),
+            ArgKind::Location => write!(f, "a location `x, y`"),
+        }
+    }
+}
+
+/// Declares a positional argument of a command.
+#[derive(Debug, Clone, Copy)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+/// Describes a command, or one of its subcommands: what it takes and what it does.
+/// A subcommand named `""` runs when no subcommand is given, e.g. a bare `history`.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandSchema {
+    pub name: &'static str,
+    pub summary: &'static str,
+    pub args: &'static [ArgSpec],
+    pub options: &'static [OptionSpec],
+    pub subcommands: &'static [CommandSchema],
+    pub examples: &'static [&'static str],
+}
+
+impl CommandSchema {
+    pub fn subcommand(&self, name: &str) -> Option<&'static CommandSchema> {
+        self.subcommands
+            .iter()
+            .find(|subcommand| subcommand.name == name)
+    }
+
+    pub fn default_subcommand(&self) -> Option<&'static CommandSchema> {
+        self.subcommand("")
+    }
+
+    /// One usage line per runnable form of the command, e.g.
+    /// `buffer <layer> <distance> [? out= quad_segs= overwrite=]`.
+    pub fn usage(&self) -> Vec<String> {
+        if !self.subcommands.is_empty() {
+            return self
+                .subcommands
+                .iter()
+                .flat_map(|subcommand| subcommand.usage())
+                .map(|usage| format!("{} {}", self.name, usage).trim_end().to_string())
+                .collect();
+        }
+
+        let mut usage = match self.name.is_empty() {
+            true => vec![],
+            false => vec![self.name.to_string()],
+        };
+        for arg in self.args {
+            usage.push(match arg.required {
+                true => format!("<{}>", arg.name),
+                false => format!("[{}]", arg.name),
+            });
+        }
+        if !self.options.is_empty() {
+            let options = self
+                .options
+                .iter()
+                .map(|option| format!("{}=", option.name))
+                .collect::<Vec<String>>();
+            usage.push(format!("[? {}]", options.join(" ")));
+        }
+        vec![usage.join(" ")]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ArgValue {
+    Text(String),
+    Number(f64),
+    Integer(i64),
+    Json(serde_json::Value),
+    Location(Location),
+}
+
+/// A command was handed arguments its schema doesn't account for: its handler looked up an
+/// argument the schema doesn't declare, or the frontend sent a subcommand it doesn't have.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArgError(pub String);
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<ArgError> for Output {
+    fn from(err: ArgError) -> Output {
+        let mut output = Output::default();
+        output.error(ErrorCode::Args, format!("ERROR! {}", err));
+        output
+    }
+}
+
+/// The validated arguments of a command, checked against its [`CommandSchema`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Arguments {
+    /// The line of input the arguments were parsed from.
+    pub source: String,
+    pub command: Spanned<String>,
+    pub subcommand: Option<Spanned<String>>,
+    pub values: BTreeMap<String, Spanned<ArgValue>>,
+    pub options: Options,
+    /// `WITH ...` clause holding the earlier steps of a pipeline. Commands that read
+    /// pipelines prefix their queries with it. It's SQL, so it's only ever built by
+    /// [`crate::pipeline::run`], never taken from the frontend.
+    #[serde(skip)]
+    pub ctes: Option<String>,
+}
+
+impl Arguments {
+    pub fn subcommand(&self) -> Option<&str> {
+        self.subcommand
+            .as_ref()
+            .map(|subcommand| subcommand.value.as_str())
+    }
+
+    fn missing(&self, name: &str) -> ArgError {
+        ArgError(format!(
+            "Command '{}' has no argument <{}>. Is it declared in its schema?",
+            self.command.value, name
+        ))
+    }
+
+    /// The error for a subcommand the command doesn't have.
+    pub fn unknown_subcommand(&self) -> ArgError {
+        ArgError(format!(
+            "Command '{}' has no subcommand '{}'.",
+            self.command.value,
+            self.subcommand().unwrap_or_default()
+        ))
+    }
+
+    pub fn optional_text(&self, name: &str) -> Option<&str> {
+        match self.values.get(name).map(|val| &val.value) {
+            Some(ArgValue::Text(val)) => Some(val.as_str()),
+            _ => None,
+        }
+    }
+
+    /// The value of a required Text, Path, Layer, Command or Secret argument.
+    pub fn text(&self, name: &str) -> Result<&str, ArgError> {
+        self.optional_text(name).ok_or_else(|| self.missing(name))
+    }
+
+    pub fn number(&self, name: &str) -> Result<f64, ArgError> {
+        match self.values.get(name).map(|val| &val.value) {
+            Some(ArgValue::Number(val)) => Ok(*val),
+            _ => Err(self.missing(name)),
+        }
+    }
+
+    pub fn optional_integer(&self, name: &str) -> Option<i64> {
+        match self.values.get(name).map(|val| &val.value) {
+            Some(ArgValue::Integer(val)) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self, name: &str) -> Result<i64, ArgError> {
+        self.optional_integer(name)
+            .ok_or_else(|| self.missing(name))
+    }
+
+    pub fn json(&self, name: &str) -> Result<&serde_json::Value, ArgError> {
+        match self.values.get(name).map(|val| &val.value) {
+            Some(ArgValue::Json(val)) => Ok(val),
+            _ => Err(self.missing(name)),
+        }
+    }
+
+    pub fn location(&self, name: &str) -> Option<&Location> {
+        match self.values.get(name).map(|val| &val.value) {
+            Some(ArgValue::Location(val)) => Some(val),
+            _ => None,
+        }
+    }
+}
+
+/// The SELECT a command's output is made of, run as a CTE when the command is an
+/// intermediate step of a pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    /// Name of the output as a table, e.g. `rivers_buffer`. As a CTE it's renamed
+    /// `__step_<n>`, which later steps refer to as `-`.
+    pub name: String,
+    pub sql: String,
+}
+
+/// A REPL command. Implement this and add it to a [`Registry`] to make it available
+/// to `eval`, `help` and completion.
+pub trait Command: Send + Sync {
+    fn schema(&self) -> &'static CommandSchema;
+
+    fn name(&self) -> &'static str {
+        self.schema().name
+    }
+
+    fn usage(&self) -> Vec<String> {
+        self.schema().usage()
+    }
+
+    /// Runs the command. Fails only if `args` don't match the schema, which the parser rules
+    /// out for typed input.
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>>;
+
+    /// Whether `execute` prefixes its queries with [`Arguments::ctes`], so its layer
+    /// arguments can be `-` in a pipeline.
+    fn reads_pipelines(&self) -> bool {
+        false
+    }
+
+    /// The query behind this command's output, if it can pass it on to the next step of a
+    /// pipeline without creating a table.
+    fn query(&self, _args: &Arguments) -> Result<Option<Query>, ArgError> {
+        Ok(None)
+    }
+}
+
+/// Finds the candidate closest to a misspelled `name`, if any is close enough to be a
+/// likely typo.
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (strsim::levenshtein(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Walks the tokens of a single command, handing out typed arguments.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+    command: String,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .filter(|token| !token.is_separator())
+            .cloned();
+        if token.is_some() {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn subcommand(
+        &mut self,
+        schema: &'static CommandSchema,
+    ) -> Result<(Option<Spanned<String>>, &'static CommandSchema), ParseError> {
+        let names = schema
+            .subcommands
+            .iter()
+            .map(|subcommand| subcommand.name)
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<&str>>();
+
+        let token = match (self.next(), schema.default_subcommand()) {
+            (Some(token), _) => token,
+            (None, Some(default)) => return Ok((None, default)),
+            (None, None) => {
+                return Err(ParseError::new(
+                    format!(
+                        "No arguments provided for command '{}'. Expected one of: {}.",
+                        self.command,
+                        names.join(", ")
+                    ),
+                    Span::end_of(self.source),
+                ))
+            }
+        };
+
+        match schema.subcommand(&token.text) {
+            Some(subcommand) => Ok((
+                Some(Spanned {
+                    value: token.text,
+                    span: token.span,
+                }),
+                subcommand,
+            )),
+            None => Err(ParseError::new(
+                match suggest(&token.text, names.iter().copied()) {
+                    Some(name) => format!(
+                        "Unknown subcommand '{}' for command '{}'. Did you mean '{}'?",
+                        token.text, self.command, name
+                    ),
+                    None => format!(
+                        "Unknown subcommand '{}' for command '{}'. Expected one of: {}.",
+                        token.text,
+                        self.command,
+                        names.join(", ")
+                    ),
+                },
+                token.span,
+            )),
+        }
+    }
+
+    fn value(&mut self, arg: &ArgSpec) -> Result<Option<Spanned<ArgValue>>, ParseError> {
+        let token = match self.next() {
+            Some(token) => token,
+            None if arg.required => {
+                return Err(ParseError::new(
+                    format!(
+                        "Missing argument <{}> for command '{}'.",
+                        arg.name, self.command
+                    ),
+                    Span::end_of(self.source),
+                ))
+            }
+            None => return Ok(None),
+        };
+
+        let expected = |what: &str| {
+            ParseError::new(
+                format!(
+                    "Expected {} for <{}>, found '{}'.",
+                    what, arg.name, token.text
+                ),
+                token.span,
+            )
+        };
+
+        let value = match arg.kind {
+            ArgKind::Text | ArgKind::Path | ArgKind::Layer | ArgKind::Command | ArgKind::Secret => {
+                ArgValue::Text(token.text.clone())
+            }
+            ArgKind::Integer => ArgValue::Integer(
+                token
+                    .text
+                    .parse::<i64>()
+                    .map_err(|_| expected("an integer"))?,
+            ),
+            ArgKind::Number => ArgValue::Number(
+                token
+                    .text
+                    .parse::<f64>()
+                    .map_err(|_| expected("a number"))?,
+            ),
+            ArgKind::Port => ArgValue::Integer(
+                token
+                    .text
+                    .parse::<u16>()
+                    .map_err(|_| expected("a port number"))? as i64,
+            ),
+            ArgKind::Json => ArgValue::Json(
+                token
+                    .text
+                    .parse::<serde_json::Value>()
+                    .map_err(|_| expected("valid JSON"))?,
+            ),
+            ArgKind::Location => ArgValue::Location(
+                token
+                    .text
+                    .parse::<Location>()
+                    .map_err(|err| ParseError::new(err, token.span))?,
+            ),
+        };
+
+        Ok(Some(Spanned {
+            value,
+            span: token.span,
+        }))
+    }
+
+    /// Parses everything after `?` as `key=value` options.
+    fn options(&mut self, schema: &[OptionSpec]) -> Result<Options, ParseError> {
+        match self.tokens.get(self.position) {
+            Some(token) if token.is_separator() => {
+                let options =
+                    Options::parse(&self.tokens[self.position + 1..], schema, &self.command)?;
+                self.position = self.tokens.len();
+                Ok(options)
+            }
+            _ => Ok(Options::defaults(schema)),
+        }
+    }
+
+    fn finish(&self) -> Result<(), ParseError> {
+        match self.tokens.get(self.position) {
+            Some(token) => Err(ParseError::new(
+                format!("Unexpected argument '{}'.", token.text),
+                token.span,
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parses a line of REPL input into the validated [`Arguments`] of a registered command.
+pub fn parse(source: &str, registry: &Registry) -> Result<Arguments, ParseError> {
+    let tokens = tokenize(source)?;
+    let name = match tokens.first() {
+        Some(token) if !token.is_separator() => token.clone(),
+        _ => {
+            return Err(ParseError::new(
+                "Expected a command.",
+                tokens
+                    .first()
+                    .map(|t| t.span)
+                    .unwrap_or(Span::end_of(source)),
+            ))
+        }
+    };
+
+    let mut schema = match registry.get(&name.text) {
+        Some(command) => command.schema(),
+        None => {
+            return Err(ParseError::new(
+                match suggest(
+                    &name.text,
+                    registry.commands().map(|command| command.name()),
+                ) {
+                    Some(suggestion) => format!(
+                        "Unknown command '{}'. Did you mean '{}'?",
+                        name.text, suggestion
+                    ),
+                    None => format!(
+                        "Unknown command '{}'. Type `help` for a list of commands.",
+                        name.text
+                    ),
+                },
+                name.span,
+            ))
+        }
+    };
+
+    let mut parser = Parser {
+        source,
+        tokens,
+        position: 1,
+        command: name.text.clone(),
+    };
+
+    let mut subcommand = None;
+    if !schema.subcommands.is_empty() {
+        let (token, subcommand_schema) = parser.subcommand(schema)?;
+        subcommand = token;
+        schema = subcommand_schema;
+    }
+
+    let mut values = BTreeMap::new();
+    for arg in schema.args {
+        if let Some(value) = parser.value(arg)? {
+            values.insert(arg.name.to_string(), value);
+        }
+    }
+
+    let options = parser.options(schema.options)?;
+    parser.finish()?;
+
+    Ok(Arguments {
+        source: source.to_string(),
+        command: Spanned {
+            value: name.text,
+            span: name.span,
+        },
+        subcommand,
+        values,
+        options,
+        ctes: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::BufferCommand;
+
+    fn registry() -> Registry {
+        let mut registry = Registry::default();
+        registry.register(BufferCommand);
+        registry
+    }
+
+    #[test]
+    fn parse_rejects_empty_input() {
+        let registry = registry();
+        let err = parse("", &registry).unwrap_err();
+        assert_eq!(err.message, "Expected a command.");
+        assert_eq!(err.span, Span::new(0, 0));
+
+        let err = parse("? out=x", &registry).unwrap_err();
+        assert_eq!(err.span, Span::new(0, 1));
+    }
+
+    #[test]
+    fn parse_suggests_misspelt_commands() {
+        let err = parse("bufer rivers 10", &registry()).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Unknown command 'bufer'. Did you mean 'buffer'?"
+        );
+        assert_eq!(err.span, Span::new(0, 5));
+    }
+
+    #[test]
+    fn parse_reports_missing_arguments_at_the_end() {
+        let source = "buffer rivers";
+        let err = parse(source, &registry()).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Missing argument <distance> for command 'buffer'."
+        );
+        assert_eq!(err.span, Span::end_of(source));
+    }
+
+    #[test]
+    fn parse_arguments_and_options() {
+        let args = parse(
+            "buffer `public.wide rivers` 0.5 ? out=public.floodplain",
+            &registry(),
+        )
+        .unwrap();
+        assert_eq!(args.text("layer").unwrap(), "public.wide rivers");
+        assert_eq!(args.number("distance").unwrap(), 0.5);
+        assert_eq!(args.options.text("out"), Some("public.floodplain"));
+    }
+}
@@ -0,0 +1,248 @@
+use crate::command::{ArgKind, CommandSchema};
+use crate::db::list_layers;
+use crate::parser::{tokenize, Token};
+use crate::registry::{Context, Registry};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionKind {
+    Command,
+    Subcommand,
+    Layer,
+    Path,
+    Option,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub value: String,
+    pub kind: CompletionKind,
+    pub description: Option<String>,
+}
+
+/// Candidates for the word under the cursor. Picking one replaces the characters
+/// `start..end` of the input with its value.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Completions {
+    pub start: usize,
+    pub end: usize,
+    pub candidates: Vec<Candidate>,
+}
+
+/// What the word under the cursor should be.
+enum Target {
+    Command,
+    Subcommand(&'static CommandSchema),
+    Argument(ArgKind),
+    /// Every positional argument is given, so only `?` can follow.
+    Separator(&'static CommandSchema),
+    Option(&'static CommandSchema, Vec<String>),
+}
+
+/// Works out what to complete from the words before the cursor.
+fn target(words: &[Token], registry: &Registry) -> Option<Target> {
+    let (name, mut rest) = match words.split_first() {
+        Some(val) => val,
+        None => return Some(Target::Command),
+    };
+
+    let mut schema = registry.get(&name.text)?.schema();
+    if !schema.subcommands.is_empty() {
+        match rest.split_first() {
+            None => return Some(Target::Subcommand(schema)),
+            Some((token, _)) if token.is_separator() => schema = schema.default_subcommand()?,
+            Some((token, args)) => {
+                schema = schema.subcommand(&token.text)?;
+                rest = args;
+            }
+        }
+    }
+
+    match rest.iter().position(Token::is_separator) {
+        Some(separator) => {
+            let given = rest[separator + 1..]
+                .iter()
+                .filter_map(|token| token.text.split_once('='))
+                .map(|(key, _)| key.to_string())
+                .collect();
+            Some(Target::Option(schema, given))
+        }
+        None => match schema.args.get(rest.len()) {
+            Some(arg) => Some(Target::Argument(arg.kind)),
+            None => Some(Target::Separator(schema)),
+        },
+    }
+}
+
+fn command_candidates(word: &str, registry: &Registry) -> Vec<Candidate> {
+    registry
+        .commands()
+        .filter(|command| command.name().starts_with(word))
+        .map(|command| Candidate {
+            value: command.name().to_string(),
+            kind: CompletionKind::Command,
+            description: Some(command.schema().summary.to_string()),
+        })
+        .collect()
+}
+
+async fn layer_candidates(word: &str, pool: Option<Pool>) -> Vec<Candidate> {
+    let client = match pool {
+        Some(pool) => match pool.get().await {
+            Ok(val) => val,
+            Err(_) => return vec![],
+        },
+        None => return vec![],
+    };
+    let layers = match list_layers(&client).await {
+        Ok(val) => val,
+        Err(_) => return vec![],
+    };
+
+    let word = word.to_lowercase();
+    layers
+        .into_iter()
+        .filter(|(schema, name)| {
+            format!("{}.{}", schema, name)
+                .to_lowercase()
+                .starts_with(&word)
+                || (!word.contains('.') && name.to_lowercase().starts_with(&word))
+        })
+        .map(|(schema, name)| Candidate {
+            value: format!("{}.{}", schema, name),
+            kind: CompletionKind::Layer,
+            description: None,
+        })
+        .collect()
+}
+
+fn path_candidates(word: &str) -> Vec<Candidate> {
+    let (dir, file_prefix) = match word.rfind(std::path::is_separator) {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+
+    let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+        Ok(val) => val,
+        Err(_) => return vec![],
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with(file_prefix) || (name.starts_with('.') && file_prefix.is_empty()) {
+                return None;
+            }
+
+            let is_dir = entry.file_type().map(|val| val.is_dir()).unwrap_or(false);
+            let path = format!("{}{}", dir, name);
+            // Paths with spaces need quoting. Directories leave the quote open so the
+            // path can be completed further.
+            let value = match (path.contains(char::is_whitespace), is_dir) {
+                (true, true) => format!("`{}{}", path, std::path::MAIN_SEPARATOR),
+                (true, false) => format!("`{}`", path),
+                (false, true) => format!("{}{}", path, std::path::MAIN_SEPARATOR),
+                (false, false) => path,
+            };
+
+            Some(Candidate {
+                value,
+                kind: CompletionKind::Path,
+                description: None,
+            })
+        })
+        .collect()
+}
+
+/// Completes the word before `cursor` (a character offset into `source`).
+pub async fn complete(source: &str, cursor: usize, ctx: &Context) -> Completions {
+    let cursor = source
+        .char_indices()
+        .nth(cursor)
+        .map(|(i, _)| i)
+        .unwrap_or(source.len());
+    let before = &source[..cursor];
+
+    // Close a quote left open by the user so the input still tokenizes
+    let mut words = match tokenize(before).or_else(|_| tokenize(&format!("{}`", before))) {
+        Ok(val) => val,
+        Err(_) => return Completions::default(),
+    };
+
+    // Only the step under the cursor matters in a pipeline
+    let in_pipeline = match words.iter().rposition(Token::is_pipe) {
+        Some(pipe) => {
+            words.drain(..=pipe);
+            true
+        }
+        None => false,
+    };
+
+    let (start, word) = match words.last() {
+        Some(token) if token.span.end >= before.len() => {
+            let token = words.pop().unwrap();
+            (token.span.start, token.text)
+        }
+        _ => (before.len(), String::new()),
+    };
+
+    let mut candidates = match target(&words, &ctx.registry) {
+        Some(Target::Command) => command_candidates(&word, &ctx.registry),
+        Some(Target::Subcommand(schema)) => schema
+            .subcommands
+            .iter()
+            .filter(|subcommand| !subcommand.name.is_empty() && subcommand.name.starts_with(&word))
+            .map(|subcommand| Candidate {
+                value: subcommand.name.to_string(),
+                kind: CompletionKind::Subcommand,
+                description: Some(subcommand.summary.to_string()),
+            })
+            .collect(),
+        Some(Target::Argument(ArgKind::Layer)) => {
+            let pool = ctx.state.lock().await.pgsql_pool.clone();
+            let mut candidates = layer_candidates(&word, pool).await;
+            if in_pipeline && "-".starts_with(&word) {
+                candidates.push(Candidate {
+                    value: "-".to_string(),
+                    kind: CompletionKind::Layer,
+                    description: Some("Output of the previous step.".to_string()),
+                });
+            }
+            candidates
+        }
+        Some(Target::Argument(ArgKind::Path)) => path_candidates(&word),
+        Some(Target::Argument(ArgKind::Command)) => command_candidates(&word, &ctx.registry),
+        Some(Target::Argument(_)) => vec![],
+        Some(Target::Separator(schema)) if !schema.options.is_empty() && "?".starts_with(&word) => {
+            vec![Candidate {
+                value: "?".to_string(),
+                kind: CompletionKind::Option,
+                description: Some("Start the optional arguments.".to_string()),
+            }]
+        }
+        Some(Target::Separator(_)) => vec![],
+        Some(Target::Option(schema, given)) => schema
+            .options
+            .iter()
+            .filter(|option| !given.iter().any(|key| key == option.name))
+            .filter(|option| !word.contains('=') && option.name.starts_with(&word))
+            .map(|option| Candidate {
+                value: format!("{}=", option.name),
+                kind: CompletionKind::Option,
+                description: Some(option.description.to_string()),
+            })
+            .collect(),
+        None => vec![],
+    };
+    candidates.sort_by(|a, b| a.value.cmp(&b.value));
+
+    Completions {
+        start: source[..start].chars().count(),
+        end: source[..cursor].chars().count(),
+        candidates,
+    }
+}
@@ -1,15 +1,24 @@
-use crate::appstate::AppState;
-use crate::gdal_utils::postgis_layer_to_gpkg;
-use crate::output::Output;
+use crate::appstate::{AppState, Database};
+use crate::command::{ArgError, ArgKind, ArgSpec, Arguments, Command, CommandSchema, Location};
+use crate::gdal_utils::{postgis_layer_to_gpkg, quote_identifier};
+use crate::options::{OptionKind, OptionSpec};
+use crate::output::{ErrorCode, Output, OutputResult};
+use crate::profiles::{pgpass_password, save_pgpass_password, service, Profile, Profiles};
+use crate::registry::Context;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Runtime};
+use futures::future::{BoxFuture, FutureExt};
 use geozero::wkb::GpkgWkb;
 use geozero::ToJson;
-use postgres::{Client, NoTls};
-use rusqlite::fallible_iterator::FallibleIterator;
-use rusqlite::Connection;
-use std::collections::HashMap;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use std::collections::BTreeMap;
 use std::fs;
-use tauri::{Emitter, Manager, State};
+use std::time::Duration;
 use tokio::sync::Mutex;
+use tokio_postgres::NoTls;
+
+/// Connections a pool keeps open to the database at most.
+pub const POOL_SIZE: usize = 8;
 
 #[derive(Default, Clone, PartialEq)]
 pub struct PGConnection {
@@ -21,56 +30,258 @@ pub struct PGConnection {
     optional_params: Option<String>,
 }
 
+/// Percent-encodes `text` for a connection URL, so passwords with `@`, `:` or `/` in them
+/// don't break it.
+fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
 impl PGConnection {
+    /// The connection saved as `profile`, logging in with `password`.
+    pub fn from_profile(profile: &Profile, password: String) -> PGConnection {
+        PGConnection {
+            username: profile.username.clone(),
+            password,
+            host: profile.host.clone(),
+            port: profile.port.clone(),
+            db: profile.database.clone(),
+            optional_params: profile.params.clone(),
+        }
+    }
+
+    /// The connection as a profile, which leaves the password out.
+    pub fn profile(&self) -> Profile {
+        Profile {
+            username: self.username.clone(),
+            host: self.host.clone(),
+            port: self.port.clone(),
+            database: self.db.clone(),
+            params: self.optional_params.clone(),
+        }
+    }
+
     pub fn pg_string(&self) -> String {
         let optional_params = match &self.optional_params {
-            Some(params) => &params,
+            Some(params) => params,
             None => "",
         };
 
         format!(
             "postgresql://{}:{}@{}:{}/{}?{}",
-            &self.username, &self.password, &self.host, &self.port, &self.db, optional_params
+            url_encode(&self.username),
+            url_encode(&self.password),
+            self.host,
+            self.port,
+            url_encode(&self.db),
+            optional_params
+        )
+    }
+
+    /// The connection string without the password, safe to show or store.
+    pub fn redacted_string(&self) -> String {
+        format!(
+            "postgresql://{}@{}:{}/{}",
+            self.username, self.host, self.port, self.db
         )
     }
 
     pub fn gdal_string(&self) -> String {
         format!(
             "PG:dbname={} host={} port={} user={} password={}",
-            &self.db, &self.host, &self.port, &self.username, &self.password
+            self.db, self.host, self.port, self.username, self.password
         )
     }
+
+    /// A pool of connections to the database. Connections are made when first needed, and
+    /// idle ones are checked with a round trip before they're reused, so ones the server
+    /// dropped are replaced.
+    pub fn pool(&self) -> Result<Pool, String> {
+        let config = self
+            .pg_string()
+            .parse::<tokio_postgres::Config>()
+            .map_err(|err| self.redact(&err.to_string()))?;
+        let manager = Manager::from_config(
+            config,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Verified,
+            },
+        );
+        Pool::builder(manager)
+            .max_size(POOL_SIZE)
+            .runtime(Runtime::Tokio1)
+            .wait_timeout(Some(Duration::from_secs(30)))
+            .build()
+            .map_err(|err| err.to_string())
+    }
+
+    /// `text` with the password replaced by `***`, for messages that may quote the
+    /// connection string, like GDAL's.
+    pub fn redact(&self, text: &str) -> String {
+        match self.password.is_empty() {
+            true => text.to_string(),
+            false => text
+                .replace(&self.password, "***")
+                .replace(&url_encode(&self.password), "***"),
+        }
+    }
 }
 
-pub async fn inspect_layer(table: &str, pgsql_connection: &PGConnection) -> Result<String, String> {
-    let mut pgsql_client = match Client::connect(&pgsql_connection.pg_string(), NoTls) {
+/// Works out the connection `db connect` asks for: a saved profile or a `pg_service.conf`
+/// service when only a name is given, otherwise the username, password, host, port and
+/// database. An empty password is looked up in the password file, and options given to the
+/// command take precedence over those of the profile or service.
+fn resolve_connection(
+    name: &str,
+    args: &Arguments,
+    profiles: &Profiles,
+) -> Result<PGConnection, String> {
+    let mut params = args
+        .options
+        .iter()
+        .map(|(key, val)| (key.to_string(), val.to_string()))
+        .collect::<BTreeMap<String, String>>();
+
+    let mut connection = match (
+        args.optional_text("password"),
+        args.optional_text("host"),
+        args.optional_integer("port"),
+        args.optional_text("database"),
+    ) {
+        (Some(password), Some(host), Some(port), Some(database)) => PGConnection {
+            username: name.to_string(),
+            password: password.to_string(),
+            host: host.to_string(),
+            port: port.to_string(),
+            db: database.to_string(),
+            optional_params: None,
+        },
+        (None, None, None, None) => match (profiles.get(name)?, service(name)) {
+            (Some(profile), _) => {
+                for pair in profile.params.iter().flat_map(|params| params.split('&')) {
+                    if let Some((key, value)) = pair.split_once('=') {
+                        params.entry(key.to_string()).or_insert(value.to_string());
+                    }
+                }
+                PGConnection::from_profile(&profile, String::new())
+            }
+            (None, Some(mut service)) => {
+                let connection = PGConnection {
+                    username: service
+                        .remove("user")
+                        .unwrap_or_else(|| std::env::var("USER").unwrap_or_default()),
+                    password: service.remove("password").unwrap_or_default(),
+                    host: service
+                        .remove("host")
+                        .unwrap_or_else(|| "localhost".to_string()),
+                    port: service.remove("port").unwrap_or_else(|| "5432".to_string()),
+                    db: service.remove("dbname").unwrap_or_default(),
+                    optional_params: None,
+                };
+                for (key, value) in service {
+                    params.entry(key).or_insert(value);
+                }
+                connection
+            }
+            (None, None) => {
+                return Err(format!(
+                    "No profile or pg_service.conf service named '{}'. Connect with the \
+                    username, password, host, port and database, then `db profile save {}`.",
+                    name, name
+                ))
+            }
+        },
+        _ => {
+            return Err(
+                "Give the name of a profile or service, or the username, password, host, port \
+                and database."
+                    .to_string(),
+            )
+        }
+    };
+
+    if connection.password.is_empty() {
+        if let Some(password) = pgpass_password(
+            &connection.host,
+            &connection.port,
+            &connection.db,
+            &connection.username,
+        ) {
+            connection.password = password;
+        }
+    }
+    if !params.is_empty() {
+        connection.optional_params = Some(
+            params
+                .iter()
+                .map(|(key, val)| format!("{}={}", key, val))
+                .collect::<Vec<String>>()
+                .join("&"),
+        );
+    }
+    Ok(connection)
+}
+
+/// SQL moving `geometry`, in lon/lat like the map, into the SRS of the `geom` column it's
+/// compared with. Layers stored without an SRID are taken to be in lon/lat already.
+fn to_layer_srs(geometry: &str) -> String {
+    format!(
+        "CASE ST_SRID(geom) WHEN 0 THEN {} \
+        ELSE ST_Transform(ST_SetSRID({}, 4326), ST_SRID(geom)) END",
+        geometry, geometry
+    )
+}
+
+/// SQL moving `geometry`, in the SRS of the `geom` column, into lon/lat for the map.
+fn to_map_srs(geometry: &str) -> String {
+    format!(
+        "CASE ST_SRID(geom) WHEN 0 THEN {} ELSE ST_Transform({}, 4326) END",
+        geometry, geometry
+    )
+}
+
+/// `ctes` is a `WITH` clause to prefix the query with, e.g. defining `table` itself.
+pub async fn inspect_layer(
+    table: &str,
+    database: &Database,
+    ctes: Option<&str>,
+) -> Result<String, String> {
+    let pgsql_client = match database.client().await {
         Ok(val) => val,
-        Err(_) => return Err("ERROR! Lost connection to the database.".to_string()),
+        Err(err) => return Err(format!("ERROR! {}", err)),
     };
 
     match pgsql_client.query(
-        format!("SELECT to_jsonb(dta) FROM (SELECT json_agg(sub) FROM (SELECT * FROM {} ORDER BY geom LIMIT 1000) sub) dta", table)
+        format!("{}SELECT to_jsonb(dta) FROM (SELECT json_agg(sub) FROM (SELECT * FROM {} ORDER BY geom LIMIT 1000) sub) dta", ctes.unwrap_or_default(), table)
             .as_str(),
         &[],
-    ) {
+    ).await {
         Ok(val) => {
             match val.first() {
                 Some(row) => Ok(row.get::<usize, serde_json::Value>(0).to_string()),
-                None => return Err("Found 0 results.".to_string()),
+                None => Err("Found 0 results.".to_string()),
             }
         },
-        Err(err) => return Err(format!("ERROR! Couldn't inspect layer: {}", err)),
+        Err(err) => Err(format!("ERROR! Couldn't inspect layer: {}", err)),
     }
 }
 
 pub async fn inspect_layer_at_location(
     table: &str,
-    pgsql_connection: &PGConnection,
-    location: &str,
+    database: &Database,
+    location: &Location,
+    ctes: Option<&str>,
 ) -> Result<String, String> {
-    let mut pgsql_client = match Client::connect(&pgsql_connection.pg_string(), NoTls) {
+    let pgsql_client = match database.client().await {
         Ok(val) => val,
-        Err(_) => return Err("ERROR! Lost connection to the database.".to_string()),
+        Err(err) => return Err(format!("ERROR! {}", err)),
     };
 
     let table_split = table.split(".").collect::<Vec<&str>>();
@@ -80,43 +291,41 @@ pub async fn inspect_layer_at_location(
     };
 
     match pgsql_client.query(
-        format!("SELECT to_jsonb(dta) FROM (SELECT json_agg({}) FROM {} WHERE ST_Intersects(geom, ST_SetSRID(ST_MakePoint({}), 0)) = TRUE) dta", short_table, table, location).as_str(),
+        format!("{}SELECT to_jsonb(dta) FROM (SELECT json_agg({}) FROM {} WHERE ST_Intersects(geom, {}) = TRUE) dta", ctes.unwrap_or_default(), short_table, table, to_layer_srs(&format!("ST_MakePoint({})", location))).as_str(),
         &[],
-    ) {
+    ).await {
         Ok(val) => {
             match val.first() {
                 Some(row) => Ok(row.get::<usize, serde_json::Value>(0).to_string()),
-                None => return Err("Found 0 results.".to_string()),
+                None => Err("Found 0 results.".to_string()),
             }
         },
-        Err(err) => return Err(format!("ERROR! Couldn't inspect layer: {}", err)),
+        Err(err) => Err(format!("ERROR! Couldn't inspect layer: {}", err)),
     }
 }
 
-#[tauri::command]
 pub async fn get_layer_symbology(
     schema: &str,
     table: &str,
-    app: tauri::AppHandle,
+    state: &Mutex<AppState>,
 ) -> Result<String, String> {
-    let state: State<'_, Mutex<AppState>> = app.app_handle().state();
-
-    let mut pgsql_client =
-        match Client::connect(&state.lock().await.pgsql_connection.pg_string(), NoTls) {
-            Ok(val) => val,
-            Err(_) => {
-                return Err("ERROR! Lost connection to the database.".to_string());
-            }
-        };
+    let database = state.lock().await.database();
+    let pgsql_client = match database.client().await {
+        Ok(val) => val,
+        Err(err) => return Err(format!("ERROR! {}", err)),
+    };
 
-    let layer_symbology: Vec<postgres::Row> = match pgsql_client.query(
-        format!(
-            "SELECT to_json(pg_catalog.obj_description('{}.{}'::regclass, 'pg_class'))::text",
-            schema, table
+    let layer_symbology: Vec<tokio_postgres::Row> = match pgsql_client
+        .query(
+            format!(
+                "SELECT to_json(pg_catalog.obj_description('{}.{}'::regclass, 'pg_class'))::text",
+                schema, table
+            )
+            .as_str(),
+            &[],
         )
-        .as_str(),
-        &[],
-    ) {
+        .await
+    {
         Ok(layer_symbology) => layer_symbology,
         Err(err) => return Err(format!("ERROR! Failed to query database: {}", err)),
     };
@@ -127,110 +336,153 @@ pub async fn get_layer_symbology(
     }
 }
 
-#[tauri::command]
-pub async fn get_as_json_gpkg(schema: &str, table: &str) -> Result<Vec<String>, ()> {
-    let sqlite_connection = match Connection::open(format!("/tmp/tigre/{}.{}.gpkg", schema, table))
+/// A value of the GeoPackage cache as a GeoJSON property.
+fn json_value(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(val) => serde_json::Value::from(val),
+        ValueRef::Real(val) => serde_json::Value::from(val),
+        ValueRef::Text(val) => serde_json::Value::from(String::from_utf8_lossy(val)),
+        ValueRef::Blob(val) => serde_json::Value::from(hex::encode(val)),
+    }
+}
+
+/// The features cached for `schema.table`, as GeoJSON Features with the FID as their id and
+/// the attributes as their properties.
+pub async fn get_as_json_gpkg(schema: &str, table: &str) -> Result<Vec<String>, String> {
+    // Opened read-only, so a missing cache isn't created empty
+    let path = format!("/tmp/tigre/{}.{}.gpkg", schema, table);
+    let sqlite_connection =
+        match Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY) {
+            Ok(val) => val,
+            Err(err) => return Err(format!("ERROR! Couldn't open '{}': {}", path, err)),
+        };
+
+    let mut statement = match sqlite_connection
+        .prepare(format!("SELECT * FROM \"{}\"", table.replace('"', "\"\"")).as_str())
     {
         Ok(val) => val,
-        Err(_) => panic!("ERROR! Couldn't open gpkg."),
+        Err(err) => return Err(format!("ERROR! Couldn't query '{}': {}", path, err)),
     };
+    let columns = statement
+        .column_names()
+        .iter()
+        .map(|column| column.to_string())
+        .collect::<Vec<String>>();
 
-    match sqlite_connection.prepare(format!("SELECT hex(geom) FROM {}", table).as_str()) {
-        Ok(mut val) => {
-            return match val.query([]) {
-                Ok(rows) => Ok(rows
-                    .map(|row| {
-                        let wkb_data = hex::decode(row.get::<usize, String>(0)?).unwrap();
-                        let wkb = GpkgWkb(wkb_data);
-                        match wkb.to_json() {
-                            Ok(json) => Ok(json),
-                            Err(_) => Ok("[]".to_string()),
-                        }
-                    })
-                    .collect()
-                    .expect("No data.")),
-                Err(_) => Ok(vec![]),
-            };
+    let features = statement.query_map([], |row| {
+        let mut id = serde_json::Value::Null;
+        let mut geometry = serde_json::Value::Null;
+        let mut properties = serde_json::Map::new();
+        for (i, column) in columns.iter().enumerate() {
+            let value = row.get_ref(i)?;
+            match (column.as_str(), value) {
+                ("fid", _) => id = json_value(value),
+                ("geom", ValueRef::Blob(wkb)) => {
+                    geometry = GpkgWkb(wkb.to_vec())
+                        .to_json()
+                        .ok()
+                        .and_then(|json| serde_json::from_str(&json).ok())
+                        .unwrap_or_default()
+                }
+                ("geom", _) => (),
+                _ => {
+                    properties.insert(column.clone(), json_value(value));
+                }
+            }
         }
-        Err(err) => panic!("ERROR! Couldn't query gpkg: {}", err),
-    };
+
+        Ok(serde_json::json!({
+            "type": "Feature",
+            "id": id,
+            "geometry": geometry,
+            "properties": properties,
+        })
+        .to_string())
+    });
+
+    match features {
+        Ok(rows) => Ok(rows.flatten().collect()),
+        Err(err) => Err(format!("ERROR! Couldn't query '{}': {}", path, err)),
+    }
 }
 
-#[tauri::command]
 pub async fn get_as_wkt(
     table: &str,
     bb: Vec<Vec<f32>>,
-    app: tauri::AppHandle,
-) -> Result<Vec<String>, ()> {
-    let state: State<'_, Mutex<AppState>> = app.app_handle().state();
-
-    let _ = state.lock().await.app_handle.emit("loading", 10);
-
+    state: &Mutex<AppState>,
+) -> Result<Vec<String>, String> {
     if bb.len() != 2 {
-        let _ = state.lock().await.app_handle.emit("loading", 0);
-        panic!("Bounding box has fewer than 2 corners.");
+        return Err("Bounding box has fewer than 2 corners.".to_string());
     }
 
-    let _ = state.lock().await.app_handle.emit("loading", 25);
+    state.lock().await.events.emit("loading", 10);
+    state.lock().await.events.emit("loading", 25);
 
-    let mut pgsql_client =
-        match Client::connect(&state.lock().await.pgsql_connection.pg_string(), NoTls) {
-            Ok(val) => val,
-            Err(_) => {
-                let _ = state.lock().await.app_handle.emit("loading", 0);
-                panic!("ERROR! Lost connection to the database.");
-            }
-        };
+    let database = state.lock().await.database();
+    let pgsql_client = match database.client().await {
+        Ok(val) => val,
+        Err(err) => {
+            state.lock().await.events.emit("loading", 0);
+            return Err(format!("ERROR! {}", err));
+        }
+    };
 
     let mut wkt_rows: Vec<String> = vec![];
-    let wkt_result = pgsql_client.query(
-        format!(
-            "SELECT ST_AsText(ST_Intersection(ST_SetSRID(ST_MakeEnvelope({}, {}, {}, {}), 4326), geom)) FROM {}",
-            bb[0][0], bb[0][1], bb[1][0], bb[1][1], table
-        )
-        .as_str(),
-        &[],
+    let envelope = format!(
+        "ST_MakeEnvelope({}, {}, {}, {})",
+        bb[0][0], bb[0][1], bb[1][0], bb[1][1]
     );
+    let intersection = format!("ST_Intersection({}, geom)", to_layer_srs(&envelope));
+    let wkt_result = pgsql_client
+        .query(
+            format!(
+                "SELECT ST_AsText({}) FROM {}",
+                to_map_srs(&intersection),
+                table
+            )
+            .as_str(),
+            &[],
+        )
+        .await;
 
-    let _ = state.lock().await.app_handle.emit("loading", 50);
-    match wkt_result {
-        Ok(val) => {
-            for row in val {
-                for col in row.columns() {
-                    wkt_rows.push(row.get::<&str, &str>(col.name()).to_string());
-                }
+    state.lock().await.events.emit("loading", 50);
+    if let Ok(val) = wkt_result {
+        for row in val {
+            for col in row.columns() {
+                wkt_rows.push(row.get::<&str, &str>(col.name()).to_string());
             }
         }
-        Err(_) => (),
     }
 
-    let _ = state.lock().await.app_handle.emit("loading", 0);
+    state.lock().await.events.emit("loading", 0);
     Ok(wkt_rows)
 }
 
-#[tauri::command]
 pub async fn get_as_json(
     table: &str,
     bb: Vec<Vec<f32>>,
-    app: tauri::AppHandle,
+    state: &Mutex<AppState>,
 ) -> Result<String, String> {
     if bb.len() != 2 {
         return Err("Bounding box has fewer than 2 corners.".to_string());
     }
 
-    let state: State<'_, Mutex<AppState>> = app.app_handle().state();
-    let mut pgsql_client =
-        match Client::connect(&state.lock().await.pgsql_connection.pg_string(), NoTls) {
-            Ok(val) => val,
-            Err(_) => {
-                return Err("ERROR! Lost connection to the database.".to_string());
-            }
-        };
+    let database = state.lock().await.database();
+    let pgsql_client = match database.client().await {
+        Ok(val) => val,
+        Err(err) => return Err(format!("ERROR! {}", err)),
+    };
 
+    let envelope = format!(
+        "ST_MakeEnvelope({}, {}, {}, {})",
+        bb[0][0], bb[0][1], bb[1][0], bb[1][1]
+    );
+    let intersection = format!("ST_Intersection({}, geom)", to_layer_srs(&envelope));
     let geojson_result = match pgsql_client.query(
-        format!("SELECT json_build_object('type', 'Feature', 'geometry', ST_AsGeoJSON(ST_Intersection(ST_MakeEnvelope({}, {}, {}, {}), geom))::json) FROM {}", bb[0][0], bb[0][1], bb[1][0], bb[1][1], table).as_str(),
+        format!("SELECT json_build_object('type', 'Feature', 'geometry', ST_AsGeoJSON({})::json) FROM {}", to_map_srs(&intersection), table).as_str(),
         &[],
-    ) {
+    ).await {
         Ok(val) => val,
         Err(err) => {
             return Err(format!("ERROR! Failed to query database: {}", err));
@@ -247,183 +499,767 @@ pub async fn get_as_json(
     Ok(format!("[{}]", json_rows.join(",")))
 }
 
+/// Renders the part of PostGIS raster `schema.table` inside `bb`, given as lon/lat corners, as
+/// a PNG of at most `width` by `height` pixels. Rasters with three or more 8-bit bands are
+/// drawn as RGB, others have their first band stretched between its min and max. Returns the
+/// PNG as base64 with its lat/lon bounds as JSON, or `null` if the raster doesn't cover `bb`.
+pub async fn get_raster_png(
+    schema: &str,
+    table: &str,
+    bb: Vec<Vec<f32>>,
+    width: u32,
+    height: u32,
+    state: &Mutex<AppState>,
+) -> Result<String, String> {
+    if bb.len() != 2 {
+        return Err("Bounding box has fewer than 2 corners.".to_string());
+    }
+    let envelope = [
+        bb[0][0] as f64,
+        bb[0][1] as f64,
+        bb[1][0] as f64,
+        bb[1][1] as f64,
+    ];
+
+    let database = state.lock().await.database();
+    let pgsql_client = match database.client().await {
+        Ok(val) => val,
+        Err(err) => return Err(format!("ERROR! {}", err)),
+    };
+
+    let raster = match pgsql_client
+        .query_opt(
+            "SELECT srid, abs(scale_x) FROM raster_columns
+            WHERE r_table_schema = $1 AND r_table_name = $2 AND r_raster_column = 'rast'",
+            &[&schema, &table],
+        )
+        .await
+    {
+        Ok(Some(row)) => row,
+        Ok(None) => return Err(format!("ERROR! {}.{} is not a raster.", schema, table)),
+        Err(err) => return Err(format!("ERROR! Failed to query database: {}", err)),
+    };
+    let srid = raster.get::<usize, i32>(0);
+    let scale = raster.get::<usize, Option<f64>>(1);
+
+    // Draw from the coarsest overview that still has at least one pixel per screen pixel
+    let bb_width = match pgsql_client
+        .query_one(
+            "SELECT ST_XMax(geom) - ST_XMin(geom)
+            FROM (SELECT ST_Transform(ST_MakeEnvelope($1, $2, $3, $4, 4326), $5) AS geom) AS bbox",
+            &[
+                &envelope[0],
+                &envelope[1],
+                &envelope[2],
+                &envelope[3],
+                &srid,
+            ],
+        )
+        .await
+    {
+        Ok(row) => row.get::<usize, f64>(0),
+        Err(err) => return Err(format!("ERROR! Failed to query database: {}", err)),
+    };
+    let overviews = match pgsql_client
+        .query(
+            "SELECT o_table_name::text, overview_factor FROM raster_overviews
+            WHERE r_table_schema = $1 AND r_table_name = $2 ORDER BY overview_factor",
+            &[&schema, &table],
+        )
+        .await
+    {
+        Ok(val) => val,
+        Err(err) => return Err(format!("ERROR! Failed to query database: {}", err)),
+    };
+    let mut source = table.to_string();
+    if let Some(scale) = scale {
+        let wanted = bb_width / width.max(1) as f64;
+        for row in overviews {
+            if scale * row.get::<usize, i32>(1) as f64 <= wanted {
+                source = row.get::<usize, String>(0);
+            }
+        }
+    }
+
+    let tile = match pgsql_client.query_opt(
+        format!(
+            "WITH bbox AS (
+                SELECT ST_Transform(ST_MakeEnvelope($1, $2, $3, $4, 4326), $5) AS geom
+            ), clipped AS (
+                SELECT ST_Clip(ST_Union(rast), (SELECT geom FROM bbox)) AS rast
+                FROM \"{}\".\"{}\"
+                WHERE ST_Intersects(rast, (SELECT geom FROM bbox))
+            ), sized AS (
+                SELECT ST_Resize(
+                    rast,
+                    least(1.0::float8, $6 / ST_Width(rast)),
+                    least(1.0::float8, $7 / ST_Height(rast))
+                ) AS rast
+                FROM (SELECT ST_Transform(rast, 4326) AS rast FROM clipped) AS warped
+                WHERE rast IS NOT NULL
+            ), stretched AS (
+                SELECT CASE
+                    WHEN ST_NumBands(rast) >= 3 AND ST_BandPixelType(rast, 1) = '8BUI'
+                        THEN ST_AsPNG(rast, ARRAY[1, 2, 3])
+                    ELSE ST_AsPNG(ST_MapAlgebra(
+                        rast, 1, '8BUI',
+                        format('1 + 254 * ([rast] - %s) / %s', stats.min, greatest(stats.max - stats.min, 1e-9)),
+                        0
+                    ))
+                END AS png, ST_Envelope(rast) AS bounds
+                FROM sized, ST_SummaryStats(rast, 1) AS stats
+            )
+            SELECT translate(encode(png, 'base64'), E'\\n', ''),
+                ST_YMin(bounds), ST_XMin(bounds), ST_YMax(bounds), ST_XMax(bounds)
+            FROM stretched",
+            schema, source
+        )
+        .as_str(),
+        &[
+            &envelope[0],
+            &envelope[1],
+            &envelope[2],
+            &envelope[3],
+            &srid,
+            &(width as f64),
+            &(height as f64),
+        ],
+    ).await {
+        Ok(val) => val,
+        Err(err) => return Err(format!("ERROR! Failed to render raster: {}", err)),
+    };
+
+    match tile {
+        Some(row) => Ok(serde_json::json!({
+            "png": row.get::<usize, String>(0),
+            "bounds": [
+                [row.get::<usize, f64>(1), row.get::<usize, f64>(2)],
+                [row.get::<usize, f64>(3), row.get::<usize, f64>(4)],
+            ],
+        })
+        .to_string()),
+        None => Ok("null".to_string()),
+    }
+}
+
 async fn describe(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
+    name: &str,
+    description: &str,
+    contact_email: &str,
+    contact_phone: &str,
+    contact_website: &str,
+    state: &Mutex<AppState>,
 ) -> Result<Output, ()> {
     let mut output = Output {
         errors: vec![],
         results: vec![],
     };
 
-    let mut state = state.lock().await;
-    let _ = &state.app_handle.emit("loading", 10);
-
-    if ast["args"].len() < 6 {
-        output
-            .errors
-            .push("ERROR! You must provide a name, description, contact email, contact phone, and contact website.".to_string());
-        return Ok(output);
-    }
-
-    let name = ast["args"][1].to_string();
-    let description = ast["args"][2].to_string();
-    let contact_email = ast["args"][3].to_string();
-    let contact_phone = ast["args"][4].to_string();
-    let contact_website = ast["args"][5].to_string();
+    let (events, database) = {
+        let state = state.lock().await;
+        (state.events.clone(), state.database())
+    };
+    events.emit("loading", 10);
 
-    let _ = &state.app_handle.emit("loading", 50);
-    let client = Client::connect(&state.pgsql_connection.pg_string(), NoTls);
+    events.emit("loading", 50);
+    let client = database.client().await;
 
     match client {
-        Ok(mut client) => {
+        Ok(client) => {
             let _ = client.execute(
                 "CREATE TABLE IF NOT EXISTS hytigre_description (id SERIAL PRIMARY KEY, name TEXT, description TEXT, contact_email TEXT, contact_phone TEXT, contact_website TEXT)",
                 &[]
-            );
-            let _ = client.execute("DELETE FROM hytigre_description", &[]);
+            ).await;
+            let _ = client.execute("DELETE FROM hytigre_description", &[]).await;
             let _ = client.execute(
                 "INSERT INTO hytigre_description (name, description, contact_email, contact_phone, contact_website) VALUES ($1, $2, $3, $4, $5)",
                 &[&name, &description, &contact_email, &contact_phone, &contact_website]
-            );
-            let _ = &state.app_handle.emit("loading", 90);
-            output.results.push("Done.".to_string());
+            ).await;
+            events.emit("loading", 90);
+            output.message("Done.");
         }
-        Err(_) => {
-            output
-                .errors
-                .push("ERROR! Failed to connect to database.".to_string());
+        Err(err) => {
+            output.error(ErrorCode::Connection, format!("ERROR! {}", err));
         }
     }
 
-    let _ = &state.app_handle.emit("loading", 0);
+    events.emit("loading", 0);
     Ok(output)
 }
 
-async fn db_connect(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
+/// Creates, lists or drops the schemas of the database, for `db schema`.
+async fn manage_schemas(
+    action: &str,
+    name: Option<&str>,
+    cascade: bool,
+    state: &Mutex<AppState>,
 ) -> Result<Output, ()> {
     let mut output = Output {
         errors: vec![],
         results: vec![],
     };
 
-    if ast["args"].len() == 1 {
-        output
-            .errors
-            .push("ERROR! You must provide a pgsql connection string.".to_string());
+    let (connected, database) = {
+        let state = state.lock().await;
+        (state.pgsql_pool.is_some(), state.database())
+    };
+    if !connected {
+        output.error(
+            ErrorCode::NotConnected,
+            "ERROR! You must connect to a database before managing its schemas.",
+        );
         return Ok(output);
     }
 
-    if ast["args"].len() < 6 {
-        output
-            .errors
-            .push("ERROR! You must provide a username, password, host, port, and database to connect to PostgreSQL.".to_string());
-        return Ok(output);
+    let client = match database.client().await {
+        Ok(val) => val,
+        Err(err) => {
+            output.error(ErrorCode::Connection, format!("ERROR! {}", err));
+            return Ok(output);
+        }
+    };
+
+    let name = match (action, name) {
+        ("list", _) => "",
+        ("create" | "drop", Some(name)) => name,
+        ("create" | "drop", None) => {
+            output.error(
+                ErrorCode::Parse,
+                format!("ERROR! Give the name of the schema to {}.", action),
+            );
+            return Ok(output);
+        }
+        _ => {
+            output.error(
+                ErrorCode::Parse,
+                format!(
+                    "ERROR! Unknown action '{}'. Use create, list or drop.",
+                    action
+                ),
+            );
+            return Ok(output);
+        }
+    };
+
+    match action {
+        "list" => match client
+            .query(
+                "SELECT n.nspname::text, count(c.oid)
+                FROM pg_namespace AS n
+                LEFT JOIN pg_class AS c ON c.relnamespace = n.oid AND c.relkind IN ('r', 'v', 'm', 'p')
+                WHERE n.nspname NOT LIKE 'pg\\_%' AND n.nspname <> 'information_schema'
+                GROUP BY n.nspname
+                ORDER BY n.nspname",
+                &[],
+            )
+            .await
+        {
+            Ok(rows) => output.results.push(OutputResult::Table {
+                name: "schemas".to_string(),
+                columns: vec!["schema".to_string(), "tables".to_string()],
+                rows: rows
+                    .iter()
+                    .map(|row| {
+                        vec![
+                            serde_json::Value::from(row.get::<usize, String>(0)),
+                            serde_json::Value::from(row.get::<usize, i64>(1)),
+                        ]
+                    })
+                    .collect(),
+            }),
+            Err(err) => output.error(
+                ErrorCode::Query,
+                format!("ERROR! Failed to list schemas: {}", err),
+            ),
+        },
+        "create" => match client
+            .execute(
+                format!("CREATE SCHEMA {}", quote_identifier(name)).as_str(),
+                &[],
+            )
+            .await
+        {
+            Ok(_) => output.message(format!("Created schema '{}'.", name)),
+            Err(err) => output.error(
+                ErrorCode::Query,
+                format!("ERROR! Failed to create schema '{}': {}", name, err),
+            ),
+        },
+        _ => {
+            let cascade = match cascade {
+                true => " CASCADE",
+                false => "",
+            };
+            match client
+                .execute(
+                    format!("DROP SCHEMA {}{}", quote_identifier(name), cascade).as_str(),
+                    &[],
+                )
+                .await
+            {
+                Ok(_) => output.message(format!("Dropped schema '{}'.", name)),
+                Err(err) => output.error(
+                    ErrorCode::Query,
+                    format!(
+                        "ERROR! Failed to drop schema '{}': {}. Use `cascade=true` to drop its \
+                        tables too.",
+                        name, err
+                    ),
+                ),
+            }
+        }
     }
 
-    let mut state = state.lock().await;
+    Ok(output)
+}
 
-    let _ = &state.app_handle.emit("loading", 10);
-    let _ = &state.app_handle.emit("wipe-layers", true);
+/// Saves, lists or deletes connection profiles, for `db profile`.
+async fn manage_profiles(
+    action: &str,
+    name: Option<&str>,
+    save_password: bool,
+    ctx: &Context,
+) -> Result<Output, ()> {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
 
-    state.pgsql_connection = PGConnection {
-        username: ast["args"][1].to_string(),
-        password: ast["args"][2].to_string(),
-        host: ast["args"][3].to_string(),
-        port: ast["args"][4].to_string(),
-        db: ast["args"][5].to_string(),
-        optional_params: None,
+    let name = match (action, name) {
+        ("list", _) => "",
+        ("save" | "delete", Some(name)) => name,
+        ("save" | "delete", None) => {
+            output.error(
+                ErrorCode::Parse,
+                format!("ERROR! Give the name of the profile to {}.", action),
+            );
+            return Ok(output);
+        }
+        _ => {
+            output.error(
+                ErrorCode::Parse,
+                format!(
+                    "ERROR! Unknown action '{}'. Use save, list or delete.",
+                    action
+                ),
+            );
+            return Ok(output);
+        }
     };
 
-    if ast["args"].len() == 7 {
-        state.pgsql_connection.optional_params = Some(ast["args"][6].to_string());
+    match action {
+        "save" => {
+            let connection = ctx.state.lock().await.pgsql_connection.clone();
+            if connection == PGConnection::default() {
+                output.error(
+                    ErrorCode::NotConnected,
+                    "ERROR! Connect to the database before saving it as a profile.",
+                );
+                return Ok(output);
+            }
+
+            let profile = connection.profile();
+            if let Err(err) = ctx.profiles.save(name, profile.clone()) {
+                output.error(ErrorCode::Io, format!("ERROR! {}", err));
+                return Ok(output);
+            }
+            output.message(format!(
+                "Saved profile '{}'. Connect with `db connect {}`.",
+                name, name
+            ));
+
+            if save_password && !connection.password.is_empty() {
+                if let Err(err) = save_pgpass_password(
+                    &profile.host,
+                    &profile.port,
+                    &profile.database,
+                    &profile.username,
+                    &connection.password,
+                ) {
+                    output.warning(
+                        ErrorCode::Io,
+                        format!("WARNING! Couldn't save the password: {}", err),
+                    );
+                }
+            }
+        }
+        "list" => match ctx.profiles.list() {
+            Ok(profiles) => output.results.push(OutputResult::Table {
+                name: "profiles".to_string(),
+                columns: vec![
+                    "name".to_string(),
+                    "connection".to_string(),
+                    "password".to_string(),
+                ],
+                rows: profiles
+                    .iter()
+                    .map(|(name, profile)| {
+                        let password = pgpass_password(
+                            &profile.host,
+                            &profile.port,
+                            &profile.database,
+                            &profile.username,
+                        );
+                        vec![
+                            serde_json::Value::from(name.as_str()),
+                            serde_json::Value::from(
+                                PGConnection::from_profile(profile, String::new())
+                                    .redacted_string(),
+                            ),
+                            serde_json::Value::from(match password {
+                                Some(_) => "saved",
+                                None => "not saved",
+                            }),
+                        ]
+                    })
+                    .collect(),
+            }),
+            Err(err) => output.error(ErrorCode::Io, format!("ERROR! {}", err)),
+        },
+        _ => match ctx.profiles.delete(name) {
+            Ok(true) => output.message(format!("Deleted profile '{}'.", name)),
+            Ok(false) => output.error(
+                ErrorCode::Parse,
+                format!("ERROR! There's no profile named '{}'.", name),
+            ),
+            Err(err) => output.error(ErrorCode::Io, format!("ERROR! {}", err)),
+        },
     }
 
-    let client = Client::connect(&state.pgsql_connection.pg_string(), NoTls);
+    Ok(output)
+}
+
+/// Lists the user tables of the database as (schema, table) pairs, leaving out PostGIS and
+/// TIGRE bookkeeping tables.
+pub async fn list_layers(
+    client: &tokio_postgres::Client,
+) -> Result<Vec<(String, String)>, tokio_postgres::Error> {
+    let rows = client.query("SELECT table_name, table_schema FROM information_schema.tables WHERE table_schema != 'pg_catalog' AND table_schema != 'information_schema' AND table_name != 'geometry_columns' AND table_name != 'geography_columns' AND table_name != 'spatial_ref_sys' AND table_name != 'raster_overviews' AND table_name != 'raster_columns' AND table_name != 'hytigre_description' AND table_name != 'tigre_metadata'", &[]).await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get::<usize, String>(1), row.get::<usize, String>(0)))
+        .collect())
+}
+
+async fn db_connect(connection: PGConnection, state: &Mutex<AppState>) -> Result<Output, ()> {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
+
+    // The lock is only held to swap the connection, never while waiting on the server
+    let events = {
+        let mut state = state.lock().await;
+        if let Some(pool) = state.pgsql_pool.take() {
+            pool.close();
+        }
+        state.pgsql_connection = PGConnection::default();
+        state.events.clone()
+    };
+
+    events.emit("loading", 10);
+    events.emit("wipe-layers", true);
+
+    let client = match connection.pool() {
+        Ok(pool) => match pool.get().await {
+            Ok(client) => {
+                let mut state = state.lock().await;
+                state.pgsql_connection = connection.clone();
+                state.pgsql_pool = Some(pool);
+                Ok(client)
+            }
+            Err(err) => Err(err.to_string()),
+        },
+        Err(err) => Err(err),
+    };
     let _ = fs::create_dir("/tmp/tigre");
-    let _ = &state.app_handle.emit("loading", 25);
+    events.emit("loading", 25);
 
     match client {
-        Ok(mut client) => {
-            let tables_result = &client.query("SELECT table_name, table_schema FROM information_schema.tables WHERE table_schema != 'pg_catalog' AND table_schema != 'information_schema' AND table_name != 'geometry_columns' AND table_name != 'geography_columns' AND table_name != 'spatial_ref_sys' AND table_name != 'raster_overviews' AND table_name != 'raster_columns' AND table_name != 'hytigre_description'", &[]);
-            match tables_result {
-                Ok(tables_result) => {
-                    let _ = &state.app_handle.emit("loading", 75);
-                    if !tables_result.is_empty() {
-                        for row in tables_result {
-                            let schema = row.get::<usize, &str>(1);
-                            let name = row.get::<usize, &str>(0);
-
-                            postgis_layer_to_gpkg(
-                                name,
-                                schema,
-                                state.pgsql_connection.gdal_string(),
-                            )
-                            .await;
-
-                            let _ = &state.app_handle.emit("add-vector-layer", [name, schema]);
+        Ok(client) => {
+            match list_layers(&client).await {
+                Ok(layers) => {
+                    events.emit("loading", 75);
+                    // GDAL blocks, so the layers are cached off the async runtime
+                    let (recache_events, recache_connection) = (events.clone(), connection.clone());
+                    let recached = tokio::task::spawn_blocking(move || {
+                        let mut warnings = vec![];
+                        for (schema, name) in layers {
+                            let gdal_string = recache_connection.gdal_string();
+                            match postgis_layer_to_gpkg(&name, &schema, gdal_string) {
+                                Ok(_) => recache_events.emit("add-vector-layer", [name, schema]),
+                                Err(err) => warnings.push(format!(
+                                    "WARNING! Couldn't show {}.{} on the map: {}",
+                                    schema,
+                                    name,
+                                    recache_connection.redact(&err)
+                                )),
+                            }
                         }
+                        warnings
+                    })
+                    .await;
+                    match recached {
+                        Ok(warnings) => {
+                            for warning in warnings {
+                                output.warning(ErrorCode::Dataset, warning);
+                            }
+                        }
+                        Err(_) => output.error(
+                            ErrorCode::Dataset,
+                            "ERROR! Caching the layers for the map stopped unexpectedly.",
+                        ),
                     }
-                    let _ = &state.app_handle.emit("loading", 90);
+                    events.emit("loading", 90);
                 }
                 Err(_) => {
-                    let _ = &state.app_handle.emit("loading", 0);
-                    output
-                        .errors
-                        .push("ERROR! Failed to load layers from database.".to_string());
+                    events.emit("loading", 0);
+                    output.error(
+                        ErrorCode::Query,
+                        "ERROR! Failed to load layers from database.",
+                    );
                 }
             }
-            output.results.push("Connected to database".to_string());
+            output.message("Connected to database");
         }
-        Err(_) => {
-            output
-                .errors
-                .push("ERROR! Failed to connect to database.".to_string());
-            state.pgsql_connection = PGConnection::default();
-            let _ = &state.app_handle.emit("loading", 0);
+        Err(err) => {
+            output.error(
+                ErrorCode::Connection,
+                format!(
+                    "ERROR! Failed to connect to database: {}",
+                    connection.redact(&err)
+                ),
+            );
+            events.emit("loading", 0);
         }
     }
 
-    let _ = &state.app_handle.emit("loading", 0);
+    events.emit("loading", 0);
     Ok(output)
 }
 
-pub async fn db(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
-) -> Result<Output, ()> {
-    let mut output = Output {
-        errors: vec![],
-        results: vec![],
-    };
+pub const DB_CONNECT_OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "sslmode",
+        kind: OptionKind::Text,
+        default: None,
+        description: "SSL mode to use (disable, prefer, require, ...).",
+    },
+    OptionSpec {
+        name: "connect_timeout",
+        kind: OptionKind::Integer,
+        default: None,
+        description: "Seconds to wait for the connection before giving up.",
+    },
+    OptionSpec {
+        name: "application_name",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Name reported to the server in pg_stat_activity.",
+    },
+];
 
-    if ast["args"].is_empty() {
-        output
-            .errors
-            .push("ERROR! No arguments provided for command 'db'.".to_string())
-    } else {
-        match ast["args"][0] {
-            "connect" => {
-                let db_connect_output = db_connect(ast, state).await.unwrap();
-                output.errors.extend(db_connect_output.errors);
-                output.results.extend(db_connect_output.results);
-            }
-            "current" => {
-                output
-                    .results
-                    .extend(vec![state.lock().await.pgsql_connection.pg_string()]);
-            }
-            "describe" => {
-                let db_describe_output = describe(ast, state).await.unwrap();
-                output.errors.extend(db_describe_output.errors);
-                output.results.extend(db_describe_output.results);
-            }
-            &_ => output
-                .errors
-                .push("ERROR! Found unknown argument.".to_string()),
+const fn text_arg(name: &'static str, description: &'static str) -> ArgSpec {
+    ArgSpec {
+        name,
+        kind: ArgKind::Text,
+        required: true,
+        description,
+    }
+}
+
+static DB_SCHEMA: CommandSchema = CommandSchema {
+    name: "db",
+    summary: "Connect to and manage the PostGIS database.",
+    args: &[],
+    options: &[],
+    subcommands: &[
+        CommandSchema {
+            name: "connect",
+            summary: "Connect to a database and load its tables onto the map.",
+            args: &[
+                text_arg(
+                    "name",
+                    "Saved profile or pg_service.conf service, or the user to log in as.",
+                ),
+                ArgSpec {
+                    name: "password",
+                    kind: ArgKind::Secret,
+                    required: false,
+                    description: "Password of the user. Empty to look it up in ~/.pgpass.",
+                },
+                ArgSpec {
+                    name: "host",
+                    kind: ArgKind::Text,
+                    required: false,
+                    description: "Host name or address of the server.",
+                },
+                ArgSpec {
+                    name: "port",
+                    kind: ArgKind::Port,
+                    required: false,
+                    description: "Port the server listens on.",
+                },
+                ArgSpec {
+                    name: "database",
+                    kind: ArgKind::Text,
+                    required: false,
+                    description: "Name of the database.",
+                },
+            ],
+            options: DB_CONNECT_OPTIONS,
+            subcommands: &[],
+            examples: &[
+                "db connect postgres secret localhost 5432 gis ? sslmode=require",
+                "db connect postgres `` localhost 5432 gis",
+                "db connect work",
+            ],
+        },
+        CommandSchema {
+            name: "current",
+            summary: "Show the connection string of the current database, without its password.",
+            args: &[],
+            options: &[],
+            subcommands: &[],
+            examples: &["db current"],
+        },
+        CommandSchema {
+            name: "describe",
+            summary: "Record who owns the database and what it holds.",
+            args: &[
+                text_arg("name", "Display name of the database."),
+                text_arg("description", "What the database contains."),
+                text_arg("contact_email", "Email address of the maintainer."),
+                text_arg("contact_phone", "Phone number of the maintainer."),
+                text_arg("contact_website", "Website of the maintainer."),
+            ],
+            options: &[],
+            subcommands: &[],
+            examples: &[
+                "db describe Rivers `River network of Europe` me@example.com 555-0100 example.com",
+            ],
+        },
+        CommandSchema {
+            name: "schema",
+            summary: "Create, list or drop the schemas of the database.",
+            args: &[
+                text_arg("action", "create, list or drop."),
+                ArgSpec {
+                    name: "name",
+                    kind: ArgKind::Text,
+                    required: false,
+                    description: "Schema to create or drop.",
+                },
+            ],
+            options: &[OptionSpec {
+                name: "cascade",
+                kind: OptionKind::Boolean,
+                default: Some("false"),
+                description: "Also drop the tables of the schema.",
+            }],
+            subcommands: &[],
+            examples: &[
+                "db schema list",
+                "db schema create staging",
+                "db schema drop staging ? cascade=true",
+            ],
+        },
+        CommandSchema {
+            name: "profile",
+            summary: "Save, list or delete named connection profiles.",
+            args: &[
+                text_arg("action", "save, list or delete."),
+                ArgSpec {
+                    name: "name",
+                    kind: ArgKind::Text,
+                    required: false,
+                    description: "Profile to save the current connection as, or to delete.",
+                },
+            ],
+            options: &[OptionSpec {
+                name: "save_password",
+                kind: OptionKind::Boolean,
+                default: Some("true"),
+                description: "Keep the password in ~/.pgpass, readable only by you.",
+            }],
+            subcommands: &[],
+            examples: &[
+                "db profile save work",
+                "db profile list",
+                "db profile delete work",
+            ],
+        },
+    ],
+    examples: &[],
+};
+
+pub struct DbCommand;
+
+impl Command for DbCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &DB_SCHEMA
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            Ok(match args.subcommand() {
+                Some("connect") => {
+                    match resolve_connection(args.text("name")?, &args, &ctx.profiles) {
+                        Ok(connection) => db_connect(connection, &ctx.state).await.unwrap(),
+                        Err(err) => {
+                            let mut output = Output::default();
+                            output.error(ErrorCode::Connection, format!("ERROR! {}", err));
+                            output
+                        }
+                    }
+                }
+                Some("current") => {
+                    let mut output = Output::default();
+                    let state = ctx.state.lock().await;
+                    output.message(state.pgsql_connection.redacted_string());
+                    if let Some(pool) = &state.pgsql_pool {
+                        let status = pool.status();
+                        output.message(format!(
+                            "{} of {} pooled connections open, {} idle.",
+                            status.size, status.max_size, status.available
+                        ));
+                    }
+                    output
+                }
+                Some("describe") => describe(
+                    args.text("name")?,
+                    args.text("description")?,
+                    args.text("contact_email")?,
+                    args.text("contact_phone")?,
+                    args.text("contact_website")?,
+                    &ctx.state,
+                )
+                .await
+                .unwrap(),
+                Some("profile") => manage_profiles(
+                    args.text("action")?,
+                    args.optional_text("name"),
+                    args.options.boolean("save_password"),
+                    ctx,
+                )
+                .await
+                .unwrap(),
+                Some("schema") => manage_schemas(
+                    args.text("action")?,
+                    args.optional_text("name"),
+                    args.options.boolean("cascade"),
+                    &ctx.state,
+                )
+                .await
+                .unwrap(),
+                _ => return Err(args.unknown_subcommand()),
+            })
         }
+        .boxed()
     }
-    Ok(output)
 }
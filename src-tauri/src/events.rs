@@ -0,0 +1,23 @@
+use serde::Serialize;
+
+/// Receives the events commands send while they run ("loading", "add-vector-layer",
+/// "open-table", ...). The desktop app forwards them to the frontend, the CLI prints them.
+pub trait EventSink: Send + Sync {
+    fn emit_json(&self, event: &str, payload: serde_json::Value);
+}
+
+impl dyn EventSink {
+    pub fn emit<S: Serialize>(&self, event: &str, payload: S) {
+        match serde_json::to_value(payload) {
+            Ok(payload) => self.emit_json(event, payload),
+            Err(err) => eprintln!("ERROR! Failed to serialize '{}' event: {}", event, err),
+        }
+    }
+}
+
+/// Drops every event.
+pub struct NoEvents;
+
+impl EventSink for NoEvents {
+    fn emit_json(&self, _event: &str, _payload: serde_json::Value) {}
+}
@@ -1,253 +1,1851 @@
-use gdal::spatial_ref::SpatialRef;
-use gdal::vector::{Geometry, LayerAccess, LayerOptions};
-use gdal::{Dataset, DriverManager};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use encoding_rs::Encoding;
+use gdal::raster::GdalDataType;
+use gdal::spatial_ref::{AxisMappingStrategy, CoordTransform, SpatialRef};
+use gdal::vector::{Feature, FieldValue, Geometry, Layer, LayerAccess, LayerOptions};
+use gdal::{Dataset, DatasetOptions, DriverManager, GdalOpenFlags, Metadata};
+use gdal_sys::{OGRFieldSubType, OGRFieldType};
+use std::ffi::CStr;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::{Client, Transaction};
 
-pub async fn generic_to_postgis_layer(
-    dataset: Dataset,
-    mut pgsql_client: postgres::Client,
-    name: &str,
-) {
-    let mut fields: Vec<String> = vec![];
-    let mut geometries: Vec<Geometry> = vec![];
-    let mut geometry_type = String::new();
-
-    dataset.layers()
-        .for_each(| mut lyr | {
-            // COLLECT LAYER GEOMETRIES
-            lyr.features()
-                .for_each(| feature | {
-                    match feature.geometry() {
-                        Some(geometry) => geometries.push(geometry.clone()),
-                        _ => ()
-                    }
-                });
+/// The SRS the geometries of a layer are stored in, and how to get them there.
+pub struct LayerSrs {
+    /// SRS of the stored geometries, if known.
+    pub spatial_ref: Option<SpatialRef>,
+    /// EPSG code of `spatial_ref`, or 0 if it's unknown or has none.
+    pub srid: i32,
+    /// Reprojects the source geometries, when a target SRS was given.
+    transform: Option<CoordTransform>,
+}
 
-            // COLLECT FIELD TYPES
-            for geometry in geometries.clone() {
-                if geometry_type == "" {
-                    geometry_type = geometry.geometry_name();
-                    continue;
-                }
+impl LayerSrs {
+    /// Works out the SRS of `layer` from its `.prj` or the like. `srid` overrides the SRS of
+    /// the source, and `t_srs` (anything GDAL understands, e.g. `EPSG:3857`) reprojects into
+    /// another.
+    pub fn new(layer: &Layer, srid: Option<i64>, t_srs: Option<&str>) -> Result<LayerSrs, String> {
+        let source = match srid {
+            Some(srid) => match SpatialRef::from_epsg(srid as u32) {
+                Ok(val) => Some(val),
+                Err(err) => return Err(format!("Unknown SRID {}: {}", srid, err)),
+            },
+            None => layer.spatial_ref(),
+        };
 
-                if geometry_type != geometry.geometry_name() {
-                    panic!("ERROR! Some features in layer have mismatched geometries. Expected {}, but got {}.", geometry_type, geometry.geometry_name());
+        let (spatial_ref, transform) = match (source, t_srs) {
+            (Some(mut source), Some(t_srs)) => {
+                let mut target = match SpatialRef::from_definition(t_srs) {
+                    Ok(val) => val,
+                    Err(err) => return Err(format!("Unknown SRS '{}': {}", t_srs, err)),
+                };
+                // Keep x as the longitude, whatever the authority says
+                source.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+                target.set_axis_mapping_strategy(AxisMappingStrategy::TraditionalGisOrder);
+                match CoordTransform::new(&source, &target) {
+                    Ok(val) => (Some(target), Some(val)),
+                    Err(err) => return Err(format!("Can't reproject to '{}': {}", t_srs, err)),
                 }
             }
+            (None, Some(_)) => {
+                return Err(format!(
+                    "Layer '{}' has no SRS to reproject from. Give it one with `srid=`.",
+                    layer.name()
+                ))
+            }
+            (source, None) => (source, None),
+        };
 
-            lyr.defn()
-                .fields()
-                .for_each(| f | {
-                    let pg_field_type = match f.field_type() {
-                        8 => "bytea",
-                        9 => "date",
-                        11 => "timestamp",
-                        0 => "integer",
-                        12 => "bigint",
-                        13 => "bigint[]",
-                        1 => "integer[]",
-                        2 => "numeric",
-                        3 => "numeric[]",
-                        4 => "text",
-                        5 => "text[]",
-                        10 => "time",
-                        6 => "text",
-                        7 => "text[]",
-                        _ => "text"
-                    };
-                    fields.push(format!("{} {}", f.name(), pg_field_type));
-                });
+        Ok(LayerSrs {
+            srid: spatial_ref.as_ref().and_then(epsg_code).unwrap_or(0),
+            spatial_ref,
+            transform,
+        })
+    }
+
+    /// `geometry` in the SRS it's stored in.
+    fn reproject(&self, geometry: &Geometry) -> Result<Geometry, String> {
+        match &self.transform {
+            Some(transform) => geometry
+                .transform(transform)
+                .map_err(|err| format!("Failed to reproject a geometry: {}", err)),
+            None => Ok(geometry.clone()),
+        }
+    }
+}
+
+/// The EPSG code of `spatial_ref`, if it has or can be matched to one.
+fn epsg_code(spatial_ref: &SpatialRef) -> Option<i32> {
+    let mut spatial_ref = spatial_ref.clone();
+    let _ = spatial_ref.auto_identify_epsg();
+    match spatial_ref.auth_name() {
+        Ok(name) if name == "EPSG" => spatial_ref.auth_code().ok(),
+        _ => None,
+    }
+}
+
+/// A table the features of a layer are written to.
+pub struct GeometryTable {
+    /// Appended to the name of the table when a layer is split by geometry type.
+    pub suffix: Option<String>,
+    /// OGR names of the geometries the table holds, e.g. `POLYGON` and `MULTIPOLYGON`.
+    geometry_names: Vec<String>,
+    /// Whether the table also holds the features without a geometry.
+    keeps_empty: bool,
+    /// PostGIS type of the `geom` column, e.g. `MULTIPOLYGON`, or `GEOMETRY` for a mix.
+    column_type: String,
+    /// OGR type of the geometry column in the GeoPackage cache.
+    ogr_type: u32,
+    /// Whether single geometries are promoted to their multi variant, with `ST_Multi`.
+    promote: bool,
+    /// Number of features in the table.
+    pub features: u64,
+    /// Whether the table has a `geom` column at all. Plain attribute tables don't.
+    pub spatial: bool,
+}
+
+impl GeometryTable {
+    /// A table without a geometry column, holding every feature of `layer`.
+    pub fn attributes_only(layer: &Layer) -> GeometryTable {
+        GeometryTable {
+            suffix: None,
+            geometry_names: vec![],
+            keeps_empty: true,
+            column_type: String::new(),
+            ogr_type: 0,
+            promote: false,
+            features: layer.feature_count(),
+            spatial: false,
+        }
+    }
+
+    fn contains(&self, geometry: Option<&Geometry>) -> bool {
+        match geometry {
+            Some(geometry) => self.geometry_names.contains(&geometry.geometry_name()),
+            None => self.keeps_empty,
+        }
+    }
+}
+
+/// The tables a layer is written to, and what had to be done to its geometries to fit them.
+pub struct GeometryPlan {
+    pub tables: Vec<GeometryTable>,
+    pub warnings: Vec<String>,
+}
+
+/// The name of a geometry type, its OGR code and how many features of a layer have it.
+type GeometryCount = (String, u32, u64);
+
+/// Works out how to store the geometries of `layer`. A mix of single and multi variants of
+/// a type, e.g. `POLYGON` and `MULTIPOLYGON`, is promoted to the multi variant. Any other
+/// mix is stored in a generic geometry column, or with `split` in one table per type.
+pub fn plan_geometry_tables(layer: &mut Layer, split: bool) -> GeometryPlan {
+    // (OGR name, OGR type, count) of every geometry type, in order of appearance
+    let mut types: Vec<GeometryCount> = vec![];
+    let mut empty = 0;
+    for feature in layer.features() {
+        let geometry = match feature.geometry() {
+            Some(val) => val,
+            None => {
+                empty += 1;
+                continue;
+            }
+        };
+
+        let name = geometry.geometry_name();
+        match types.iter_mut().find(|(val, _, _)| *val == name) {
+            Some((_, _, count)) => *count += 1,
+            None => types.push((name, geometry.geometry_type(), 1)),
+        }
+    }
+
+    // Group single and multi variants, e.g. POINT with MULTIPOINT
+    let mut families: Vec<(String, Vec<GeometryCount>)> = vec![];
+    for geometry_type in types.iter().cloned() {
+        let family = geometry_type.0.trim_start_matches("MULTI").to_string();
+        match families.iter_mut().find(|(val, _)| *val == family) {
+            Some((_, members)) => members.push(geometry_type),
+            None => families.push((family, vec![geometry_type])),
+        }
+    }
+
+    let mut warnings = vec![];
+    let family_table = |members: &[(String, u32, u64)], empty: u64, warnings: &mut Vec<String>| {
+        let (name, ogr_type, _) = members
+            .iter()
+            .max_by_key(|(name, _, _)| name.starts_with("MULTI"))
+            .unwrap();
+        let promote = members.len() > 1;
+        if promote {
+            let promoted = members
+                .iter()
+                .filter(|(val, _, _)| val != name)
+                .map(|(val, _, count)| format!("{} {}", count, val))
+                .collect::<Vec<String>>();
+            warnings.push(format!(
+                "Promoted {} geometries of layer '{}' to {}.",
+                promoted.join(", "),
+                layer.name(),
+                name
+            ));
+        }
+
+        GeometryTable {
+            suffix: None,
+            geometry_names: members.iter().map(|(val, _, _)| val.clone()).collect(),
+            keeps_empty: true,
+            column_type: name.clone(),
+            ogr_type: *ogr_type,
+            promote,
+            features: members.iter().map(|(_, _, count)| count).sum::<u64>() + empty,
+            spatial: true,
+        }
+    };
+
+    let tables = match families.len() {
+        // Layers without any geometry, e.g. plain tables in a GeoPackage, get a generic column
+        0 => vec![GeometryTable {
+            suffix: None,
+            geometry_names: vec![],
+            keeps_empty: true,
+            column_type: "GEOMETRY".to_string(),
+            ogr_type: 0,
+            promote: false,
+            features: empty,
+            spatial: true,
+        }],
+        1 => vec![family_table(&families[0].1, empty, &mut warnings)],
+        _ if split => {
+            if empty > 0 {
+                warnings.push(format!(
+                    "Skipped {} features of layer '{}' without a geometry.",
+                    empty,
+                    layer.name()
+                ));
+            }
+            let tables = families
+                .iter()
+                .map(|(family, members)| GeometryTable {
+                    suffix: Some(family.to_lowercase()),
+                    keeps_empty: false,
+                    ..family_table(members, 0, &mut warnings)
+                })
+                .collect::<Vec<GeometryTable>>();
+            warnings.push(format!(
+                "Split layer '{}' into one table per geometry type: {}.",
+                layer.name(),
+                families
+                    .iter()
+                    .map(|(family, _)| family.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ));
+            tables
+        }
+        _ => {
+            warnings.push(format!(
+                "Layer '{}' mixes {} geometries, so they're stored in a generic geometry \
+                column. Use `split=true` to get one table per geometry type.",
+                layer.name(),
+                types
+                    .iter()
+                    .map(|(name, _, _)| name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ));
+            vec![GeometryTable {
+                suffix: None,
+                geometry_names: types.iter().map(|(name, _, _)| name.clone()).collect(),
+                keeps_empty: true,
+                column_type: "GEOMETRY".to_string(),
+                ogr_type: 0,
+                promote: false,
+                features: types.iter().map(|(_, _, count)| count).sum::<u64>() + empty,
+                spatial: true,
+            }]
+        }
+    };
+
+    GeometryPlan { tables, warnings }
+}
+
+/// Number of features copied into the database between two progress updates.
+const COPY_BATCH_SIZE: u64 = 10_000;
+
+/// Longest identifier PostgreSQL keeps; longer ones are truncated.
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+/// Columns every table or its GeoPackage cache gets, which fields can't be named after.
+const RESERVED_COLUMNS: &[&str] = &["geom", "fid"];
+
+/// How the values of an OGR field are stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnKind {
+    Boolean,
+    SmallInt,
+    Integer,
+    BigInt,
+    Real,
+    Double,
+    Text,
+    Json,
+    Bytes,
+    Date,
+    Time,
+    Timestamp,
+    IntegerList,
+    BigIntList,
+    DoubleList,
+    TextList,
+}
+
+impl ColumnKind {
+    fn of(field_type: OGRFieldType::Type, sub_type: OGRFieldSubType::Type) -> ColumnKind {
+        match (field_type, sub_type) {
+            (OGRFieldType::OFTInteger, OGRFieldSubType::OFSTBoolean) => ColumnKind::Boolean,
+            (OGRFieldType::OFTInteger, OGRFieldSubType::OFSTInt16) => ColumnKind::SmallInt,
+            (OGRFieldType::OFTInteger, _) => ColumnKind::Integer,
+            (OGRFieldType::OFTInteger64, _) => ColumnKind::BigInt,
+            (OGRFieldType::OFTReal, OGRFieldSubType::OFSTFloat32) => ColumnKind::Real,
+            (OGRFieldType::OFTReal, _) => ColumnKind::Double,
+            (OGRFieldType::OFTString, OGRFieldSubType::OFSTJSON) => ColumnKind::Json,
+            (OGRFieldType::OFTBinary, _) => ColumnKind::Bytes,
+            (OGRFieldType::OFTDate, _) => ColumnKind::Date,
+            (OGRFieldType::OFTTime, _) => ColumnKind::Time,
+            (OGRFieldType::OFTDateTime, _) => ColumnKind::Timestamp,
+            (OGRFieldType::OFTIntegerList, _) => ColumnKind::IntegerList,
+            (OGRFieldType::OFTInteger64List, _) => ColumnKind::BigIntList,
+            (OGRFieldType::OFTRealList, _) => ColumnKind::DoubleList,
+            (OGRFieldType::OFTStringList | OGRFieldType::OFTWideStringList, _) => {
+                ColumnKind::TextList
+            }
+            _ => ColumnKind::Text,
+        }
+    }
+
+    /// The type values are sent to the database as.
+    fn sql_type(&self) -> Type {
+        match self {
+            ColumnKind::Boolean => Type::BOOL,
+            ColumnKind::SmallInt => Type::INT2,
+            ColumnKind::Integer => Type::INT4,
+            ColumnKind::BigInt => Type::INT8,
+            ColumnKind::Real => Type::FLOAT4,
+            ColumnKind::Double => Type::FLOAT8,
+            ColumnKind::Text => Type::TEXT,
+            ColumnKind::Json => Type::JSONB,
+            ColumnKind::Bytes => Type::BYTEA,
+            ColumnKind::Date => Type::DATE,
+            ColumnKind::Time => Type::TIME,
+            ColumnKind::Timestamp => Type::TIMESTAMP,
+            ColumnKind::IntegerList => Type::INT4_ARRAY,
+            ColumnKind::BigIntList => Type::INT8_ARRAY,
+            ColumnKind::DoubleList => Type::FLOAT8_ARRAY,
+            ColumnKind::TextList => Type::TEXT_ARRAY,
+        }
+    }
+
+    /// Whether values of this kind can be inserted into an existing column, by the `data_type`
+    /// and `udt_name` `information_schema.columns` has for it. Arrays are told apart by the
+    /// latter, which is the name of their element type with a leading underscore.
+    fn fits(&self, data_type: &str, udt_name: &str) -> bool {
+        match self {
+            ColumnKind::Boolean => data_type == "boolean",
+            ColumnKind::SmallInt
+            | ColumnKind::Integer
+            | ColumnKind::BigInt
+            | ColumnKind::Real
+            | ColumnKind::Double => [
+                "smallint",
+                "integer",
+                "bigint",
+                "real",
+                "double precision",
+                "numeric",
+            ]
+            .contains(&data_type),
+            ColumnKind::Text => ["text", "character varying", "character"].contains(&data_type),
+            ColumnKind::Json => ["jsonb", "json"].contains(&data_type),
+            ColumnKind::Bytes => data_type == "bytea",
+            ColumnKind::Date => data_type == "date" || data_type.starts_with("timestamp"),
+            ColumnKind::Time => data_type.starts_with("time "),
+            ColumnKind::Timestamp => data_type.starts_with("timestamp"),
+            ColumnKind::IntegerList | ColumnKind::BigIntList => {
+                ["_int2", "_int4", "_int8", "_numeric"].contains(&udt_name)
+            }
+            ColumnKind::DoubleList => ["_float4", "_float8", "_numeric"].contains(&udt_name),
+            ColumnKind::TextList => ["_text", "_varchar", "_bpchar"].contains(&udt_name),
+        }
+    }
+
+    fn null(&self) -> Box<dyn ToSql + Sync> {
+        match self {
+            ColumnKind::Boolean => Box::new(None::<bool>),
+            ColumnKind::SmallInt => Box::new(None::<i16>),
+            ColumnKind::Integer => Box::new(None::<i32>),
+            ColumnKind::BigInt => Box::new(None::<i64>),
+            ColumnKind::Real => Box::new(None::<f32>),
+            ColumnKind::Double => Box::new(None::<f64>),
+            ColumnKind::Text => Box::new(None::<String>),
+            ColumnKind::Json => Box::new(None::<serde_json::Value>),
+            ColumnKind::Bytes => Box::new(None::<Vec<u8>>),
+            ColumnKind::Date => Box::new(None::<NaiveDate>),
+            ColumnKind::Time => Box::new(None::<NaiveTime>),
+            ColumnKind::Timestamp => Box::new(None::<NaiveDateTime>),
+            ColumnKind::IntegerList => Box::new(None::<Vec<i32>>),
+            ColumnKind::BigIntList => Box::new(None::<Vec<i64>>),
+            ColumnKind::DoubleList => Box::new(None::<Vec<f64>>),
+            ColumnKind::TextList => Box::new(None::<Vec<String>>),
+        }
+    }
+
+    fn value(&self, value: Option<FieldData>) -> Box<dyn ToSql + Sync> {
+        match value {
+            Some(FieldData::Value(value)) => self.field_value(value),
+            Some(FieldData::Time(val)) => Box::new(val),
+            Some(FieldData::Binary(val)) => Box::new(val),
+            None => self.null(),
+        }
+    }
+
+    fn field_value(&self, value: FieldValue) -> Box<dyn ToSql + Sync> {
+        match (self, Some(value)) {
+            (ColumnKind::Boolean, Some(FieldValue::IntegerValue(val))) => Box::new(val != 0),
+            (ColumnKind::SmallInt, Some(FieldValue::IntegerValue(val))) => Box::new(val as i16),
+            (ColumnKind::Real, Some(FieldValue::RealValue(val))) => Box::new(val as f32),
+            (ColumnKind::Json, Some(FieldValue::StringValue(val))) => Box::new(
+                serde_json::from_str::<serde_json::Value>(&val)
+                    .unwrap_or(serde_json::Value::String(val)),
+            ),
+            (_, Some(FieldValue::IntegerValue(val))) => Box::new(val),
+            (_, Some(FieldValue::IntegerListValue(val))) => Box::new(val),
+            (_, Some(FieldValue::Integer64Value(val))) => Box::new(val),
+            (_, Some(FieldValue::Integer64ListValue(val))) => Box::new(val),
+            (_, Some(FieldValue::RealValue(val))) => Box::new(val),
+            (_, Some(FieldValue::RealListValue(val))) => Box::new(val),
+            (_, Some(FieldValue::StringValue(val))) => Box::new(val),
+            (_, Some(FieldValue::StringListValue(val))) => Box::new(val),
+            (_, Some(FieldValue::DateValue(val))) => Box::new(val),
+            (_, Some(FieldValue::DateTimeValue(val))) => Box::new(val.naive_local()),
+            (_, None) => self.null(),
+        }
+    }
+}
+
+/// A value read from a field. [`FieldValue`] has no variant for times or binary data.
+#[derive(Debug, PartialEq)]
+enum FieldData {
+    Value(FieldValue),
+    Time(NaiveTime),
+    Binary(Vec<u8>),
+}
+
+/// A column a field of a layer is written to.
+#[derive(Debug, Clone)]
+pub struct Column {
+    /// Name of the field in the source.
+    pub field: String,
+    /// Name of the column, which differs from the field's when that's taken or too long.
+    pub name: String,
+    kind: ColumnKind,
+    /// OGR type of the field.
+    field_type: OGRFieldType::Type,
+    /// Type the column is created with, e.g. `varchar(80)`.
+    sql_type: String,
+    /// Type the column is converted to once the values are copied, e.g. `numeric(10, 2)`,
+    /// as the client can't send those directly.
+    final_type: Option<String>,
+    /// Encoding the text of the field is stored in.
+    encoding: &'static Encoding,
+}
+
+impl Column {
+    /// The column name, quoted for use in SQL.
+    pub fn quoted_name(&self) -> String {
+        quote_identifier(&self.name)
+    }
+}
+
+/// Quotes a table or column name, keeping its case and any reserved word intact.
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Cuts `name` down to at most `max_length` bytes, without splitting a character.
+fn truncate_identifier(name: &str, max_length: usize) -> &str {
+    let mut end = name.len().min(max_length);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// Subtype of the field `i` of `layer`, e.g. boolean for an integer field.
+fn field_sub_type(layer: &Layer, i: usize) -> OGRFieldSubType::Type {
+    // SAFETY: the definitions are owned by the layer, which outlives this call
+    unsafe {
+        let defn = gdal_sys::OGR_L_GetLayerDefn(layer.c_layer());
+        gdal_sys::OGR_Fld_GetSubType(gdal_sys::OGR_FD_GetFieldDefn(defn, i as i32))
+    }
+}
+
+/// Name of the field `i` of `layer`, decoded from `encoding`.
+fn field_name(layer: &Layer, i: usize, encoding: &'static Encoding) -> String {
+    // SAFETY: the definitions are owned by the layer, which outlives this call, and the name
+    // is copied before anything else is read from it
+    let bytes = unsafe {
+        let defn = gdal_sys::OGR_L_GetLayerDefn(layer.c_layer());
+        CStr::from_ptr(gdal_sys::OGR_Fld_GetNameRef(gdal_sys::OGR_FD_GetFieldDefn(
+            defn, i as i32,
+        )))
+        .to_bytes()
+    };
+    encoding.decode_without_bom_handling(bytes).0.into_owned()
+}
+
+/// Finds the encoding for a label of a `.cpg` file, GDAL or `encoding=`, e.g. `UTF-8`, `1252`,
+/// `CP1252`, `88591` or `latin1`.
+pub fn encoding_for_label(label: &str) -> Option<&'static Encoding> {
+    let label = label.trim();
+    if let Some(encoding) = Encoding::for_label(label.as_bytes()) {
+        return Some(encoding);
+    }
+
+    // Code pages are often written as bare numbers, as ArcGIS does in `.cpg` files
+    let upper = label.to_uppercase();
+    let code = upper
+        .trim_start_matches("ANSI")
+        .trim_start_matches("CP")
+        .trim_start_matches("WINDOWS-")
+        .trim();
+    let label = match code {
+        "932" => "shift_jis".to_string(),
+        "936" => "gbk".to_string(),
+        "949" => "euc-kr".to_string(),
+        "950" => "big5".to_string(),
+        _ if code.starts_with("8859") && code.len() > 4 => format!("iso-8859-{}", &code[4..]),
+        _ => format!("windows-{}", code),
+    };
+    Encoding::for_label(label.as_bytes())
+}
+
+/// Encoding a shapefile says its attributes are stored in, from its `.cpg` or else the
+/// language driver of its `.dbf`. Only known when it's opened without recoding.
+pub fn shapefile_encoding(layer: &Layer) -> Option<String> {
+    layer
+        .metadata_item("ENCODING_FROM_CPG", "SHAPEFILE")
+        .or_else(|| layer.metadata_item("ENCODING_FROM_LDID", "SHAPEFILE"))
+        .filter(|encoding| !encoding.is_empty())
+}
+
+/// Works out the column each field of `layer` is written to, with a warning for every field
+/// that had to be renamed. Text is decoded from `encoding` into UTF-8.
+pub fn plan_columns(layer: &Layer, encoding: &'static Encoding) -> (Vec<Column>, Vec<String>) {
+    let mut columns: Vec<Column> = vec![];
+    let mut warnings = vec![];
+
+    for (i, field) in layer.defn().fields().enumerate() {
+        let field_type = field.field_type();
+        let kind = ColumnKind::of(field_type, field_sub_type(layer, i));
+        let (sql_type, final_type) = match kind {
+            ColumnKind::Text if field.width() > 0 => (format!("varchar({})", field.width()), None),
+            ColumnKind::Double if field.width() > 0 && field.precision() > 0 => (
+                "double precision".to_string(),
+                Some(format!("numeric({}, {})", field.width(), field.precision())),
+            ),
+            _ => (sql_type_name(kind).to_string(), None),
+        };
+
+        // Fields named like a reserved column, or the same once truncated, get a suffix
+        let field_name = field_name(layer, i, encoding);
+        let is_taken = |name: &str| {
+            RESERVED_COLUMNS.contains(&name) || columns.iter().any(|column| column.name == name)
+        };
+        let mut name = truncate_identifier(&field_name, MAX_IDENTIFIER_LENGTH).to_string();
+        let mut n = 1;
+        while is_taken(&name) {
+            let suffix = format!("_{}", n);
+            let max_length = MAX_IDENTIFIER_LENGTH - suffix.len();
+            name = format!("{}{}", truncate_identifier(&field_name, max_length), suffix);
+            n += 1;
+        }
+        if name != field_name {
+            warnings.push(format!(
+                "Renamed field '{}' of layer '{}' to '{}'.",
+                field_name,
+                layer.name(),
+                name
+            ));
+        }
+
+        columns.push(Column {
+            field: field_name,
+            name,
+            kind,
+            field_type,
+            sql_type,
+            final_type,
+            encoding,
         });
+    }
 
-    // CREATE TABLE
-    match pgsql_client.execute(
-        format!(
-            "CREATE TABLE {} ({}, geom geometry)",
+    (columns, warnings)
+}
+
+/// The values of `feature` for each of `columns`. Text is read as the bytes in the source and
+/// decoded with the encoding of its column, with whether any of it had bytes that aren't valid
+/// in that encoding, which are replaced.
+fn feature_values(feature: &Feature, columns: &[Column]) -> (Vec<Option<FieldData>>, bool) {
+    let mut undecodable = false;
+    let values = (0..feature.field_count())
+        .zip(columns)
+        .map(|(i, column)| field_data(feature, i, column, &mut undecodable))
+        .collect();
+    (values, undecodable)
+}
+
+/// The value of the field `i` of `feature`, which is written to `column`. Fields are read by
+/// index, as [`Feature::fields`] skips the types it can't read and so shifts every field after
+/// them. Sets `undecodable` if text had bytes that aren't valid in the encoding of the column.
+fn field_data(
+    feature: &Feature,
+    i: i32,
+    column: &Column,
+    undecodable: &mut bool,
+) -> Option<FieldData> {
+    let mut decode = |bytes: &[u8]| {
+        let (text, had_errors) = column.encoding.decode_without_bom_handling(bytes);
+        *undecodable |= had_errors;
+        text.into_owned()
+    };
+
+    // SAFETY: the feature is alive for the whole call, and whatever it owns is copied before
+    // anything else is read from it
+    unsafe {
+        let c_feature = feature.c_feature();
+        if gdal_sys::OGR_F_IsFieldSetAndNotNull(c_feature, i) == 0 {
+            return None;
+        }
+
+        let value = match column.field_type {
+            OGRFieldType::OFTInteger => {
+                FieldValue::IntegerValue(feature.field_as_integer(i).ok()??)
+            }
+            OGRFieldType::OFTInteger64 => {
+                FieldValue::Integer64Value(feature.field_as_integer64(i).ok()??)
+            }
+            OGRFieldType::OFTReal => FieldValue::RealValue(feature.field_as_double(i).ok()??),
+            OGRFieldType::OFTDate => {
+                FieldValue::DateValue(feature.field_as_datetime(i).ok()??.date_naive())
+            }
+            OGRFieldType::OFTDateTime => {
+                FieldValue::DateTimeValue(feature.field_as_datetime(i).ok()??)
+            }
+            OGRFieldType::OFTTime => {
+                let (mut year, mut month, mut day, mut tz_flag) = (0, 0, 0, 0);
+                let (mut hour, mut minute, mut second) = (0, 0, 0.0);
+                if gdal_sys::OGR_F_GetFieldAsDateTimeEx(
+                    c_feature,
+                    i,
+                    &mut year,
+                    &mut month,
+                    &mut day,
+                    &mut hour,
+                    &mut minute,
+                    &mut second,
+                    &mut tz_flag,
+                ) == 0
+                {
+                    return None;
+                }
+                let whole = second.trunc();
+                let milli = (((second - whole) * 1000.0).round() as u32).min(999);
+                return NaiveTime::from_hms_milli_opt(
+                    hour as u32,
+                    minute as u32,
+                    whole as u32,
+                    milli,
+                )
+                .map(FieldData::Time);
+            }
+            OGRFieldType::OFTBinary => {
+                let mut len = 0;
+                let bytes = gdal_sys::OGR_F_GetFieldAsBinary(c_feature, i, &mut len);
+                return Some(FieldData::Binary(copy_list(bytes, len)));
+            }
+            OGRFieldType::OFTIntegerList => {
+                let mut len = 0;
+                let values = gdal_sys::OGR_F_GetFieldAsIntegerList(c_feature, i, &mut len);
+                FieldValue::IntegerListValue(copy_list(values, len))
+            }
+            OGRFieldType::OFTInteger64List => {
+                let mut len = 0;
+                let values = gdal_sys::OGR_F_GetFieldAsInteger64List(c_feature, i, &mut len);
+                FieldValue::Integer64ListValue(copy_list(values, len))
+            }
+            OGRFieldType::OFTRealList => {
+                let mut len = 0;
+                let values = gdal_sys::OGR_F_GetFieldAsDoubleList(c_feature, i, &mut len);
+                FieldValue::RealListValue(copy_list(values, len))
+            }
+            OGRFieldType::OFTStringList | OGRFieldType::OFTWideStringList => {
+                let mut values = vec![];
+                let mut item = gdal_sys::OGR_F_GetFieldAsStringList(c_feature, i);
+                while !item.is_null() && !(*item).is_null() {
+                    values.push(decode(CStr::from_ptr(*item).to_bytes()));
+                    item = item.add(1);
+                }
+                FieldValue::StringListValue(values)
+            }
+            _ => FieldValue::StringValue(decode(
+                CStr::from_ptr(gdal_sys::OGR_F_GetFieldAsString(c_feature, i)).to_bytes(),
+            )),
+        };
+        Some(FieldData::Value(value))
+    }
+}
+
+/// Copies the `len` values at `values`, which GDAL returns as null when there are none.
+///
+/// # Safety
+///
+/// `values` must be null or point to at least `len` values.
+unsafe fn copy_list<T: Copy>(values: *const T, len: i32) -> Vec<T> {
+    match values.is_null() || len <= 0 {
+        true => vec![],
+        false => std::slice::from_raw_parts(values, len as usize).to_vec(),
+    }
+}
+
+fn sql_type_name(kind: ColumnKind) -> &'static str {
+    match kind {
+        ColumnKind::Boolean => "boolean",
+        ColumnKind::SmallInt => "smallint",
+        ColumnKind::Integer => "integer",
+        ColumnKind::BigInt => "bigint",
+        ColumnKind::Real => "real",
+        ColumnKind::Double => "double precision",
+        ColumnKind::Text => "text",
+        ColumnKind::Json => "jsonb",
+        ColumnKind::Bytes => "bytea",
+        ColumnKind::Date => "date",
+        ColumnKind::Time => "time",
+        ColumnKind::Timestamp => "timestamp",
+        ColumnKind::IntegerList => "integer[]",
+        ColumnKind::BigIntList => "bigint[]",
+        ColumnKind::DoubleList => "double precision[]",
+        ColumnKind::TextList => "text[]",
+    }
+}
+
+/// What to do with a PostGIS table when a layer is written to it.
+pub enum WriteMode {
+    /// Create the table, failing if it already exists.
+    Create,
+    /// Add the features to an existing table with compatible columns.
+    Append,
+    /// Replace the table, or create it if it doesn't exist yet.
+    Overwrite,
+    /// Update the rows of an existing table whose key field matches a feature, and add the
+    /// other features.
+    Upsert(String),
+}
+
+impl WriteMode {
+    pub fn parse(mode: &str, key: Option<&str>) -> Result<WriteMode, String> {
+        match (mode, key) {
+            ("create", _) => Ok(WriteMode::Create),
+            ("append", _) => Ok(WriteMode::Append),
+            ("overwrite", _) => Ok(WriteMode::Overwrite),
+            ("upsert", Some(key)) => Ok(WriteMode::Upsert(key.to_string())),
+            ("upsert", None) => Err("`mode=upsert` needs a `key=` field to match rows on.".into()),
+            (mode, _) => Err(format!(
+                "Unknown mode '{}'. Use create, append, overwrite or upsert.",
+                mode
+            )),
+        }
+    }
+}
+
+/// Number of rows a write added to and updated in its table.
+#[derive(Default)]
+pub struct WriteCounts {
+    pub inserted: u64,
+    pub updated: u64,
+    /// FIDs of the features with text that couldn't be decoded, in the order they were read.
+    pub undecodable: Vec<u64>,
+}
+
+/// Checks that an existing table has a column of a fitting type for every column of a layer,
+/// and stores its geometries in the same SRID, before features are appended or upserted
+/// into it.
+async fn check_compatible(
+    transaction: &Transaction<'_>,
+    schema: &str,
+    name: &str,
+    columns: &[Column],
+    table: &GeometryTable,
+    srs: &LayerSrs,
+) -> Result<(), String> {
+    let existing = match transaction.query(
+        "SELECT column_name::text, data_type::text, udt_name::text FROM information_schema.columns
+        WHERE table_schema = $1 AND table_name = $2",
+        &[&schema, &name],
+    ).await {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect::<Vec<(String, String, String)>>(),
+        Err(err) => {
+            return Err(format!(
+                "Failed to read the columns of {}.{}: {}",
+                schema, name, err
+            ))
+        }
+    };
+
+    let find = |name: &str| existing.iter().find(|(column, _, _)| column == name);
+    let mut missing = columns
+        .iter()
+        .map(|column| column.name.clone())
+        .filter(|column| find(column).is_none())
+        .collect::<Vec<String>>();
+    if table.spatial && find("geom").is_none() {
+        missing.push("geom".to_string());
+    }
+    if !missing.is_empty() {
+        return Err(format!(
+            "{}.{} has no column {}, so the layer can't be written to it. \
+            Use `mode=overwrite` to replace the table.",
+            schema,
+            name,
+            missing.join(", ")
+        ));
+    }
+
+    let mismatched = columns
+        .iter()
+        .filter_map(|column| {
+            let (_, data_type, udt_name) = find(&column.name)?;
+            match column.kind.fits(data_type, udt_name) {
+                true => None,
+                false => Some(format!(
+                    "{} is {} but the layer has {}",
+                    column.name,
+                    data_type,
+                    sql_type_name(column.kind)
+                )),
+            }
+        })
+        .collect::<Vec<String>>();
+    if !mismatched.is_empty() {
+        return Err(format!(
+            "The columns of {}.{} don't fit the layer: {}. \
+            Use `mode=overwrite` to replace the table.",
+            schema,
             name,
-            fields.join(", ").to_lowercase()
+            mismatched.join(", ")
+        ));
+    }
+
+    if table.spatial {
+        let srid = match transaction
+            .query_one("SELECT Find_SRID($1, $2, 'geom')", &[&schema, &name])
+            .await
+        {
+            Ok(row) => row.get::<usize, i32>(0),
+            Err(err) => {
+                return Err(format!(
+                    "Failed to read the SRID of {}.{}: {}",
+                    schema, name, err
+                ))
+            }
+        };
+        if srid != srs.srid {
+            return Err(format!(
+                "{}.{} stores geometries with SRID {}, but the layer has SRID {}. \
+                Use `t_srs=EPSG:{}` to reproject it.",
+                schema, name, srid, srs.srid, srid
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Copies one layer of a dataset into the PostGIS table `schema.name`, as `mode` says, and
+/// returns how many rows were inserted and updated. Features are streamed with a binary
+/// `COPY` in a single transaction, so a failed import leaves the database as it was. Appended
+/// and upserted features are copied into a temporary table first, then merged into `name`.
+/// `on_progress` is called with the number of features copied so far and the total after
+/// every batch.
+#[allow(clippy::too_many_arguments)]
+pub async fn generic_to_postgis_layer(
+    layer: &mut Layer<'_>,
+    pgsql_client: &mut Client,
+    schema: &str,
+    name: &str,
+    columns: &[Column],
+    table: &GeometryTable,
+    srs: &LayerSrs,
+    mode: &WriteMode,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<WriteCounts, String> {
+    let mut fields = columns
+        .iter()
+        .map(|column| format!("{} {}", column.quoted_name(), column.sql_type))
+        .collect::<Vec<String>>();
+    let mut cols = columns
+        .iter()
+        .map(Column::quoted_name)
+        .collect::<Vec<String>>();
+    let mut types = columns
+        .iter()
+        .map(|column| column.kind.sql_type())
+        .collect::<Vec<Type>>();
+
+    // Geometries are sent as WKB, which PostGIS reads as the binary form of a geometry
+    if table.spatial {
+        fields.push("geom geometry".to_string());
+        cols.push("geom".to_string());
+        types.push(Type::BYTEA);
+    }
+
+    let qualified = format!("{}.{}", schema, name);
+    let target = format!("{}.{}", quote_identifier(schema), quote_identifier(name));
+
+    let transaction = match pgsql_client.transaction().await {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Failed to start a transaction: {}", err)),
+    };
+
+    // The symbology of a table is its comment, which overwriting it shouldn't lose
+    let existing = match transaction
+        .query_one(
+            "SELECT to_regclass($1) IS NOT NULL, obj_description(to_regclass($1), 'pg_class')",
+            &[&target],
         )
-        .as_str(),
-        &[],
-    ) {
-        Ok(_) => (),
-        Err(_) => {
-            panic!("ERROR! Failed to create layer in database.");
+        .await
+    {
+        Ok(row) => (
+            row.get::<usize, bool>(0),
+            row.get::<usize, Option<String>>(1),
+        ),
+        Err(err) => return Err(format!("Failed to look up {}: {}", qualified, err)),
+    };
+    let (exists, symbology) = existing;
+    match (mode, exists) {
+        (WriteMode::Create, true) => {
+            return Err(format!(
+                "{} already exists. Use `mode=overwrite`, `append` or `upsert` to \
+                write to it.",
+                qualified
+            ))
         }
+        (WriteMode::Append | WriteMode::Upsert(_), false) => {
+            return Err(format!(
+                "{} doesn't exist yet. Use `mode=create` to create it.",
+                qualified
+            ))
+        }
+        (WriteMode::Append | WriteMode::Upsert(_), true) => {
+            check_compatible(&transaction, schema, name, columns, table, srs).await?
+        }
+        (WriteMode::Overwrite, true) => {
+            if let Err(err) = transaction
+                .execute(format!("DROP TABLE {}", target).as_str(), &[])
+                .await
+            {
+                return Err(format!("Failed to drop {}: {}", qualified, err));
+            }
+        }
+        _ => (),
+    }
+
+    let key = match mode {
+        WriteMode::Upsert(key) => {
+            match columns
+                .iter()
+                .find(|column| column.field == *key || column.name == *key)
+            {
+                Some(column) => Some(column.quoted_name()),
+                None => {
+                    return Err(format!(
+                        "The layer has no field '{}' to match rows on.",
+                        key
+                    ))
+                }
+            }
+        }
+        _ => None,
+    };
+
+    // CREATE TABLE
+    let staged = matches!(mode, WriteMode::Append | WriteMode::Upsert(_));
+    let copy_table = match staged {
+        true => quote_identifier(truncate_identifier(
+            &format!("tigre_staging_{}", name),
+            MAX_IDENTIFIER_LENGTH,
+        )),
+        false => target.clone(),
+    };
+    let create = match staged {
+        true => format!(
+            "CREATE TEMP TABLE {} ({}) ON COMMIT DROP",
+            copy_table,
+            fields.join(", ")
+        ),
+        false => format!("CREATE TABLE {} ({})", copy_table, fields.join(", ")),
+    };
+    if let Err(err) = transaction.execute(create.as_str(), &[]).await {
+        return Err(format!("Failed to create layer in database: {}", err));
     };
 
     // SET GEOMETRY TYPE
-    match pgsql_client.execute(
-        format!(
-            "ALTER TABLE \"{}\" ALTER COLUMN geom TYPE Geometry({}, 0)",
-            name, geometry_type
+    // Promoted geometries are only made multi once copied, so they need a generic column until then
+    let column_type = match table.promote {
+        true => "GEOMETRY",
+        false => table.column_type.as_str(),
+    };
+    if table.spatial {
+        if let Err(err) = transaction
+            .execute(
+                format!(
+                    "ALTER TABLE {} ALTER COLUMN geom TYPE Geometry({}, {})",
+                    copy_table, column_type, srs.srid
+                )
+                .as_str(),
+                &[],
+            )
+            .await
+        {
+            return Err(format!("Failed to set geometry information: {}", err));
+        };
+    }
+
+    // COPY FROM GENERIC DATASET -> NEW PGSQL TABLE
+    let writer = match transaction
+        .copy_in(
+            format!(
+                "COPY {} ({}) FROM STDIN (FORMAT binary)",
+                copy_table,
+                cols.join(", ")
+            )
+            .as_str(),
         )
-        .as_str(),
-        &[],
-    ) {
-        Ok(_) => (),
-        Err(_) => {
-            panic!("ERROR! Failed to set geometry information.");
+        .await
+    {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Failed to start copying into the table: {}", err)),
+    };
+    let writer = BinaryCopyInWriter::new(writer, &types);
+    let mut writer = std::pin::pin!(writer);
+
+    let total = table.features;
+    let mut copied = 0;
+    let mut undecodable = vec![];
+    for feature in layer.features() {
+        if !table.contains(feature.geometry()) {
+            continue;
+        }
+
+        let (values, had_errors) = feature_values(&feature, columns);
+        if had_errors {
+            undecodable.push(feature.fid().unwrap_or(copied));
+        }
+        let mut values = values
+            .into_iter()
+            .zip(columns)
+            .map(|(value, column)| column.kind.value(value))
+            .collect::<Vec<Box<dyn ToSql + Sync>>>();
+        if table.spatial {
+            let wkb = match feature.geometry() {
+                Some(geometry) => Some(
+                    srs.reproject(geometry)?
+                        .wkb()
+                        .map_err(|err| format!("Failed to encode a geometry: {}", err))?,
+                ),
+                None => None,
+            };
+            values.push(Box::new(wkb));
         }
+
+        let row = values
+            .iter()
+            .map(|value| value.as_ref())
+            .collect::<Vec<&(dyn ToSql + Sync)>>();
+        if let Err(err) = writer.as_mut().write(&row).await {
+            return Err(format!(
+                "Failed to copy feature {} into the table: {}",
+                copied + 1,
+                err
+            ));
+        }
+
+        copied += 1;
+        if copied % COPY_BATCH_SIZE == 0 {
+            on_progress(copied, total);
+        }
+    }
+
+    let rows = match writer.finish().await {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Failed to copy features into the table: {}", err)),
     };
+    on_progress(rows, total);
 
-    // COPY FROM GENERIC DATASET -> NEW PGSQL TABLE
-    dataset.layers().for_each(|mut lyr| {
-        let mut queries: Vec<String> = vec![];
-
-        let cols = lyr
-            .defn()
-            .fields()
-            .map(|field| {
-                return format!("\"{}\"", field.name());
+    // Converting every column that needs it at once rewrites the table only once
+    let mut conversions = columns
+        .iter()
+        .filter_map(|column| {
+            column.final_type.as_ref().map(|final_type| {
+                format!("ALTER COLUMN {} TYPE {}", column.quoted_name(), final_type)
             })
-            .collect::<Vec<String>>();
-
-        let mut i = 0;
-        lyr.features().for_each(|feature| {
-            let values = feature
-                .fields()
-                .filter(|field| field.0 != "geom")
-                .map(|field| {
-                    return match field.1 {
-                        Some(gdal::vector::FieldValue::StringValue(val)) => format!("\'{}\'", val),
-                        Some(gdal::vector::FieldValue::IntegerValue(val)) => format!("{}", val),
-                        Some(gdal::vector::FieldValue::DateValue(val)) => format!("\'{}\'", val),
-                        Some(gdal::vector::FieldValue::RealValue(val)) => format!("{}", val),
-                        Some(gdal::vector::FieldValue::Integer64Value(val)) => format!("{}", val),
-                        Some(gdal::vector::FieldValue::Integer64ListValue(val)) => format!(
-                            "\'{}\'",
-                            val.iter()
-                                .map(|v| v.to_string())
-                                .collect::<Vec<String>>()
-                                .join(", ")
-                        ),
-                        Some(gdal::vector::FieldValue::IntegerListValue(val)) => format!(
-                            "\'{}\'",
-                            val.iter()
-                                .map(|v| v.to_string())
-                                .collect::<Vec<String>>()
-                                .join(", ")
-                        ),
-                        Some(gdal::vector::FieldValue::RealListValue(val)) => format!(
-                            "\'{}\'",
-                            val.iter()
-                                .map(|v| v.to_string())
-                                .collect::<Vec<String>>()
-                                .join(", ")
-                        ),
-                        Some(gdal::vector::FieldValue::DateTimeValue(val)) => {
-                            format!("\'{}\'", val)
-                        }
-                        Some(gdal::vector::FieldValue::StringListValue(val)) => {
-                            format!("\'{}\'", val.join(", "))
-                        }
-                        None => "NULL".to_string(),
-                    };
-                })
-                .collect::<Vec<String>>()
-                .join(", ");
-
-            queries.push(format!(
-                "INSERT INTO {} ({}, \"geom\") VALUES ({}, '{:?}')",
-                name,
-                cols.join(", ").to_lowercase(),
-                values,
-                geometries[i]
-            ));
-            i += 1;
-        });
+        })
+        .collect::<Vec<String>>();
+    if table.promote {
+        conversions.push(format!(
+            "ALTER COLUMN geom TYPE Geometry({}, {}) USING ST_Multi(geom)",
+            table.column_type, srs.srid
+        ));
+    }
+    if !conversions.is_empty() {
+        if let Err(err) = transaction
+            .execute(
+                format!("ALTER TABLE {} {}", copy_table, conversions.join(", ")).as_str(),
+                &[],
+            )
+            .await
+        {
+            return Err(format!("Failed to convert the copied columns: {}", err));
+        };
+    }
 
-        queries.iter().for_each(|query| {
-            let insert_result = pgsql_client.batch_execute(query.as_str());
-            match insert_result {
-                Ok(_) => (),
-                Err(err) => {
-                    println!(
-                        "ERROR! Failed to load raw data into database table: {}",
-                        err
-                    );
+    let counts = match (staged, &key) {
+        (false, _) => WriteCounts {
+            inserted: rows,
+            ..Default::default()
+        },
+        (true, None) => {
+            match transaction
+                .execute(
+                    format!(
+                        "INSERT INTO {} ({}) SELECT {} FROM {}",
+                        target,
+                        cols.join(", "),
+                        cols.join(", "),
+                        copy_table
+                    )
+                    .as_str(),
+                    &[],
+                )
+                .await
+            {
+                Ok(inserted) => WriteCounts {
+                    inserted,
+                    ..Default::default()
+                },
+                Err(err) => return Err(format!("Failed to append to {}: {}", qualified, err)),
+            }
+        }
+        (true, Some(key)) => {
+            // A key given twice would update the same row twice, with either feature winning
+            let duplicate = transaction
+                .query_opt(
+                    format!(
+                        "SELECT {}::text FROM {} GROUP BY {} HAVING count(*) > 1 LIMIT 1",
+                        key, copy_table, key
+                    )
+                    .as_str(),
+                    &[],
+                )
+                .await;
+            match duplicate {
+                Ok(None) => {}
+                Ok(Some(row)) => {
+                    return Err(format!(
+                        "Key {} isn't unique in the layer: '{}' appears more than once.",
+                        key,
+                        row.get::<usize, Option<String>>(0).unwrap_or_default()
+                    ))
                 }
+                Err(err) => return Err(format!("Failed to check the keys: {}", err)),
             }
+
+            let assignments = cols
+                .iter()
+                .filter(|col| *col != key)
+                .map(|col| format!("{} = staged.{}", col, col))
+                .collect::<Vec<String>>();
+            // With nothing but the key, existing rows are already up to date
+            let updated = match assignments.is_empty() {
+                true => 0,
+                false => match transaction
+                    .execute(
+                        format!(
+                            "UPDATE {} AS target SET {} FROM {} AS staged \
+                        WHERE target.{} = staged.{}",
+                            target,
+                            assignments.join(", "),
+                            copy_table,
+                            key,
+                            key
+                        )
+                        .as_str(),
+                        &[],
+                    )
+                    .await
+                {
+                    Ok(val) => val,
+                    Err(err) => return Err(format!("Failed to update {}: {}", qualified, err)),
+                },
+            };
+            let inserted = match transaction
+                .execute(
+                    format!(
+                        "INSERT INTO {} ({}) SELECT {} FROM {} AS staged WHERE NOT EXISTS \
+                    (SELECT 1 FROM {} AS target WHERE target.{} = staged.{})",
+                        target,
+                        cols.join(", "),
+                        cols.join(", "),
+                        copy_table,
+                        target,
+                        key,
+                        key
+                    )
+                    .as_str(),
+                    &[],
+                )
+                .await
+            {
+                Ok(val) => val,
+                Err(err) => return Err(format!("Failed to insert into {}: {}", qualified, err)),
+            };
+            WriteCounts {
+                inserted,
+                updated,
+                ..Default::default()
+            }
+        }
+    };
+
+    if table.spatial && !staged {
+        let symbology = symbology.unwrap_or_else(|| {
+            "{\"fillColor\": \"#d18a69\", \"fillOpacity\": 0.5, \"color\": \"#d18a69\", \"weight\": 1}"
+                .to_string()
         });
-    });
+        let _ = transaction
+            .execute(
+                format!(
+                    "COMMENT ON TABLE {} IS '{}'",
+                    target,
+                    symbology.replace('\'', "''")
+                )
+                .as_str(),
+                &[],
+            )
+            .await;
+    }
 
-    let _ = pgsql_client.execute(
-        format!("COMMENT ON TABLE public.{} IS '{{\"fillColor\": \"#d18a69\", \"fillOpacity\": 0.5, \"color\": \"#d18a69\", \"weight\": 1}}'", name).as_str(),
-        &[]
-    );
+    match transaction.commit().await {
+        Ok(_) => Ok(WriteCounts {
+            undecodable,
+            ..counts
+        }),
+        Err(err) => Err(format!("Failed to commit the layer: {}", err)),
+    }
 }
 
-pub async fn generic_to_gpkg(dataset: Dataset) -> Result<Vec<String>, ()> {
-    let mut gpkgs = vec![];
+/// `geometry` as the multi variant `ogr_type` of its type, like `ST_Multi` does in PostGIS.
+fn force_to_multi(geometry: Geometry, ogr_type: u32) -> gdal::errors::Result<Geometry> {
+    if geometry.geometry_type() == ogr_type {
+        return Ok(geometry);
+    }
+    let mut multi = Geometry::empty(ogr_type)?;
+    multi.add_geometry(geometry)?;
+    Ok(multi)
+}
+
+/// Caches one layer of a dataset as `/tmp/tigre/<schema>.<name>.gpkg` for the map, with its
+/// attributes under the names of their PostGIS columns and its FIDs, returning the path of
+/// the GeoPackage.
+pub fn generic_to_gpkg(
+    layer: &mut Layer,
+    schema: &str,
+    name: &str,
+    columns: &[Column],
+    table: &GeometryTable,
+    srs: &LayerSrs,
+) -> Result<String, String> {
+    let long_name = format!("{}.{}", schema, name);
+    let path = format!("/tmp/tigre/{}.gpkg", long_name);
+
+    if table.geometry_names.is_empty() {
+        return Err(format!("Layer '{}' has no geometries.", layer.name()));
+    }
 
     let driver = DriverManager::get_driver_by_name("GPKG").unwrap();
-    for mut layer in dataset.layers() {
-        let name = layer.name();
-        let long_name = format!("public.{}", name);
-        let mut gpkg_dataset = driver
-            .create_vector_only(format!("/tmp/tigre/{}.gpkg", long_name))
-            .unwrap();
+    let mut gpkg_dataset = match driver.create_vector_only(&path) {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Couldn't create '{}': {}", path, err)),
+    };
 
-        let layer_srs = SpatialRef::from_epsg(4326).unwrap();
+    let layer_options = LayerOptions {
+        name,
+        srs: srs.spatial_ref.as_ref(),
+        ty: table.ogr_type,
+        options: Some(&["GEOMETRY_NAME=geom", "FID=fid"]),
+    };
+    let gpkg_layer = match gpkg_dataset.create_layer(layer_options) {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Couldn't create layer '{}': {}", name, err)),
+    };
 
-        let layer_geom = match layer.features().collect::<Vec<_>>().first() {
-            Some(layer_geom) => layer_geom.geometry().unwrap().geometry_type(),
-            None => {
-                panic!("ERROR! Layer '{}' has no features.", long_name);
+    let fields = columns
+        .iter()
+        .map(|column| (column.name.as_str(), column.field_type))
+        .collect::<Vec<(&str, OGRFieldType::Type)>>();
+    if let Err(err) = gpkg_layer.create_defn_fields(&fields) {
+        return Err(format!("Couldn't create the fields of '{}': {}", name, err));
+    }
+
+    for feature in layer.features() {
+        if !table.contains(feature.geometry()) {
+            continue;
+        }
+
+        let geometry = match feature.geometry() {
+            Some(geometry) => Some(srs.reproject(geometry)?),
+            None => None,
+        };
+        let write = || -> gdal::errors::Result<()> {
+            let mut gpkg_feature = Feature::new(gpkg_layer.defn())?;
+            // Single geometries are promoted like they are in the table
+            match geometry {
+                Some(geometry) if table.promote => {
+                    gpkg_feature.set_geometry(force_to_multi(geometry, table.ogr_type)?)?
+                }
+                Some(geometry) => gpkg_feature.set_geometry(geometry)?,
+                None => {}
+            }
+            let (values, _) = feature_values(&feature, columns);
+            for (i, (value, column)) in values.into_iter().zip(columns).enumerate() {
+                // The fields of the GeoPackage are created in the order of the columns
+                // SAFETY: the feature is alive for the whole call, and GDAL copies the bytes
+                match value {
+                    Some(FieldData::Value(value)) => {
+                        gpkg_feature.set_field(&column.name, &value)?
+                    }
+                    Some(FieldData::Time(val)) => unsafe {
+                        gdal_sys::OGR_F_SetFieldDateTimeEx(
+                            gpkg_feature.c_feature(),
+                            i as i32,
+                            0,
+                            0,
+                            0,
+                            val.hour() as i32,
+                            val.minute() as i32,
+                            val.second() as f32 + val.nanosecond() as f32 / 1e9,
+                            0,
+                        )
+                    },
+                    Some(FieldData::Binary(val)) => unsafe {
+                        gdal_sys::OGR_F_SetFieldBinary(
+                            gpkg_feature.c_feature(),
+                            i as i32,
+                            val.len() as i32,
+                            val.as_ptr() as *const std::ffi::c_void,
+                        )
+                    },
+                    None => {}
+                }
+            }
+            if let Some(fid) = feature.fid() {
+                // SAFETY: the feature is alive for the whole call
+                unsafe { gdal_sys::OGR_F_SetFID(gpkg_feature.c_feature(), fid as i64) };
             }
+            gpkg_feature.create(&gpkg_layer)
         };
+        if let Err(err) = write() {
+            return Err(format!("Couldn't write to '{}': {}", path, err));
+        }
+    }
 
-        let layer_options = LayerOptions {
-            name: name.as_str(),
-            srs: Some(&layer_srs),
-            ty: layer_geom,
-            options: Some(&["GEOMETRY_NAME=geom", "FID=fid"]),
-        };
-        let mut gpkg_layer = gpkg_dataset.create_layer(layer_options).unwrap();
+    Ok(path)
+}
+
+/// Caches a PostGIS table as `/tmp/tigre/<schema>.<name>.gpkg` for the map, like
+/// [`generic_to_gpkg`] does for the layers it imports.
+pub fn postgis_layer_to_gpkg(
+    name: &str,
+    schema: &str,
+    gdal_pgsql_connection: String,
+) -> Result<String, String> {
+    // Only the PostgreSQL driver may open it, not the GNM or PostGIS Raster ones that also
+    // take `PG:` strings
+    let postgis_dataset = match Dataset::open_ex(
+        gdal_pgsql_connection,
+        DatasetOptions {
+            open_flags: GdalOpenFlags::GDAL_OF_VECTOR,
+            allowed_drivers: Some(&["PostgreSQL"]),
+            ..Default::default()
+        },
+    ) {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Couldn't open the database: {}", err)),
+    };
+
+    // The PostgreSQL driver only leaves the schema out for tables in the search path
+    let layer_name = match schema {
+        "public" => name.to_string(),
+        _ => format!("{}.{}", schema, name),
+    };
+    let mut postgis_layer = match postgis_dataset.layer_by_name(&layer_name) {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Couldn't open '{}.{}': {}", schema, name, err)),
+    };
 
-        for feature in layer.features() {
-            gpkg_layer
-                .create_feature(feature.geometry().unwrap().clone())
-                .unwrap();
+    let srs = LayerSrs::new(&postgis_layer, None, None)?;
+    let (columns, _) = plan_columns(&postgis_layer, encoding_rs::UTF_8);
+    let plan = plan_geometry_tables(&mut postgis_layer, false);
+    generic_to_gpkg(
+        &mut postgis_layer,
+        schema,
+        name,
+        &columns,
+        &plan.tables[0],
+        &srs,
+    )
+}
+
+/// PostGIS raster pixel types GDAL bands are stored as.
+#[derive(Clone, Copy)]
+enum PixelType {
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl PixelType {
+    /// Bands of any other type, e.g. complex or 64-bit integers, are stored as doubles.
+    fn of(band_type: GdalDataType) -> PixelType {
+        match band_type {
+            GdalDataType::UInt8 => PixelType::UInt8,
+            GdalDataType::Int16 => PixelType::Int16,
+            GdalDataType::UInt16 => PixelType::UInt16,
+            GdalDataType::Int32 => PixelType::Int32,
+            GdalDataType::UInt32 => PixelType::UInt32,
+            GdalDataType::Float32 => PixelType::Float32,
+            _ => PixelType::Float64,
         }
+    }
 
-        gpkgs.push(format!("/tmp/tigre/{}.gpkg", long_name));
+    /// The code of the type in the raster WKB format.
+    fn code(&self) -> u8 {
+        match self {
+            PixelType::UInt8 => 4,
+            PixelType::Int16 => 5,
+            PixelType::UInt16 => 6,
+            PixelType::Int32 => 7,
+            PixelType::UInt32 => 8,
+            PixelType::Float32 => 10,
+            PixelType::Float64 => 11,
+        }
     }
 
-    Ok(gpkgs)
+    fn write(&self, wkb: &mut Vec<u8>, value: f64) {
+        match self {
+            PixelType::UInt8 => wkb.push(value as u8),
+            PixelType::Int16 => wkb.extend((value as i16).to_le_bytes()),
+            PixelType::UInt16 => wkb.extend((value as u16).to_le_bytes()),
+            PixelType::Int32 => wkb.extend((value as i32).to_le_bytes()),
+            PixelType::UInt32 => wkb.extend((value as u32).to_le_bytes()),
+            PixelType::Float32 => wkb.extend((value as f32).to_le_bytes()),
+            PixelType::Float64 => wkb.extend(value.to_le_bytes()),
+        }
+    }
 }
 
-pub async fn postgis_layer_to_gpkg(name: &str, schema: &str, gdal_pgsql_connection: String) {
-    let long_name = format!("{}.{}", schema, name);
+/// A band of a raster tile: its pixel type, nodata value and pixels, row by row.
+struct TileBand {
+    pixel_type: PixelType,
+    nodata: Option<f64>,
+    pixels: Vec<f64>,
+}
 
-    std::env::set_var("GDAL_SKIP", "GNMFile,GNMDatabase,PostGISRaster"); // This forces GDAL to use the PostgreSQL Driver
-    let postgis_dataset = Dataset::open(gdal_pgsql_connection).unwrap();
-    let mut postgis_layer = postgis_dataset.layer_by_name(name).unwrap();
+/// Encodes a tile in the little-endian WKB format of PostGIS rasters. `geo_transform` is the
+/// GDAL geotransform of the tile's top left corner.
+fn raster_wkb(
+    geo_transform: &[f64; 6],
+    srid: i32,
+    size: (usize, usize),
+    bands: &[TileBand],
+) -> Vec<u8> {
+    let mut wkb = vec![1];
+    wkb.extend(0u16.to_le_bytes());
+    wkb.extend((bands.len() as u16).to_le_bytes());
+    for value in [
+        geo_transform[1],
+        geo_transform[5],
+        geo_transform[0],
+        geo_transform[3],
+        geo_transform[2],
+        geo_transform[4],
+    ] {
+        wkb.extend(value.to_le_bytes());
+    }
+    wkb.extend(srid.to_le_bytes());
+    wkb.extend((size.0 as u16).to_le_bytes());
+    wkb.extend((size.1 as u16).to_le_bytes());
 
-    let driver = DriverManager::get_driver_by_name("GPKG").unwrap();
-    let mut gpkg_dataset = driver
-        .create_vector_only(format!("/tmp/tigre/{}.gpkg", long_name))
-        .unwrap();
+    for band in bands {
+        let has_nodata = match band.nodata {
+            Some(_) => 0x40,
+            None => 0,
+        };
+        wkb.push(band.pixel_type.code() | has_nodata);
+        band.pixel_type.write(&mut wkb, band.nodata.unwrap_or(0.0));
+        for pixel in &band.pixels {
+            band.pixel_type.write(&mut wkb, *pixel);
+        }
+    }
+    wkb
+}
 
-    let layer_srs = SpatialRef::from_epsg(4326).unwrap();
+/// The EPSG code of the SRS of a raster dataset, or 0 if it has none or it's unknown.
+pub fn raster_srid(dataset: &Dataset) -> i32 {
+    dataset
+        .spatial_ref()
+        .ok()
+        .and_then(|spatial_ref| epsg_code(&spatial_ref))
+        .unwrap_or(0)
+}
+
+/// Name of the table holding the overview of raster `name` reduced by `factor`, following
+/// the `raster2pgsql` convention.
+pub fn overview_name(name: &str, factor: usize) -> String {
+    truncate_identifier(&format!("o_{}_{}", factor, name), MAX_IDENTIFIER_LENGTH).to_string()
+}
+
+/// Copies the tiles of a raster, reduced by `factor`, into a new table with a `rast` column.
+/// `table` is the quoted and qualified name of the table.
+async fn raster_level_to_postgis(
+    dataset: &Dataset,
+    transaction: &Transaction<'_>,
+    table: &str,
+    srid: i32,
+    tile_size: usize,
+    factor: usize,
+    on_tile: &mut dyn FnMut(),
+) -> Result<u64, String> {
+    let geo_transform = dataset
+        .geo_transform()
+        .map_err(|err| format!("The raster has no geotransform: {}", err))?;
+    let (width, height) = dataset.raster_size();
+    let (level_width, level_height) = (width.div_ceil(factor), height.div_ceil(factor));
+
+    // Tiles are copied as WKB and only turned into rasters once they're all in
+    if let Err(err) = transaction
+        .execute(
+            format!(
+                "CREATE TABLE {} (rid serial PRIMARY KEY, rast bytea)",
+                table
+            )
+            .as_str(),
+            &[],
+        )
+        .await
+    {
+        return Err(format!("Failed to create table {}: {}", table, err));
+    }
+    let writer = match transaction
+        .copy_in(format!("COPY {} (rast) FROM STDIN (FORMAT binary)", table).as_str())
+        .await
+    {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Failed to start copying into the table: {}", err)),
+    };
+    let writer = BinaryCopyInWriter::new(writer, &[Type::BYTEA]);
+    let mut writer = std::pin::pin!(writer);
 
-    let layer_geom = match postgis_layer.features().collect::<Vec<_>>().first() {
-        Some(layer_geom) => layer_geom.geometry().unwrap().geometry_type(),
-        None => {
-            panic!("ERROR! Layer '{}' has no features.", long_name);
+    for row in (0..level_height).step_by(tile_size) {
+        for col in (0..level_width).step_by(tile_size) {
+            let size = (
+                tile_size.min(level_width - col),
+                tile_size.min(level_height - row),
+            );
+            let window = ((col * factor) as isize, (row * factor) as isize);
+            let window_size = (
+                (size.0 * factor).min(width - col * factor),
+                (size.1 * factor).min(height - row * factor),
+            );
+
+            let mut bands = vec![];
+            for i in 1..=dataset.raster_count() {
+                let band = dataset
+                    .rasterband(i)
+                    .map_err(|err| format!("Failed to open band {}: {}", i, err))?;
+                let pixels = band
+                    .read_as::<f64>(window, window_size, size, None)
+                    .map_err(|err| format!("Failed to read band {}: {}", i, err))?;
+                bands.push(TileBand {
+                    pixel_type: PixelType::of(band.band_type()),
+                    nodata: band.no_data_value(),
+                    pixels: pixels.data().to_vec(),
+                });
+            }
+
+            let f = factor as f64;
+            let tile_transform = [
+                geo_transform[0]
+                    + window.0 as f64 * geo_transform[1]
+                    + window.1 as f64 * geo_transform[2],
+                geo_transform[1] * f,
+                geo_transform[2] * f,
+                geo_transform[3]
+                    + window.0 as f64 * geo_transform[4]
+                    + window.1 as f64 * geo_transform[5],
+                geo_transform[4] * f,
+                geo_transform[5] * f,
+            ];
+            let wkb = raster_wkb(&tile_transform, srid, size, &bands);
+            if let Err(err) = writer.as_mut().write(&[&wkb as &(dyn ToSql + Sync)]).await {
+                return Err(format!("Failed to copy a tile into the table: {}", err));
+            }
+            on_tile();
         }
+    }
+
+    let tiles = match writer.finish().await {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Failed to copy tiles into the table: {}", err)),
     };
 
-    let layer_options = LayerOptions {
-        name,
-        srs: Some(&layer_srs),
-        ty: layer_geom,
-        options: None,
+    for query in [
+        format!(
+            "ALTER TABLE {} ALTER COLUMN rast TYPE raster USING ST_RastFromWKB(rast)",
+            table
+        ),
+        format!("CREATE INDEX ON {} USING gist (ST_ConvexHull(rast))", table),
+    ] {
+        if let Err(err) = transaction.execute(query.as_str(), &[]).await {
+            return Err(format!(
+                "Failed to set up the raster column of {}: {}",
+                table, err
+            ));
+        }
+    }
+    Ok(tiles)
+}
+
+/// Copies every band of a raster dataset into a new PostGIS raster table `schema.name`, cut
+/// into tiles of `tile_size` pixels, with one overview table per factor in `overviews`. Both
+/// are registered in `raster_columns` and `raster_overviews`. Returns the number of tiles at
+/// full resolution. `on_progress` is called with the number of tiles copied so far, across
+/// all tables, and the total.
+#[allow(clippy::too_many_arguments)]
+pub async fn raster_to_postgis(
+    dataset: &Dataset,
+    pgsql_client: &mut Client,
+    schema: &str,
+    name: &str,
+    srid: i32,
+    tile_size: usize,
+    overviews: &[usize],
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, String> {
+    if dataset.raster_count() == 0 {
+        return Err("The dataset has no raster bands.".to_string());
+    }
+
+    let (width, height) = dataset.raster_size();
+    let levels = std::iter::once(1)
+        .chain(overviews.iter().copied())
+        .collect::<Vec<usize>>();
+    let total = levels
+        .iter()
+        .map(|factor| {
+            (width.div_ceil(factor * tile_size) * height.div_ceil(factor * tile_size)) as u64
+        })
+        .sum::<u64>();
+
+    let transaction = match pgsql_client.transaction().await {
+        Ok(val) => val,
+        Err(err) => return Err(format!("Failed to start a transaction: {}", err)),
+    };
+
+    let mut copied = 0;
+    let mut on_tile = || {
+        copied += 1;
+        on_progress(copied, total);
     };
-    let mut gpkg_layer = gpkg_dataset.create_layer(layer_options).unwrap();
+    let tiles = raster_level_to_postgis(
+        dataset,
+        &transaction,
+        &format!("{}.{}", quote_identifier(schema), quote_identifier(name)),
+        srid,
+        tile_size,
+        1,
+        &mut on_tile,
+    )
+    .await?;
+    for factor in overviews {
+        raster_level_to_postgis(
+            dataset,
+            &transaction,
+            &format!(
+                "{}.{}",
+                quote_identifier(schema),
+                quote_identifier(&overview_name(name, *factor))
+            ),
+            srid,
+            tile_size,
+            *factor,
+            &mut on_tile,
+        )
+        .await?;
+    }
 
-    for feature in postgis_layer.features() {
-        gpkg_layer
-            .create_feature(feature.geometry().unwrap().clone())
+    // The constraints are what `raster_columns` and `raster_overviews` read the SRID, scale
+    // and overview factors from
+    if let Err(err) = transaction
+        .execute(
+            "SELECT AddRasterConstraints($1::name, $2::name, 'rast'::name)",
+            &[&schema, &name],
+        )
+        .await
+    {
+        return Err(format!("Failed to register the raster: {}", err));
+    }
+    for factor in overviews {
+        if let Err(err) = transaction
+            .execute(
+                "SELECT AddOverviewConstraints($1::name, $2::name, 'rast'::name, \
+            $1::name, $3::name, 'rast'::name, $4)",
+                &[
+                    &schema,
+                    &overview_name(name, *factor),
+                    &name,
+                    &(*factor as i32),
+                ],
+            )
+            .await
+        {
+            return Err(format!(
+                "Failed to register the overviews of the raster: {}",
+                err
+            ));
+        }
+    }
+
+    match transaction.commit().await {
+        Ok(_) => Ok(tiles),
+        Err(err) => Err(format!("Failed to commit the raster: {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_values_keep_fields_after_a_time_in_place() {
+        let driver = DriverManager::get_driver_by_name("Memory").unwrap();
+        let mut dataset = driver.create_vector_only("").unwrap();
+        let layer = dataset.create_layer(LayerOptions::default()).unwrap();
+        layer
+            .create_defn_fields(&[
+                ("opens", OGRFieldType::OFTTime),
+                ("logo", OGRFieldType::OFTBinary),
+                ("name", OGRFieldType::OFTString),
+                ("floors", OGRFieldType::OFTInteger),
+            ])
             .unwrap();
+        let (columns, _) = plan_columns(&layer, encoding_rs::UTF_8);
+
+        let mut feature = Feature::new(layer.defn()).unwrap();
+        // SAFETY: the feature is alive for the whole block
+        unsafe {
+            gdal_sys::OGR_F_SetFieldDateTimeEx(feature.c_feature(), 0, 0, 0, 0, 9, 30, 15.5, 0);
+            gdal_sys::OGR_F_SetFieldBinary(
+                feature.c_feature(),
+                1,
+                3,
+                [1u8, 2, 3].as_ptr() as *const std::ffi::c_void,
+            );
+        }
+        feature.set_field_string("name", "Town hall").unwrap();
+        feature.set_field_integer("floors", 4).unwrap();
+
+        let (values, undecodable) = feature_values(&feature, &columns);
+        assert!(!undecodable);
+        assert_eq!(
+            values,
+            vec![
+                Some(FieldData::Time(
+                    NaiveTime::from_hms_milli_opt(9, 30, 15, 500).unwrap()
+                )),
+                Some(FieldData::Binary(vec![1, 2, 3])),
+                Some(FieldData::Value(FieldValue::StringValue(
+                    "Town hall".to_string()
+                ))),
+                Some(FieldData::Value(FieldValue::IntegerValue(4))),
+            ]
+        );
+    }
+
+    #[test]
+    fn feature_values_decode_text_from_its_own_field() {
+        let driver = DriverManager::get_driver_by_name("Memory").unwrap();
+        let mut dataset = driver.create_vector_only("").unwrap();
+        let layer = dataset.create_layer(LayerOptions::default()).unwrap();
+        layer
+            .create_defn_fields(&[
+                ("logo", OGRFieldType::OFTBinary),
+                ("name", OGRFieldType::OFTString),
+            ])
+            .unwrap();
+        let (columns, _) = plan_columns(&layer, encoding_rs::WINDOWS_1252);
+
+        let feature = Feature::new(layer.defn()).unwrap();
+        // SAFETY: the feature is alive for the whole block, and GDAL copies what's set
+        unsafe {
+            gdal_sys::OGR_F_SetFieldBinary(
+                feature.c_feature(),
+                0,
+                2,
+                [0xffu8, 0xfe].as_ptr() as *const std::ffi::c_void,
+            );
+            gdal_sys::OGR_F_SetFieldString(feature.c_feature(), 1, c"Caf\xe9".as_ptr());
+        }
+
+        let (values, undecodable) = feature_values(&feature, &columns);
+        assert!(!undecodable);
+        assert_eq!(
+            values[1],
+            Some(FieldData::Value(FieldValue::StringValue(
+                "Café".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn force_to_multi_wraps_single_geometries() {
+        let multi = Geometry::from_wkt("MULTIPOINT (1 2, 3 4)").unwrap();
+        let ogr_type = multi.geometry_type();
+        assert_eq!(
+            force_to_multi(Geometry::from_wkt("POINT (1 2)").unwrap(), ogr_type)
+                .unwrap()
+                .wkt()
+                .unwrap(),
+            "MULTIPOINT (1 2)"
+        );
+        assert_eq!(
+            force_to_multi(multi, ogr_type).unwrap().wkt().unwrap(),
+            "MULTIPOINT (1 2,3 4)"
+        );
     }
 }
@@ -0,0 +1,109 @@
+use crate::appstate::AppState;
+use crate::complete::Completions;
+use crate::events::EventSink;
+use crate::pipeline::Pipeline;
+use crate::registry::{Context, Registry};
+use crate::{complete, db, repl};
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter, Manager, State};
+
+impl EventSink for AppHandle {
+    fn emit_json(&self, event: &str, payload: serde_json::Value) {
+        let _ = Emitter::emit(self, event, payload);
+    }
+}
+
+#[tauri::command]
+fn read(cmd: &str, ctx: State<'_, Context>) -> Result<Pipeline, String> {
+    repl::read(cmd, &ctx.registry)
+}
+
+#[tauri::command]
+async fn eval(ast: Pipeline, ctx: State<'_, Context>) -> Result<String, ()> {
+    Ok(repl::eval_and_record(ast, &ctx).await.to_json())
+}
+
+/// Completion candidates for the word before `cursor` in the REPL input.
+#[tauri::command]
+async fn complete(
+    partial_cmd: &str,
+    cursor: usize,
+    ctx: State<'_, Context>,
+) -> Result<Completions, ()> {
+    Ok(complete::complete(partial_cmd, cursor, &ctx).await)
+}
+
+#[tauri::command]
+async fn get_layer_symbology(
+    schema: &str,
+    table: &str,
+    ctx: State<'_, Context>,
+) -> Result<String, String> {
+    db::get_layer_symbology(schema, table, &ctx.state).await
+}
+
+#[tauri::command]
+async fn get_as_json_gpkg(schema: &str, table: &str) -> Result<Vec<String>, String> {
+    db::get_as_json_gpkg(schema, table).await
+}
+
+#[tauri::command]
+async fn get_as_wkt(
+    table: &str,
+    bb: Vec<Vec<f32>>,
+    ctx: State<'_, Context>,
+) -> Result<Vec<String>, String> {
+    db::get_as_wkt(table, bb, &ctx.state).await
+}
+
+#[tauri::command]
+async fn get_raster_png(
+    schema: &str,
+    table: &str,
+    bb: Vec<Vec<f32>>,
+    width: u32,
+    height: u32,
+    ctx: State<'_, Context>,
+) -> Result<String, String> {
+    db::get_raster_png(schema, table, bb, width, height, &ctx.state).await
+}
+
+#[tauri::command]
+async fn get_as_json(
+    table: &str,
+    bb: Vec<Vec<f32>>,
+    ctx: State<'_, Context>,
+) -> Result<String, String> {
+    db::get_as_json(table, bb, &ctx.state).await
+}
+
+// TAURI STUFF
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub async fn run() {
+    run_with_registry(Registry::builtin()).await
+}
+
+/// Starts the app with the commands in `registry` available in the REPL.
+pub async fn run_with_registry(registry: Registry) {
+    tauri::Builder::default()
+        .plugin(tauri_plugin_dialog::init())
+        .setup(move |app| {
+            let state = AppState::new(Arc::new(app.handle().clone()));
+
+            app.manage(Context::new(state, registry));
+            Ok(())
+        })
+        .plugin(tauri_plugin_opener::init())
+        .invoke_handler(tauri::generate_handler![
+            read,
+            eval,
+            complete,
+            get_as_json,
+            get_as_wkt,
+            get_as_json_gpkg,
+            get_raster_png,
+            get_layer_symbology
+        ])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
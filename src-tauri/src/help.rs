@@ -0,0 +1,234 @@
+use crate::command::{suggest, ArgError, ArgKind, ArgSpec, Arguments, Command, CommandSchema};
+use crate::output::{ErrorCode, Output};
+use crate::registry::{Context, Registry};
+use futures::future::{BoxFuture, FutureExt};
+
+static HELP_SCHEMA: CommandSchema = CommandSchema {
+    name: "help",
+    summary: "List the available commands, or explain how to use one.",
+    args: &[
+        ArgSpec {
+            name: "command",
+            kind: ArgKind::Command,
+            required: false,
+            description: "Command to explain.",
+        },
+        ArgSpec {
+            name: "subcommand",
+            kind: ArgKind::Text,
+            required: false,
+            description: "Subcommand to explain.",
+        },
+    ],
+    options: &[],
+    subcommands: &[],
+    examples: &["help", "help buffer", "help db connect"],
+};
+
+/// Lays out `(name, description)` pairs in two aligned columns.
+fn columns(rows: Vec<(String, String)>) -> Vec<String> {
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    rows.into_iter()
+        .map(|(name, description)| format!("  {:width$}  {}", name, description, width = width))
+        .collect()
+}
+
+fn overview(registry: &Registry) -> String {
+    let mut lines = vec!["Commands:".to_string()];
+    lines.extend(columns(
+        registry
+            .commands()
+            .map(|command| {
+                (
+                    command.name().to_string(),
+                    command.schema().summary.to_string(),
+                )
+            })
+            .collect(),
+    ));
+    lines.push(String::new());
+    lines.push("Type `help <command>` for details on a command.".to_string());
+    lines.join("\n")
+}
+
+/// Describes `schema` in full. `parent` is the command a subcommand belongs to.
+fn details(schema: &CommandSchema, parent: Option<&str>) -> String {
+    let name = match parent {
+        Some(parent) => format!("{} {}", parent, schema.name),
+        None => schema.name.to_string(),
+    };
+    let usage = schema.usage().into_iter().map(|usage| match parent {
+        Some(parent) => format!("  {} {}", parent, usage),
+        None => format!("  {}", usage),
+    });
+
+    let mut lines = vec![format!("{} - {}", name, schema.summary), String::new()];
+    lines.push("Usage:".to_string());
+    lines.extend(usage);
+
+    if !schema.subcommands.is_empty() {
+        lines.push(String::new());
+        lines.push("Subcommands:".to_string());
+        lines.extend(columns(
+            schema
+                .subcommands
+                .iter()
+                .map(|subcommand| {
+                    let name = match subcommand.name {
+                        "" => "(none)",
+                        name => name,
+                    };
+                    (name.to_string(), subcommand.summary.to_string())
+                })
+                .collect(),
+        ));
+        lines.push(String::new());
+        lines.push(format!("Type `help {} <subcommand>` for details.", name));
+    }
+
+    if !schema.args.is_empty() {
+        lines.push(String::new());
+        lines.push("Arguments:".to_string());
+        lines.extend(columns(
+            schema
+                .args
+                .iter()
+                .map(|arg| {
+                    let name = match arg.required {
+                        true => format!("<{}>", arg.name),
+                        false => format!("[{}]", arg.name),
+                    };
+                    (name, format!("{} ({})", arg.description, arg.kind))
+                })
+                .collect(),
+        ));
+    }
+
+    if !schema.options.is_empty() {
+        lines.push(String::new());
+        lines.push("Options (after `?`):".to_string());
+        lines.extend(columns(
+            schema
+                .options
+                .iter()
+                .map(|option| {
+                    let kind = match option.default {
+                        Some(default) => format!("{}, default {}", option.kind, default),
+                        None => option.kind.to_string(),
+                    };
+                    (
+                        format!("{}=", option.name),
+                        format!("{} ({})", option.description, kind),
+                    )
+                })
+                .collect(),
+        ));
+    }
+
+    if !schema.examples.is_empty() {
+        lines.push(String::new());
+        lines.push("Examples:".to_string());
+        lines.extend(
+            schema
+                .examples
+                .iter()
+                .map(|example| format!("  {}", example)),
+        );
+    }
+
+    lines.join("\n")
+}
+
+pub fn help(command: Option<&str>, subcommand: Option<&str>, registry: &Registry) -> Output {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
+
+    let command = match command {
+        Some(val) => val,
+        None => {
+            output.message(overview(registry));
+            return output;
+        }
+    };
+
+    let schema = match registry.get(command) {
+        Some(val) => val.schema(),
+        None => {
+            match suggest(command, registry.commands().map(|command| command.name())) {
+                Some(name) => output.error(
+                    ErrorCode::Parse,
+                    format!(
+                        "ERROR! Unknown command '{}'. Did you mean '{}'?",
+                        command, name
+                    ),
+                ),
+                None => output.error(
+                    ErrorCode::Parse,
+                    format!(
+                        "ERROR! Unknown command '{}'. Type `help` for a list of commands.",
+                        command
+                    ),
+                ),
+            }
+            return output;
+        }
+    };
+
+    match subcommand {
+        None => output.message(details(schema, None)),
+        Some(subcommand) => match schema.subcommand(subcommand) {
+            Some(val) => output.message(details(val, Some(schema.name))),
+            None => {
+                // The default subcommand has no name to suggest
+                let names = schema
+                    .subcommands
+                    .iter()
+                    .map(|subcommand| subcommand.name)
+                    .filter(|name| !name.is_empty());
+                match suggest(subcommand, names) {
+                    Some(name) => output.error(
+                        ErrorCode::Parse,
+                        format!(
+                            "ERROR! Unknown subcommand '{}' for command '{}'. Did you mean '{}'?",
+                            subcommand, command, name
+                        ),
+                    ),
+                    None => output.error(
+                        ErrorCode::Parse,
+                        format!(
+                            "ERROR! Command '{}' has no subcommand '{}'. Type `help {}` for details.",
+                            command, subcommand, command
+                        ),
+                    ),
+                }
+            }
+        },
+    }
+
+    output
+}
+
+pub struct HelpCommand;
+
+impl Command for HelpCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &HELP_SCHEMA
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            Ok(help(
+                args.optional_text("command"),
+                args.optional_text("subcommand"),
+                &ctx.registry,
+            ))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,390 @@
+use crate::command::{ArgError, ArgKind, ArgSpec, Arguments, Command, CommandSchema};
+use crate::options::{OptionKind, OptionSpec};
+use crate::output::{ErrorCode, Output, OutputResult};
+use crate::pipeline::secret_spans;
+use crate::registry::Context;
+use crate::repl::{eval_and_record, read};
+use futures::future::{BoxFuture, FutureExt};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A command run from the REPL, as stored in the history.
+pub struct Entry {
+    pub command: String,
+    /// Milliseconds since the Unix epoch.
+    pub started_at: i64,
+    pub duration_ms: i64,
+    pub connection: Option<String>,
+    pub output: Output,
+}
+
+/// The SQLite file every command run from the REPL is recorded in.
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    /// `$TIGRE_HISTORY` if set, otherwise `tigre/history.sqlite` in the user's data directory.
+    pub fn default_location() -> History {
+        let path = match std::env::var_os("TIGRE_HISTORY") {
+            Some(path) => PathBuf::from(path),
+            None => dirs::data_dir()
+                .map(|dir| dir.join("tigre"))
+                .unwrap_or(PathBuf::from("/tmp/tigre"))
+                .join("history.sqlite"),
+        };
+        History { path }
+    }
+
+    pub fn at(path: impl Into<PathBuf>) -> History {
+        History { path: path.into() }
+    }
+
+    fn open(&self) -> rusqlite::Result<Connection> {
+        if let Some(dir) = self.path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+
+        let connection = Connection::open(&self.path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                command TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                connection TEXT,
+                output TEXT NOT NULL,
+                failed INTEGER NOT NULL
+            )",
+        )?;
+        Ok(connection)
+    }
+
+    pub fn record(&self, entry: &Entry) -> rusqlite::Result<()> {
+        self.open()?.execute(
+            "INSERT INTO history (command, started_at, duration_ms, connection, output, failed)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                entry.command,
+                entry.started_at,
+                entry.duration_ms,
+                entry.connection,
+                entry.output.to_json(),
+                entry.output.failed(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The latest `limit` entries whose command contains `text`, oldest first.
+    pub fn search(&self, text: &str, limit: i64) -> rusqlite::Result<OutputResult> {
+        let pattern = format!(
+            "%{}%",
+            text.replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+
+        let connection = self.open()?;
+        let mut statement = connection.prepare(
+            "SELECT id, strftime('%Y-%m-%d %H:%M:%S', started_at / 1000, 'unixepoch', 'localtime'),
+                duration_ms, connection, command, failed
+            FROM (
+                SELECT * FROM history WHERE command LIKE ?1 ESCAPE '\\' ORDER BY id DESC LIMIT ?2
+            )
+            ORDER BY id",
+        )?;
+
+        let rows = statement
+            .query_map(params![pattern, limit], |row| {
+                Ok(vec![
+                    serde_json::Value::from(row.get::<usize, i64>(0)?),
+                    serde_json::Value::from(row.get::<usize, String>(1)?),
+                    serde_json::Value::from(row.get::<usize, i64>(2)?),
+                    serde_json::Value::from(row.get::<usize, Option<String>>(3)?),
+                    serde_json::Value::from(row.get::<usize, String>(4)?),
+                    serde_json::Value::from(match row.get::<usize, bool>(5)? {
+                        true => "failed",
+                        false => "ok",
+                    }),
+                ])
+            })?
+            .collect::<rusqlite::Result<Vec<Vec<serde_json::Value>>>>()?;
+
+        Ok(OutputResult::Table {
+            name: "history".to_string(),
+            columns: [
+                "n",
+                "started",
+                "duration_ms",
+                "connection",
+                "command",
+                "status",
+            ]
+            .iter()
+            .map(|column| column.to_string())
+            .collect(),
+            rows,
+        })
+    }
+
+    /// The command recorded as entry `n`.
+    pub fn command(&self, n: i64) -> rusqlite::Result<Option<String>> {
+        self.open()?
+            .query_row(
+                "SELECT command FROM history WHERE id = ?1",
+                params![n],
+                |row| row.get::<usize, String>(0),
+            )
+            .optional()
+    }
+
+    /// Every recorded command, oldest first, with whether it failed.
+    pub fn commands(&self) -> rusqlite::Result<Vec<(String, bool)>> {
+        let connection = self.open()?;
+        let mut statement =
+            connection.prepare("SELECT command, failed FROM history ORDER BY id")?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<usize, String>(0)?, row.get::<usize, bool>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, bool)>>>()?;
+        Ok(rows)
+    }
+}
+
+const LIMIT_OPTION: OptionSpec = OptionSpec {
+    name: "limit",
+    kind: OptionKind::Integer,
+    default: Some("20"),
+    description: "Number of entries to show.",
+};
+
+static HISTORY_SCHEMA: CommandSchema = CommandSchema {
+    name: "history",
+    summary: "Browse, replay and export the commands run in the REPL.",
+    args: &[],
+    options: &[],
+    subcommands: &[
+        CommandSchema {
+            name: "",
+            summary: "List the most recent commands.",
+            args: &[],
+            options: &[LIMIT_OPTION],
+            subcommands: &[],
+            examples: &["history", "history ? limit=100"],
+        },
+        CommandSchema {
+            name: "search",
+            summary: "List the most recent commands containing some text.",
+            args: &[ArgSpec {
+                name: "text",
+                kind: ArgKind::Text,
+                required: true,
+                description: "Text to look for.",
+            }],
+            options: &[LIMIT_OPTION],
+            subcommands: &[],
+            examples: &["history search buffer", "history search `db connect`"],
+        },
+        CommandSchema {
+            name: "replay",
+            summary: "Run a command from the history again.",
+            args: &[ArgSpec {
+                name: "n",
+                kind: ArgKind::Integer,
+                required: true,
+                description: "Number of the entry, as shown by `history`.",
+            }],
+            options: &[],
+            subcommands: &[],
+            examples: &["history replay 12"],
+        },
+        CommandSchema {
+            name: "export",
+            summary: "Write every command in the history to a .tigre script.",
+            args: &[ArgSpec {
+                name: "path",
+                kind: ArgKind::Path,
+                required: true,
+                description: "Script to write.",
+            }],
+            options: &[OptionSpec {
+                name: "overwrite",
+                kind: OptionKind::Boolean,
+                default: Some("false"),
+                description: "Replace the script if it already exists.",
+            }],
+            subcommands: &[],
+            examples: &["history export `session.tigre`"],
+        },
+    ],
+    examples: &[],
+};
+
+fn list(text: &str, limit: i64, ctx: &Context) -> Result<Output, ()> {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
+
+    match ctx.history.search(text, limit) {
+        Ok(table) => output.results.push(table),
+        Err(err) => output.error(
+            ErrorCode::Io,
+            format!("ERROR! Couldn't read the history: {}", err),
+        ),
+    }
+    Ok(output)
+}
+
+async fn replay(n: i64, ctx: &Context) -> Result<Output, ()> {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
+
+    let cmd = match ctx.history.command(n) {
+        Ok(Some(val)) => val,
+        Ok(None) => {
+            output.error(
+                ErrorCode::Io,
+                format!("ERROR! There is no entry {} in the history.", n),
+            );
+            return Ok(output);
+        }
+        Err(err) => {
+            output.error(
+                ErrorCode::Io,
+                format!("ERROR! Couldn't read the history: {}", err),
+            );
+            return Ok(output);
+        }
+    };
+
+    // Secrets are stored as `***`, so running the entry again would use that instead
+    if !secret_spans(&cmd, &ctx.registry).is_empty() {
+        output.error(
+            ErrorCode::Args,
+            format!(
+                "ERROR! Entry {} held a secret, e.g. a password, which isn't kept in the \
+                history, so it can't be replayed. Run it again with the secret instead.",
+                n
+            ),
+        );
+        return Ok(output);
+    }
+
+    output.message(format!("Replaying: {}", cmd));
+    let replay_output = match read(&cmd, &ctx.registry) {
+        Ok(pipeline) => eval_and_record(pipeline, ctx).await,
+        Err(err) => {
+            output.error(ErrorCode::Parse, err);
+            return Ok(output);
+        }
+    };
+    output.errors.extend(replay_output.errors);
+    output.results.extend(replay_output.results);
+    Ok(output)
+}
+
+fn export(path: &str, overwrite: bool, ctx: &Context) -> Result<Output, ()> {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
+
+    if !overwrite && Path::new(path).exists() {
+        output.error(
+            ErrorCode::Io,
+            format!(
+                "ERROR! '{}' already exists. Use `? overwrite=true` to replace it.",
+                path
+            ),
+        );
+        return Ok(output);
+    }
+
+    let commands = match ctx.history.commands() {
+        Ok(val) => val,
+        Err(err) => {
+            output.error(
+                ErrorCode::Io,
+                format!("ERROR! Couldn't read the history: {}", err),
+            );
+            return Ok(output);
+        }
+    };
+
+    // Failed commands are kept as comments, so the script runs cleanly but still shows
+    // everything that was tried. So are commands whose secrets were replaced by `***`, as
+    // they'd only fail.
+    let mut script = String::from("# Exported from the TIGRE history\n");
+    let mut redacted = 0;
+    for (command, failed) in &commands {
+        let secret = !secret_spans(command, &ctx.registry).is_empty();
+        match (failed, secret) {
+            (true, _) => script.push_str(&format!("# (failed) {}\n", command)),
+            (false, true) => {
+                script.push_str(&format!("# (secret removed) {}\n", command));
+                redacted += 1;
+            }
+            (false, false) => script.push_str(&format!("{}\n", command)),
+        }
+    }
+
+    match fs::write(path, script) {
+        Ok(_) => {
+            output.message(format!(
+                "Exported {} commands to '{}'.",
+                commands.len(),
+                path
+            ));
+            if redacted > 0 {
+                output.warning(
+                    ErrorCode::Args,
+                    format!(
+                        "WARNING! {} commands held a secret, e.g. a password, and were \
+                        commented out. Add the secrets to run them.",
+                        redacted
+                    ),
+                );
+            }
+        }
+        Err(err) => output.error(
+            ErrorCode::Io,
+            format!("ERROR! Couldn't write '{}': {}", path, err),
+        ),
+    }
+    Ok(output)
+}
+
+pub struct HistoryCommand;
+
+impl Command for HistoryCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &HISTORY_SCHEMA
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            let limit = args.options.integer("limit").unwrap_or(20);
+            Ok(match args.subcommand() {
+                None => list("", limit, ctx).unwrap(),
+                Some("search") => list(args.text("text")?, limit, ctx).unwrap(),
+                Some("replay") => replay(args.integer("n")?, ctx).await.unwrap(),
+                Some("export") => {
+                    export(args.text("path")?, args.options.boolean("overwrite"), ctx).unwrap()
+                }
+                _ => return Err(args.unknown_subcommand()),
+            })
+        }
+        .boxed()
+    }
+}
@@ -1,11 +1,12 @@
 use crate::output::Output;
-use crate::appstate::AppState;
+use crate::appstate::SharedState;
+use crate::command::{ArgError, Arguments, Command, CommandSchema, Location};
 use crate::db::{get_as_json, get_layer_symbology, inspect_layer, inspect_layer_at_location};
-use std::collections::HashMap;
-use tokio::sync::Mutex;
-use tauri::{State, Manager};
+use crate::metadata::get_layer_metadata;
+use crate::registry::Context;
+use futures::future::{BoxFuture, FutureExt};
 use actix_web::{App, HttpServer, Responder, HttpResponse};
-use tauri::async_runtime::spawn;
+use tokio::spawn;
 use actix_web::web;
 
 #[derive(serde::Serialize)]
@@ -27,8 +28,8 @@ struct GeometryRequest {
     bb: Vec<Vec<f32>>
 }
 
-async fn geometry(req: web::Json<GeometryRequest>, app_handle: web::Data<tauri::AppHandle>) -> impl Responder {
-    let res: String = match get_as_json(&req.table, req.bb.clone(), app_handle.get_ref().clone()).await {
+async fn geometry(req: web::Json<GeometryRequest>, state: web::Data<SharedState>) -> impl Responder {
+    let res: String = match get_as_json(&req.table, req.bb.clone(), state.get_ref()).await {
         Ok(val) => val,
         Err(e) => {
             return HttpResponse::BadRequest().json(Response {
@@ -50,8 +51,31 @@ struct SymbologyRequest {
     table: String
 }
 
-async fn symbology(req: web::Json<SymbologyRequest>, app_handle: web::Data<tauri::AppHandle>) -> impl Responder {
-    let res: String = match get_layer_symbology(&req.schema, &req.table, app_handle.get_ref().clone()).await {
+async fn symbology(req: web::Json<SymbologyRequest>, state: web::Data<SharedState>) -> impl Responder {
+    let res: String = match get_layer_symbology(&req.schema, &req.table, state.get_ref()).await {
+        Ok(val) => val,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(Response {
+                message: e,
+                result: None
+            });
+        }
+    };
+
+    HttpResponse::Ok().json(Response {
+        message: "Done.".to_string(),
+        result: Some(res)
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct MetadataRequest {
+    schema: String,
+    table: String
+}
+
+async fn metadata(req: web::Json<MetadataRequest>, state: web::Data<SharedState>) -> impl Responder {
+    let res: String = match get_layer_metadata(&req.schema, &req.table, state.get_ref()).await {
         Ok(val) => val,
         Err(e) => {
             return HttpResponse::BadRequest().json(Response {
@@ -72,9 +96,9 @@ struct InspectRequest {
     table: String
 }
 
-async fn inspect(req: web::Json<InspectRequest>, app_handle: web::Data<tauri::AppHandle>) -> impl Responder {
-    let state: State<'_, Mutex<AppState>> = app_handle.get_ref().state();
-    let res: String = match inspect_layer(&req.table, &state.lock().await.pgsql_connection).await {
+async fn inspect(req: web::Json<InspectRequest>, state: web::Data<SharedState>) -> impl Responder {
+    let database = state.lock().await.database();
+    let res: String = match inspect_layer(&req.table, &database, None).await {
         Ok(val) => val,
         Err(e) => {
             return HttpResponse::BadRequest().json(Response {
@@ -96,9 +120,19 @@ struct InspectAtLocationRequest {
     location: String
 }
 
-async fn inspect_location(req: web::Json<InspectAtLocationRequest>, app_handle: web::Data<tauri::AppHandle>) -> impl Responder {
-    let state: State<'_, Mutex<AppState>> = app_handle.get_ref().state();
-    let res: String = match inspect_layer_at_location(&req.table, &state.lock().await.pgsql_connection, &req.location).await {
+async fn inspect_location(req: web::Json<InspectAtLocationRequest>, state: web::Data<SharedState>) -> impl Responder {
+    let location = match req.location.parse::<Location>() {
+        Ok(val) => val,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(Response {
+                message: e,
+                result: None
+            });
+        }
+    };
+
+    let database = state.lock().await.database();
+    let res: String = match inspect_layer_at_location(&req.table, &database, &location, None).await {
         Ok(val) => val,
         Err(e) => {
             return HttpResponse::BadRequest().json(Response {
@@ -115,12 +149,13 @@ async fn inspect_location(req: web::Json<InspectAtLocationRequest>, app_handle:
 }
 
 
-pub async fn start_server(app_handle: tauri::AppHandle) -> std::io::Result<actix_web::dev::Server> {
+pub async fn start_server(state: SharedState) -> std::io::Result<actix_web::dev::Server> {
     let server = HttpServer::new(move || App::new()
-            .app_data(web::Data::new(app_handle.app_handle().clone()))
+            .app_data(web::Data::new(state.clone()))
             .route("/", web::get().to(index))
             .route("/geometry", web::get().to(geometry))
             .route("/symbology", web::get().to(symbology))
+            .route("/metadata", web::get().to(metadata))
             .route("/inspect", web::get().to(inspect))
             .route("/inspect-location", web::get().to(inspect_location))
         )
@@ -131,54 +166,61 @@ pub async fn start_server(app_handle: tauri::AppHandle) -> std::io::Result<actix
 }
 
 pub async fn hytigre_on(
-    state: &State<'_, Mutex<AppState>>,
+    shared_state: &SharedState,
 ) -> Result<Output, ()> {
     let mut output = Output {
         errors: vec![],
         results: vec![],
     };
 
-    let mut state = state.lock().await;
-    let app_handle = state.app_handle.clone();
-    match &state.hytigre {
-        Some(handle) => {
-            handle.abort();
-        }
-        None => {}
-    };
+    let server_state = shared_state.clone();
+    let mut state = shared_state.lock().await;
+    if let Some(handle) = &state.hytigre {
+        handle.abort();
+    }
 
     state.hytigre = Some(spawn(async move { 
-        start_server(app_handle).await.unwrap().await
+        start_server(server_state).await.unwrap().await
     }));
  
-    output.results.push("HyTigre has been turned on.".to_string());
+    output.message("HyTigre has been turned on.");
     Ok(output)
 }
 
-pub async fn hytigre(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
-) -> Result<Output, ()> {
-    let mut output = Output {
-        errors: vec![],
-        results: vec![],
-    };
+static HYTIGRE_SCHEMA: CommandSchema = CommandSchema {
+    name: "hytigre",
+    summary: "Control the HyTigre HTTP server.",
+    args: &[],
+    options: &[],
+    subcommands: &[CommandSchema {
+        name: "on",
+        summary: "Start (or restart) the HyTigre server on 127.0.0.1:8080.",
+        args: &[],
+        options: &[],
+        subcommands: &[],
+        examples: &["hytigre on"],
+    }],
+    examples: &[],
+};
+
+pub struct HytigreCommand;
+
+impl Command for HytigreCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &HYTIGRE_SCHEMA
+    }
 
-    if ast["args"].is_empty() {
-        output
-            .errors
-            .push("ERROR! No arguments provided for command 'hytigre'.".to_string())
-    } else {
-        match ast["args"][0] {
-            "on" => {
-                let hytigre_on_output = hytigre_on(state).await.unwrap();
-                output.errors.extend(hytigre_on_output.errors);
-                output.results.extend(hytigre_on_output.results);
-            },
-            &_ => output
-                .errors
-                .push("ERROR! Found unknown argument.".to_string()),
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            match args.subcommand() {
+                Some("on") => Ok(hytigre_on(&ctx.state).await.unwrap()),
+                _ => Err(args.unknown_subcommand()),
+            }
         }
+        .boxed()
     }
-    Ok(output)
 }
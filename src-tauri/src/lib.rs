@@ -1,47 +1,27 @@
 #![feature(file_buffered)]
 pub mod add;
 pub mod appstate;
+pub mod command;
+pub mod complete;
 pub mod db;
+pub mod events;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod help;
+pub mod history;
+pub mod metadata;
+pub mod options;
 pub mod output;
+pub mod parser;
+pub mod pipeline;
+pub mod profiles;
+pub mod registry;
 pub mod repl;
+pub mod script;
 pub mod tools;
 pub mod gdal_utils;
 pub mod symbology;
 pub mod hytigre;
 
-use crate::appstate::AppState;
-use crate::db::{get_as_json, get_as_wkt, get_as_json_gpkg, get_layer_symbology, PGConnection};
-use crate::repl::{eval, read};
-use postgres::{Client, NoTls};
-use std::string::String;
-use tauri::Manager;
-use tokio::sync::Mutex;
-
-// TAURI STUFF
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub async fn run() {
-    tauri::Builder::default()
-        .plugin(tauri_plugin_dialog::init())
-        .setup(|app| {
-            let state = Mutex::new(AppState {
-                app_handle: app.handle().clone(),
-                pgsql_connection: PGConnection::default(),
-                pgsql_client: Client::connect("", NoTls),
-                hytigre: None,
-            });
-
-            app.manage(state);
-            Ok(())
-        })
-        .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![
-            read,
-            eval,
-            get_as_json,
-            get_as_wkt,
-            get_as_json_gpkg,
-            get_layer_symbology
-        ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
-}
+#[cfg(feature = "gui")]
+pub use crate::gui::run;
@@ -0,0 +1,720 @@
+use crate::appstate::AppState;
+use crate::command::{ArgError, ArgKind, ArgSpec, Arguments, Command, CommandSchema};
+use crate::options::{OptionKind, OptionSpec, Options};
+use crate::output::{ErrorCode, Output, OutputResult};
+use crate::registry::Context;
+use crate::tools::split_table_name;
+use futures::future::{BoxFuture, FutureExt};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Client;
+
+/// Descriptive metadata of a layer, as found in a QGIS `.qmd` or ISO 19139 sidecar. Fields
+/// the sidecar leaves out are empty.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct LayerMetadata {
+    pub identifier: String,
+    pub title: String,
+    pub r#abstract: String,
+    pub language: String,
+    pub r#type: String,
+    pub keywords: Vec<String>,
+    pub contact_name: String,
+    pub contact_organization: String,
+    pub contact_email: String,
+    pub rights: String,
+}
+
+/// Fields of [`LayerMetadata`] that hold a single value, in the order they're shown.
+const TEXT_FIELDS: &[&str] = &[
+    "identifier",
+    "title",
+    "abstract",
+    "language",
+    "type",
+    "contact_name",
+    "contact_organization",
+    "contact_email",
+    "rights",
+];
+
+impl LayerMetadata {
+    pub fn is_empty(&self) -> bool {
+        *self == LayerMetadata::default()
+    }
+
+    fn field_mut(&mut self, field: &str) -> Option<&mut String> {
+        match field {
+            "identifier" => Some(&mut self.identifier),
+            "title" => Some(&mut self.title),
+            "abstract" => Some(&mut self.r#abstract),
+            "language" => Some(&mut self.language),
+            "type" => Some(&mut self.r#type),
+            "contact_name" => Some(&mut self.contact_name),
+            "contact_organization" => Some(&mut self.contact_organization),
+            "contact_email" => Some(&mut self.contact_email),
+            "rights" => Some(&mut self.rights),
+            _ => None,
+        }
+    }
+
+    fn field(&self, field: &str) -> &str {
+        match field {
+            "identifier" => &self.identifier,
+            "title" => &self.title,
+            "abstract" => &self.r#abstract,
+            "language" => &self.language,
+            "type" => &self.r#type,
+            "contact_name" => &self.contact_name,
+            "contact_organization" => &self.contact_organization,
+            "contact_email" => &self.contact_email,
+            "rights" => &self.rights,
+            _ => "",
+        }
+    }
+
+    /// Writes the metadata as a QGIS `.qmd` document.
+    pub fn to_qmd(&self) -> String {
+        let escape = |text: &str| quick_xml::escape::escape(text).to_string();
+        let element = |name: &str, text: &str| match text.is_empty() {
+            true => format!("  <{}></{}>\n", name, name),
+            false => format!("  <{}>{}</{}>\n", name, escape(text), name),
+        };
+
+        let mut qmd = String::from(
+            "<!DOCTYPE qgis PUBLIC 'http://mrcc.com/qgis.dtd' 'SYSTEM'>\n<qgis version=\"3\">\n",
+        );
+        qmd.push_str(&element("identifier", &self.identifier));
+        qmd.push_str("  <parentidentifier></parentidentifier>\n");
+        qmd.push_str(&element("language", &self.language));
+        qmd.push_str(&element("type", &self.r#type));
+        qmd.push_str(&element("title", &self.title));
+        qmd.push_str(&element("abstract", &self.r#abstract));
+        if !self.keywords.is_empty() {
+            qmd.push_str("  <keywords vocabulary=\"\">\n");
+            for keyword in &self.keywords {
+                qmd.push_str(&format!("    <keyword>{}</keyword>\n", escape(keyword)));
+            }
+            qmd.push_str("  </keywords>\n");
+        }
+        if !(self.contact_name.is_empty()
+            && self.contact_organization.is_empty()
+            && self.contact_email.is_empty())
+        {
+            qmd.push_str("  <contact>\n");
+            qmd.push_str(&format!("  {}", element("name", &self.contact_name)));
+            qmd.push_str(&format!(
+                "  {}",
+                element("organization", &self.contact_organization)
+            ));
+            qmd.push_str(&format!("  {}", element("email", &self.contact_email)));
+            qmd.push_str("  </contact>\n");
+        }
+        qmd.push_str("  <links/>\n");
+        qmd.push_str("  <dates/>\n");
+        qmd.push_str("  <fees></fees>\n");
+        if !self.rights.is_empty() {
+            qmd.push_str(&element("rights", &self.rights));
+        }
+        qmd.push_str("  <encoding></encoding>\n");
+        qmd.push_str("</qgis>\n");
+        qmd
+    }
+}
+
+/// Where a field of [`LayerMetadata`] is found in a document, by the local names of the
+/// elements leading to it.
+type Rule = (&'static [&'static str], &'static str);
+
+/// Paths from the root of a `.qmd`.
+const QMD_RULES: &[Rule] = &[
+    (&["qgis", "identifier"], "identifier"),
+    (&["qgis", "title"], "title"),
+    (&["qgis", "abstract"], "abstract"),
+    (&["qgis", "language"], "language"),
+    (&["qgis", "type"], "type"),
+    (&["qgis", "keywords", "keyword"], "keywords"),
+    (&["qgis", "contact", "name"], "contact_name"),
+    (&["qgis", "contact", "organization"], "contact_organization"),
+    (&["qgis", "contact", "email"], "contact_email"),
+    (&["qgis", "rights"], "rights"),
+];
+
+/// Trailing paths in an ISO 19139 document, whose nesting varies between producers. The
+/// first match of a field wins, so the contact of the metadata is preferred over any other.
+const ISO_RULES: &[Rule] = &[
+    (
+        &["MD_Metadata", "fileIdentifier", "CharacterString"],
+        "identifier",
+    ),
+    (
+        &["citation", "CI_Citation", "title", "CharacterString"],
+        "title",
+    ),
+    (&["abstract", "CharacterString"], "abstract"),
+    (&["MD_Metadata", "language", "CharacterString"], "language"),
+    (&["MD_Metadata", "language", "LanguageCode"], "language"),
+    (&["MD_Metadata", "hierarchyLevel", "MD_ScopeCode"], "type"),
+    (&["keyword", "CharacterString"], "keywords"),
+    (&["individualName", "CharacterString"], "contact_name"),
+    (
+        &["organisationName", "CharacterString"],
+        "contact_organization",
+    ),
+    (
+        &["electronicMailAddress", "CharacterString"],
+        "contact_email",
+    ),
+    (&["useLimitation", "CharacterString"], "rights"),
+    (&["otherConstraints", "CharacterString"], "rights"),
+];
+
+/// Whether the elements in `path` lead to `rule`, either from the root or, when not
+/// `anchored`, anywhere in the document.
+fn matches(path: &[String], rule: &[&str], anchored: bool) -> bool {
+    if path.len() < rule.len() || (anchored && path.len() != rule.len()) {
+        return false;
+    }
+    path[path.len() - rule.len()..]
+        .iter()
+        .zip(rule)
+        .all(|(element, name)| element == name)
+}
+
+/// Reads a QGIS `.qmd` or ISO 19139 document, told apart by their root element.
+pub fn parse_metadata(xml: &str) -> Result<LayerMetadata, String> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut metadata = LayerMetadata::default();
+    let mut rules: Option<(&[Rule], bool)> = None;
+    let mut path: Vec<String> = vec![];
+
+    // Code lists keep their value in an attribute, e.g. `<LanguageCode codeListValue="eng">`
+    let code_list_value = |element: &BytesStart| {
+        element
+            .attributes()
+            .flatten()
+            .find(|attribute| attribute.key.local_name().as_ref() == b"codeListValue")
+            .and_then(|attribute| attribute.unescape_value().ok().map(|val| val.to_string()))
+    };
+
+    loop {
+        let (text, closes) = match reader.read_event() {
+            Ok(Event::Start(element)) => {
+                path.push(String::from_utf8_lossy(element.local_name().as_ref()).to_string());
+                (code_list_value(&element), false)
+            }
+            Ok(Event::Empty(element)) => {
+                path.push(String::from_utf8_lossy(element.local_name().as_ref()).to_string());
+                (code_list_value(&element), true)
+            }
+            Ok(Event::Text(text)) => match text.unescape() {
+                Ok(val) => (Some(val.to_string()), false),
+                Err(err) => return Err(format!("Invalid XML: {}", err)),
+            },
+            Ok(Event::CData(data)) => (
+                Some(String::from_utf8_lossy(&data.into_inner()).to_string()),
+                false,
+            ),
+            Ok(Event::End(_)) => {
+                path.pop();
+                continue;
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => continue,
+            Err(err) => return Err(format!("Invalid XML: {}", err)),
+        };
+
+        if rules.is_none() {
+            rules = match path.first().map(String::as_str) {
+                Some("qgis") => Some((QMD_RULES, true)),
+                Some("MD_Metadata") => Some((ISO_RULES, false)),
+                Some(root) => {
+                    return Err(format!(
+                        "Expected QGIS or ISO 19139 metadata, found a <{}> document.",
+                        root
+                    ))
+                }
+                None => None,
+            };
+        }
+
+        if let (Some(text), Some((rules, anchored))) = (text, rules) {
+            let rule = rules
+                .iter()
+                .find(|(rule, _)| matches(&path, rule, anchored));
+            if let Some((_, field)) = rule {
+                match *field {
+                    "keywords" => metadata.keywords.push(text),
+                    field => {
+                        let value = metadata.field_mut(field).unwrap();
+                        if value.is_empty() {
+                            *value = text;
+                        }
+                    }
+                }
+            }
+        }
+
+        if closes {
+            path.pop();
+        }
+    }
+
+    match rules {
+        Some(_) => Ok(metadata),
+        None => Err("The document is empty.".to_string()),
+    }
+}
+
+/// Finds the metadata sidecar of a dataset: a QGIS `data.qmd`, or ISO 19139 in `data.xml` or
+/// `data.shp.xml`.
+pub fn find_sidecar(dataset_path: &str) -> Option<PathBuf> {
+    let path = Path::new(dataset_path);
+    [
+        path.with_extension("qmd"),
+        path.with_extension("xml"),
+        PathBuf::from(format!("{}.xml", dataset_path)),
+    ]
+    .into_iter()
+    .find(|sidecar| sidecar.is_file() && sidecar != path)
+}
+
+/// Reads and parses the metadata document at `path`.
+pub fn read_metadata(path: &Path) -> Result<LayerMetadata, String> {
+    match fs::read_to_string(path) {
+        Ok(xml) => parse_metadata(&xml),
+        Err(err) => Err(format!("Couldn't read '{}': {}", path.display(), err)),
+    }
+}
+
+async fn create_metadata_table(client: &Client) -> Result<(), tokio_postgres::Error> {
+    client
+        .execute(
+            "CREATE TABLE IF NOT EXISTS tigre_metadata (
+                layer_schema TEXT NOT NULL,
+                layer_name TEXT NOT NULL,
+                identifier TEXT NOT NULL DEFAULT '',
+                title TEXT NOT NULL DEFAULT '',
+                abstract TEXT NOT NULL DEFAULT '',
+                language TEXT NOT NULL DEFAULT '',
+                type TEXT NOT NULL DEFAULT '',
+                keywords TEXT[] NOT NULL DEFAULT '{}',
+                contact_name TEXT NOT NULL DEFAULT '',
+                contact_organization TEXT NOT NULL DEFAULT '',
+                contact_email TEXT NOT NULL DEFAULT '',
+                rights TEXT NOT NULL DEFAULT '',
+                PRIMARY KEY (layer_schema, layer_name)
+            )",
+            &[],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Stores the metadata of `schema.name`, replacing whatever it had.
+pub async fn store_metadata(
+    client: &Client,
+    schema: &str,
+    name: &str,
+    metadata: &LayerMetadata,
+) -> Result<(), String> {
+    create_metadata_table(client)
+        .await
+        .map_err(|err| format!("Couldn't create the metadata table: {}", err))?;
+
+    let columns = TEXT_FIELDS.join(", ");
+    let params = (0..TEXT_FIELDS.len())
+        .map(|i| format!("${}", i + 4))
+        .collect::<Vec<String>>();
+    let updates = TEXT_FIELDS
+        .iter()
+        .chain(&["keywords"])
+        .map(|field| format!("{} = EXCLUDED.{}", field, field))
+        .collect::<Vec<String>>();
+    let query = format!(
+        "INSERT INTO tigre_metadata (layer_schema, layer_name, keywords, {})
+        VALUES ($1, $2, $3, {})
+        ON CONFLICT (layer_schema, layer_name) DO UPDATE SET {}",
+        columns,
+        params.join(", "),
+        updates.join(", ")
+    );
+
+    let texts = TEXT_FIELDS
+        .iter()
+        .map(|field| metadata.field(field))
+        .collect::<Vec<&str>>();
+    let mut values: Vec<&(dyn ToSql + Sync)> = vec![&schema, &name, &metadata.keywords];
+    values.extend(texts.iter().map(|text| text as &(dyn ToSql + Sync)));
+
+    match client.execute(query.as_str(), &values).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Couldn't store the metadata: {}", err)),
+    }
+}
+
+/// The stored metadata of `schema.name`, if it has any. Reading doesn't create the metadata
+/// table, so until some metadata is stored no layer has any.
+pub async fn load_metadata(
+    client: &Client,
+    schema: &str,
+    name: &str,
+) -> Result<Option<LayerMetadata>, String> {
+    let row = match client
+        .query_opt(
+            format!(
+            "SELECT keywords, {} FROM tigre_metadata WHERE layer_schema = $1 AND layer_name = $2",
+            TEXT_FIELDS.join(", ")
+        )
+            .as_str(),
+            &[&schema, &name],
+        )
+        .await
+    {
+        Ok(val) => val,
+        Err(err) if err.code() == Some(&SqlState::UNDEFINED_TABLE) => return Ok(None),
+        Err(err) => return Err(format!("Couldn't read the metadata: {}", err)),
+    };
+
+    Ok(row.map(|row| {
+        let mut metadata = LayerMetadata {
+            keywords: row.get(0),
+            ..Default::default()
+        };
+        for (i, field) in TEXT_FIELDS.iter().enumerate() {
+            *metadata.field_mut(field).unwrap() = row.get(i + 1);
+        }
+        metadata
+    }))
+}
+
+/// The metadata of a layer as JSON, for HyTigre.
+pub async fn get_layer_metadata(
+    schema: &str,
+    table: &str,
+    state: &Mutex<AppState>,
+) -> Result<String, String> {
+    let database = state.lock().await.database();
+    let client = match database.client().await {
+        Ok(val) => val,
+        Err(err) => return Err(format!("ERROR! {}", err)),
+    };
+
+    match load_metadata(&client, schema, table).await {
+        Ok(Some(metadata)) => Ok(serde_json::to_string(&metadata).unwrap()),
+        Ok(None) => Err(format!(
+            "ERROR! Layer '{}.{}' has no metadata.",
+            schema, table
+        )),
+        Err(err) => Err(format!("ERROR! {}", err)),
+    }
+}
+
+/// Shows, edits, imports or exports the metadata of `layer`, for `metadata`.
+async fn manage_metadata(
+    action: &str,
+    layer: &str,
+    path: Option<&str>,
+    options: &Options,
+    state: &Mutex<AppState>,
+) -> Result<Output, ()> {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
+
+    let database = state.lock().await.database();
+    let client = match database.client().await {
+        Ok(val) => val,
+        Err(_) => {
+            output.error(
+                ErrorCode::NotConnected,
+                "ERROR! You must connect to a database before reading the metadata of a layer.",
+            );
+            return Ok(output);
+        }
+    };
+
+    let (schema, name) = split_table_name(layer);
+    let stored = match load_metadata(&client, &schema, &name).await {
+        Ok(val) => val,
+        Err(err) => {
+            output.error(ErrorCode::Query, format!("ERROR! {}", err));
+            return Ok(output);
+        }
+    };
+
+    match action {
+        "set" => {
+            let mut metadata = stored.unwrap_or_default();
+            for field in TEXT_FIELDS {
+                if let Some(value) = options.text(field) {
+                    *metadata.field_mut(field).unwrap() = value.to_string();
+                }
+            }
+            if let Some(keywords) = options.text("keywords") {
+                metadata.keywords = keywords
+                    .split(',')
+                    .map(|keyword| keyword.trim().to_string())
+                    .filter(|keyword| !keyword.is_empty())
+                    .collect();
+            }
+            match store_metadata(&client, &schema, &name, &metadata).await {
+                Ok(_) => output.message(format!("Updated the metadata of {}.{}.", schema, name)),
+                Err(err) => output.error(ErrorCode::Query, format!("ERROR! {}", err)),
+            }
+        }
+        "import" => {
+            let path = path.unwrap();
+            let metadata = match read_metadata(Path::new(path)) {
+                Ok(val) => val,
+                Err(err) => {
+                    output.error(ErrorCode::Io, format!("ERROR! {}", err));
+                    return Ok(output);
+                }
+            };
+            match store_metadata(&client, &schema, &name, &metadata).await {
+                Ok(_) => output.message(format!(
+                    "Imported the metadata of {}.{} from '{}'.",
+                    schema, name, path
+                )),
+                Err(err) => output.error(ErrorCode::Query, format!("ERROR! {}", err)),
+            }
+        }
+        _ => {
+            let metadata = match stored {
+                Some(val) => val,
+                None => {
+                    output.error(
+                        ErrorCode::Query,
+                        format!(
+                            "ERROR! Layer '{}.{}' has no metadata. Add it with `metadata set` \
+                            or `metadata import`.",
+                            schema, name
+                        ),
+                    );
+                    return Ok(output);
+                }
+            };
+
+            if action == "export" {
+                let path = path.unwrap();
+                if !options.boolean("overwrite") && Path::new(path).exists() {
+                    output.error(
+                        ErrorCode::Io,
+                        format!(
+                            "ERROR! '{}' already exists. Use `? overwrite=true` to replace it.",
+                            path
+                        ),
+                    );
+                    return Ok(output);
+                }
+                match fs::write(path, metadata.to_qmd()) {
+                    Ok(_) => output.message(format!(
+                        "Exported the metadata of {}.{} to '{}'.",
+                        schema, name, path
+                    )),
+                    Err(err) => output.error(
+                        ErrorCode::Io,
+                        format!("ERROR! Couldn't write '{}': {}", path, err),
+                    ),
+                }
+                return Ok(output);
+            }
+
+            let mut rows = TEXT_FIELDS
+                .iter()
+                .map(|field| {
+                    vec![
+                        serde_json::Value::from(*field),
+                        serde_json::Value::from(metadata.field(field)),
+                    ]
+                })
+                .collect::<Vec<Vec<serde_json::Value>>>();
+            rows.insert(
+                5,
+                vec![
+                    serde_json::Value::from("keywords"),
+                    serde_json::Value::from(metadata.keywords.join(", ")),
+                ],
+            );
+            output.results.push(OutputResult::Table {
+                name: format!("{}.{} metadata", schema, name),
+                columns: vec!["field".to_string(), "value".to_string()],
+                rows,
+            });
+        }
+    }
+
+    Ok(output)
+}
+
+const LAYER_ARG: ArgSpec = ArgSpec {
+    name: "layer",
+    kind: ArgKind::Layer,
+    required: true,
+    description: "Layer the metadata describes.",
+};
+
+const SET_OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "title",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Human readable name of the layer.",
+    },
+    OptionSpec {
+        name: "abstract",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Summary of what the layer holds.",
+    },
+    OptionSpec {
+        name: "keywords",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Comma separated keywords, replacing the current ones.",
+    },
+    OptionSpec {
+        name: "identifier",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Unique identifier of the layer, e.g. a URI.",
+    },
+    OptionSpec {
+        name: "language",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Language of the layer, e.g. ENG.",
+    },
+    OptionSpec {
+        name: "type",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Kind of resource, e.g. dataset.",
+    },
+    OptionSpec {
+        name: "contact_name",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Person to contact about the layer.",
+    },
+    OptionSpec {
+        name: "contact_organization",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Organization to contact about the layer.",
+    },
+    OptionSpec {
+        name: "contact_email",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Email address of the contact.",
+    },
+    OptionSpec {
+        name: "rights",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Who may use the layer, and how.",
+    },
+];
+
+static METADATA_SCHEMA: CommandSchema = CommandSchema {
+    name: "metadata",
+    summary: "Read and edit the descriptive metadata of layers.",
+    args: &[],
+    options: &[],
+    subcommands: &[
+        CommandSchema {
+            name: "",
+            summary: "Show the metadata of a layer.",
+            args: &[LAYER_ARG],
+            options: &[],
+            subcommands: &[],
+            examples: &["metadata public.rivers"],
+        },
+        CommandSchema {
+            name: "set",
+            summary: "Change fields of the metadata of a layer.",
+            args: &[LAYER_ARG],
+            options: SET_OPTIONS,
+            subcommands: &[],
+            examples: &[
+                "metadata set public.rivers ? title=`US rivers` keywords=`hydrography, usa`",
+            ],
+        },
+        CommandSchema {
+            name: "import",
+            summary: "Replace the metadata of a layer with a QGIS .qmd or ISO 19139 document.",
+            args: &[
+                LAYER_ARG,
+                ArgSpec {
+                    name: "path",
+                    kind: ArgKind::Path,
+                    required: true,
+                    description: "Document to read.",
+                },
+            ],
+            options: &[],
+            subcommands: &[],
+            examples: &["metadata import public.rivers `us_rivers.qmd`"],
+        },
+        CommandSchema {
+            name: "export",
+            summary: "Write the metadata of a layer to a QGIS .qmd document.",
+            args: &[
+                LAYER_ARG,
+                ArgSpec {
+                    name: "path",
+                    kind: ArgKind::Path,
+                    required: true,
+                    description: "Document to write.",
+                },
+            ],
+            options: &[OptionSpec {
+                name: "overwrite",
+                kind: OptionKind::Boolean,
+                default: Some("false"),
+                description: "Replace the document if it already exists.",
+            }],
+            subcommands: &[],
+            examples: &["metadata export public.rivers `us_rivers.qmd`"],
+        },
+    ],
+    examples: &[],
+};
+
+pub struct MetadataCommand;
+
+impl Command for MetadataCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &METADATA_SCHEMA
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            let action = args.subcommand().unwrap_or("show");
+            Ok(manage_metadata(
+                action,
+                args.text("layer")?,
+                args.optional_text("path"),
+                &args.options,
+                &ctx.state,
+            )
+            .await
+            .unwrap())
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,303 @@
+use crate::command::Spanned;
+use crate::parser::{ParseError, Span, Token};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptionKind {
+    Text,
+    Integer,
+    Number,
+    Boolean,
+}
+
+impl fmt::Display for OptionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionKind::Text => write!(f, "text"),
+            OptionKind::Integer => write!(f, "an integer"),
+            OptionKind::Number => write!(f, "a number"),
+            OptionKind::Boolean => write!(f, "true or false"),
+        }
+    }
+}
+
+/// Declares an optional `key=value` argument accepted by a command.
+#[derive(Debug, Clone, Copy)]
+pub struct OptionSpec {
+    pub name: &'static str,
+    pub kind: OptionKind,
+    pub default: Option<&'static str>,
+    pub description: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OptionValue {
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    Text(String),
+}
+
+impl OptionValue {
+    fn parse(text: &str, kind: OptionKind) -> Option<OptionValue> {
+        match kind {
+            OptionKind::Text => Some(OptionValue::Text(text.to_string())),
+            OptionKind::Integer => text.parse::<i64>().ok().map(OptionValue::Integer),
+            OptionKind::Number => text.parse::<f64>().ok().map(OptionValue::Number),
+            OptionKind::Boolean => match text.to_ascii_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Some(OptionValue::Boolean(true)),
+                "false" | "no" | "off" | "0" => Some(OptionValue::Boolean(false)),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for OptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionValue::Boolean(val) => val.fmt(f),
+            OptionValue::Integer(val) => val.fmt(f),
+            OptionValue::Number(val) => val.fmt(f),
+            OptionValue::Text(val) => val.fmt(f),
+        }
+    }
+}
+
+/// The `key=value` pairs given after `?`, checked against a command's [`OptionSpec`]s.
+/// Options that were not given but declare a default are filled in.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Options(BTreeMap<String, Spanned<OptionValue>>);
+
+impl Options {
+    pub fn defaults(schema: &[OptionSpec]) -> Options {
+        let mut options = Options::default();
+        for spec in schema {
+            if let Some(value) = spec
+                .default
+                .and_then(|val| OptionValue::parse(val, spec.kind))
+            {
+                options.0.insert(
+                    spec.name.to_string(),
+                    Spanned {
+                        value,
+                        span: Span::default(),
+                    },
+                );
+            }
+        }
+        options
+    }
+
+    pub fn parse(
+        tokens: &[Token],
+        schema: &[OptionSpec],
+        command: &str,
+    ) -> Result<Options, ParseError> {
+        let mut options = Options::defaults(schema);
+        let mut given: Vec<&str> = vec![];
+
+        for token in tokens {
+            let (key, text) = match token.text.split_once('=') {
+                Some(val) => val,
+                None => {
+                    return Err(ParseError::new(
+                        format!(
+                            "Expected an option in the form key=value, found '{}'.",
+                            token.text
+                        ),
+                        token.span,
+                    ))
+                }
+            };
+            let key_span = Span::new(token.span.start, token.span.start + key.len());
+
+            let spec = match schema.iter().find(|spec| spec.name == key) {
+                Some(spec) => spec,
+                None if schema.is_empty() => {
+                    return Err(ParseError::new(
+                        format!("Command '{}' does not take optional arguments.", command),
+                        token.span,
+                    ))
+                }
+                None => {
+                    return Err(ParseError::new(
+                        format!(
+                            "Unknown option '{}' for command '{}'. Valid options are: {}.",
+                            key,
+                            command,
+                            schema
+                                .iter()
+                                .map(|spec| spec.name)
+                                .collect::<Vec<&str>>()
+                                .join(", ")
+                        ),
+                        key_span,
+                    ))
+                }
+            };
+
+            if given.contains(&key) {
+                return Err(ParseError::new(
+                    format!("Option '{}' was given more than once.", key),
+                    key_span,
+                ));
+            }
+            given.push(key);
+
+            let value = match OptionValue::parse(text, spec.kind) {
+                Some(val) => val,
+                None => {
+                    return Err(ParseError::new(
+                        format!(
+                            "Expected {} for option '{}', found '{}'.",
+                            spec.kind, key, text
+                        ),
+                        token.span,
+                    ))
+                }
+            };
+
+            options.0.insert(
+                key.to_string(),
+                Spanned {
+                    value,
+                    span: token.span,
+                },
+            );
+        }
+
+        Ok(options)
+    }
+
+    /// Sets an option that wasn't given, e.g. a default worked out from other arguments.
+    pub fn insert(&mut self, name: &str, value: OptionValue) {
+        self.0.insert(
+            name.to_string(),
+            Spanned {
+                value,
+                span: Span::default(),
+            },
+        );
+    }
+
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.0.get(name).map(|val| &val.value) {
+            Some(OptionValue::Text(val)) => Some(val.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        match self.0.get(name).map(|val| &val.value) {
+            Some(OptionValue::Integer(val)) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self, name: &str) -> Option<f64> {
+        match self.0.get(name).map(|val| &val.value) {
+            Some(OptionValue::Number(val)) => Some(*val),
+            Some(OptionValue::Integer(val)) => Some(*val as f64),
+            _ => None,
+        }
+    }
+
+    pub fn boolean(&self, name: &str) -> bool {
+        matches!(
+            self.0.get(name).map(|val| &val.value),
+            Some(OptionValue::Boolean(true))
+        )
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &OptionValue)> {
+        self.0.iter().map(|(key, val)| (key.as_str(), &val.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::tokenize;
+
+    const SCHEMA: &[OptionSpec] = &[
+        OptionSpec {
+            name: "out",
+            kind: OptionKind::Text,
+            default: None,
+            description: "",
+        },
+        OptionSpec {
+            name: "quad_segs",
+            kind: OptionKind::Integer,
+            default: Some("8"),
+            description: "",
+        },
+        OptionSpec {
+            name: "overwrite",
+            kind: OptionKind::Boolean,
+            default: Some("false"),
+            description: "",
+        },
+    ];
+
+    fn parse(source: &str, schema: &[OptionSpec]) -> Result<Options, ParseError> {
+        Options::parse(&tokenize(source).unwrap(), schema, "buffer")
+    }
+
+    #[test]
+    fn parse_fills_in_defaults() {
+        let options = parse("", SCHEMA).unwrap();
+        assert_eq!(options.text("out"), None);
+        assert_eq!(options.integer("quad_segs"), Some(8));
+        assert!(!options.boolean("overwrite"));
+    }
+
+    #[test]
+    fn parse_typed_values() {
+        let options = parse(
+            "out=`public.wide rivers` quad_segs=16 overwrite=yes",
+            SCHEMA,
+        )
+        .unwrap();
+        assert_eq!(options.text("out"), Some("public.wide rivers"));
+        assert_eq!(options.integer("quad_segs"), Some(16));
+        assert!(options.boolean("overwrite"));
+    }
+
+    #[test]
+    fn parse_keeps_equals_signs_in_values() {
+        let options = parse("out=a=b", SCHEMA).unwrap();
+        assert_eq!(options.text("out"), Some("a=b"));
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_option() {
+        let err = parse("overwrite", SCHEMA).unwrap_err();
+        assert_eq!(err.span, Span::new(0, 9));
+
+        let err = parse("quad_segs=8 colour=red", SCHEMA).unwrap_err();
+        assert!(err.message.starts_with("Unknown option 'colour'"));
+        assert_eq!(err.span, Span::new(12, 18));
+
+        let err = parse("out=a out=b", SCHEMA).unwrap_err();
+        assert_eq!(err.message, "Option 'out' was given more than once.");
+        assert_eq!(err.span, Span::new(6, 9));
+
+        let err = parse("quad_segs=many", SCHEMA).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Expected an integer for option 'quad_segs', found 'many'."
+        );
+        assert_eq!(err.span, Span::new(0, 14));
+
+        let err = parse("out=a", &[]).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Command 'buffer' does not take optional arguments."
+        );
+    }
+}
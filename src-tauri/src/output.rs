@@ -1,6 +1,199 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::string::String;
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The command couldn't be parsed.
+    Parse,
+    /// The arguments don't match the command's schema.
+    Args,
+    /// The command needs a database connection, but none was made.
+    NotConnected,
+    /// Connecting to the database failed.
+    Connection,
+    /// A query against the database failed.
+    Query,
+    /// A file couldn't be read or written.
+    Io,
+    /// GDAL couldn't open or convert a dataset.
+    Dataset,
+    /// A script failed to parse or stopped early.
+    Script,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub code: ErrorCode,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputResult {
+    Message {
+        text: String,
+    },
+    Table {
+        name: String,
+        columns: Vec<String>,
+        rows: Vec<Vec<serde_json::Value>>,
+    },
+    Layer {
+        schema: String,
+        name: String,
+    },
+    GeoJson {
+        geojson: serde_json::Value,
+    },
+    Stats {
+        values: BTreeMap<String, serde_json::Value>,
+    },
+}
+
+impl OutputResult {
+    /// Builds a table from a JSON array of objects, e.g. the result of `json_agg`.
+    pub fn table_from_json(name: &str, json: &serde_json::Value) -> OutputResult {
+        let objects = json
+            .as_array()
+            .map(|rows| {
+                rows.iter()
+                    .filter_map(|row| row.as_object())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let mut columns: Vec<String> = vec![];
+        for object in &objects {
+            for key in object.keys() {
+                if !columns.contains(key) {
+                    columns.push(key.clone());
+                }
+            }
+        }
+
+        let rows = objects
+            .iter()
+            .map(|object| {
+                columns
+                    .iter()
+                    .map(|column| {
+                        object
+                            .get(column)
+                            .cloned()
+                            .unwrap_or(serde_json::Value::Null)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        OutputResult::Table {
+            name: name.to_string(),
+            columns,
+            rows,
+        }
+    }
+}
+
+impl fmt::Display for OutputResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputResult::Message { text } => write!(f, "{}", text),
+            OutputResult::Table {
+                name,
+                columns,
+                rows,
+            } => {
+                writeln!(f, "{} ({} rows)", name, rows.len())?;
+                write!(f, "{}", columns.join("\t"))?;
+                for row in rows {
+                    let values = row
+                        .iter()
+                        .map(|value| match value {
+                            serde_json::Value::String(val) => val.clone(),
+                            _ => value.to_string(),
+                        })
+                        .collect::<Vec<String>>();
+                    write!(f, "\n{}", values.join("\t"))?;
+                }
+                Ok(())
+            }
+            OutputResult::Layer { schema, name } => write!(f, "{}.{}", schema, name),
+            OutputResult::GeoJson { geojson } => write!(f, "{}", geojson),
+            OutputResult::Stats { values } => {
+                let values = values
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect::<Vec<String>>();
+                write!(f, "{}", values.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Output {
-    pub errors: Vec<String>,
-    pub results: Vec<String>,
+    pub errors: Vec<Diagnostic>,
+    pub results: Vec<OutputResult>,
+}
+
+impl Output {
+    pub fn error(&mut self, code: ErrorCode, message: impl Into<String>) {
+        self.errors.push(Diagnostic {
+            code,
+            severity: Severity::Error,
+            message: message.into(),
+        });
+    }
+
+    pub fn warning(&mut self, code: ErrorCode, message: impl Into<String>) {
+        self.errors.push(Diagnostic {
+            code,
+            severity: Severity::Warning,
+            message: message.into(),
+        });
+    }
+
+    pub fn message(&mut self, text: impl Into<String>) {
+        self.results
+            .push(OutputResult::Message { text: text.into() });
+    }
+
+    /// True if any diagnostic is an error, as opposed to a warning.
+    pub fn failed(&self) -> bool {
+        self.errors
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|err| {
+            serde_json::json!({
+                "errors": [{
+                    "code": ErrorCode::Io,
+                    "severity": Severity::Error,
+                    "message": format!("ERROR! Couldn't serialize output: {}", err),
+                }],
+                "results": [],
+            })
+            .to_string()
+        })
+    }
 }
@@ -0,0 +1,301 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Byte range of a token in the REPL input.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// An empty span just past the end of `source`, used for "missing argument" errors.
+    pub fn end_of(source: &str) -> Span {
+        Span::new(source.len(), source.len())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub text: String,
+    pub span: Span,
+    pub quoted: bool,
+}
+
+impl Token {
+    /// True for the bare `?` that separates positional arguments from optional ones.
+    pub fn is_separator(&self) -> bool {
+        !self.quoted && self.text == "?"
+    }
+
+    /// True for a bare `|`, which passes the output of one command on to the next.
+    pub fn is_pipe(&self) -> bool {
+        !self.quoted && self.text == "|"
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl ParseError {
+    pub fn new(message: impl Into<String>, span: Span) -> ParseError {
+        ParseError {
+            message: message.into(),
+            span,
+        }
+    }
+
+    /// Formats the error with the offending input underlined, e.g.
+    ///
+    /// ```text
+    /// ERROR! Expected a number for <distance>, found 'abc'.
+    ///   buffer rivers abc
+    ///                 ^^^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let line_start = source[..self.span.start.min(source.len())]
+            .rfind('\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let line_end = source[line_start..]
+            .find('\n')
+            .map(|i| line_start + i)
+            .unwrap_or(source.len());
+        let line = &source[line_start..line_end];
+
+        let start = self.span.start.clamp(line_start, line_end);
+        let end = self.span.end.clamp(start, line_end);
+        let column = source[line_start..start].chars().count();
+        let width = source[start..end].chars().count().max(1);
+
+        format!(
+            "ERROR! {}\n  {}\n  {}{}",
+            self.message,
+            line,
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl ParseError {
+    /// Like [`ParseError::render`], but with every span of `secrets` in `source` shown as
+    /// `***`, so a password that didn't parse isn't echoed back.
+    pub fn render_redacted(&self, source: &str, secrets: &[Span]) -> String {
+        let mut source = source.to_string();
+        let mut span = self.span;
+        // Replacing from the end keeps the earlier secrets pointing at the right text, and
+        // the error's span moves with whatever it points at
+        for secret in secrets.iter().rev() {
+            source.replace_range(secret.start..secret.end, "***");
+            let shift = |offset: usize| offset + 3 - (secret.end - secret.start);
+            span.start = match span.start {
+                start if start >= secret.end => shift(start),
+                start if start > secret.start => secret.start,
+                start => start,
+            };
+            span.end = match span.end {
+                end if end >= secret.end => shift(end),
+                end if end > secret.start => secret.start + 3,
+                end => end,
+            };
+        }
+        ParseError::new(self.message.clone(), span).render(&source)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ERROR! {}", self.message)
+    }
+}
+
+/// Splits REPL input into whitespace separated tokens. Text wrapped in backticks is kept
+/// together as a single token and may contain the escapes \` and \\.
+pub fn tokenize(source: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens: Vec<Token> = vec![];
+    let mut current: Option<Token> = None;
+    let mut quote_start: Option<usize> = None;
+    let mut chars = source.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let end = i + c.len_utf8();
+
+        if let Some(opened_at) = quote_start {
+            let token = current.get_or_insert_with(|| Token {
+                text: String::new(),
+                span: Span::new(opened_at, opened_at),
+                quoted: true,
+            });
+
+            match c {
+                '`' => {
+                    token.span.end = end;
+                    tokens.push(current.take().unwrap());
+                    quote_start = None;
+                }
+                // Any other backslash is kept as is, so Windows paths don't need escaping
+                '\\' if matches!(chars.peek(), Some((_, '`')) | Some((_, '\\'))) => {
+                    token.text.push(chars.next().unwrap().1);
+                }
+                _ => token.text.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '`' => {
+                // Text directly before a quote belongs to the same token, e.g. key=`a b`
+                if let Some(token) = current.as_mut() {
+                    token.quoted = true;
+                } else {
+                    current = Some(Token {
+                        text: String::new(),
+                        span: Span::new(i, i),
+                        quoted: true,
+                    });
+                }
+                quote_start = Some(i);
+            }
+            c if c.is_whitespace() => {
+                if let Some(token) = current.take() {
+                    tokens.push(token);
+                }
+            }
+            _ => {
+                let token = current.get_or_insert_with(|| Token {
+                    text: String::new(),
+                    span: Span::new(i, i),
+                    quoted: false,
+                });
+                token.text.push(c);
+                token.span.end = end;
+            }
+        }
+    }
+
+    if let Some(opened_at) = quote_start {
+        return Err(ParseError::new(
+            "Unterminated quote.",
+            Span::new(opened_at, opened_at + 1),
+        ));
+    }
+
+    if let Some(token) = current.take() {
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(source: &str) -> Vec<String> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.text)
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_empty_input() {
+        assert!(tokenize("").unwrap().is_empty());
+        assert!(tokenize("  \t ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn tokenize_spans() {
+        let tokens = tokenize("buffer  rivers 100").unwrap();
+        let spans = tokens.iter().map(|token| token.span).collect::<Vec<Span>>();
+        assert_eq!(
+            spans,
+            vec![Span::new(0, 6), Span::new(8, 14), Span::new(15, 18)]
+        );
+        assert!(tokens.iter().all(|token| !token.quoted));
+    }
+
+    #[test]
+    fn tokenize_backticks() {
+        let tokens = tokenize("add `my file.shp` ?").unwrap();
+        assert_eq!(tokens[1].text, "my file.shp");
+        assert_eq!(tokens[1].span, Span::new(4, 17));
+        assert!(tokens[1].quoted);
+        assert!(tokens[2].is_separator());
+
+        // A quoted `?` or `|` is plain text
+        let tokens = tokenize("`?` `|`").unwrap();
+        assert!(!tokens[0].is_separator());
+        assert!(!tokens[1].is_pipe());
+
+        assert_eq!(texts("name=`a b` x"), vec!["name=a b", "x"]);
+        assert_eq!(texts("``"), vec![""]);
+    }
+
+    #[test]
+    fn tokenize_escapes() {
+        assert_eq!(texts(r"`a\`b\\c`"), vec![r"a`b\c"]);
+        // Other backslashes are kept, so Windows paths don't need escaping
+        assert_eq!(texts(r"`C:\data\rivers.shp`"), vec![r"C:\data\rivers.shp"]);
+    }
+
+    #[test]
+    fn tokenize_unterminated_backtick() {
+        let err = tokenize("add `my file").unwrap_err();
+        assert_eq!(err.message, "Unterminated quote.");
+        assert_eq!(err.span, Span::new(4, 5));
+
+        assert!(tokenize(r"`a\`").is_err());
+    }
+
+    #[test]
+    fn render_underlines_the_span() {
+        let err = ParseError::new("Expected a number.", Span::new(14, 17));
+        assert_eq!(
+            err.render("buffer rivers abc"),
+            "ERROR! Expected a number.\n  buffer rivers abc\n                ^^^"
+        );
+    }
+
+    #[test]
+    fn render_at_the_end_of_input() {
+        let source = "buffer rivers";
+        let err = ParseError::new("Missing argument.", Span::end_of(source));
+        assert_eq!(
+            err.render(source),
+            "ERROR! Missing argument.\n  buffer rivers\n               ^"
+        );
+        assert_eq!(
+            ParseError::new("Expected a command.", Span::end_of("")).render(""),
+            "ERROR! Expected a command.\n  \n  ^"
+        );
+    }
+
+    #[test]
+    fn render_counts_characters_not_bytes() {
+        let err = ParseError::new("Unknown layer.", Span::new(8, 13));
+        assert_eq!(
+            err.render("inspect été"),
+            "ERROR! Unknown layer.\n  inspect été\n          ^^^"
+        );
+    }
+
+    #[test]
+    fn render_redacted_hides_secrets() {
+        let source = "db connect bob hunter2 host 99999";
+        let err = ParseError::new("Expected a port number.", Span::new(28, 33));
+        assert_eq!(
+            err.render_redacted(source, &[Span::new(15, 22)]),
+            "ERROR! Expected a port number.\n  db connect bob *** host 99999\n                          ^^^^^"
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use crate::command::{suggest, ArgKind, ArgValue, Arguments, Command, CommandSchema, Query};
+use crate::options::OptionValue;
+use crate::output::{ErrorCode, Output, OutputResult};
+use crate::parser::{tokenize, ParseError, Span};
+use crate::registry::{Context, Registry};
+use serde::{Deserialize, Serialize};
+
+/// A line of REPL input: a single command, or several chained with `|`, e.g.
+/// `buffer rivers 100 | intersect - us_states | inspect -`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pipeline {
+    pub source: String,
+    pub steps: Vec<Arguments>,
+}
+
+impl Pipeline {
+    /// The command of the first step.
+    pub fn command(&self) -> &str {
+        self.steps
+            .first()
+            .map(|step| step.command.as_str())
+            .unwrap_or_default()
+    }
+
+    /// The source with every secret argument, e.g. the password of `db connect`, replaced
+    /// by `***`, so it can be shown or stored.
+    pub fn redacted_source(&self, registry: &Registry) -> String {
+        // Replacing from the end keeps the earlier spans pointing at the right text
+        let mut source = self.source.clone();
+        for span in secret_spans(&self.source, registry).iter().rev() {
+            source.replace_range(span.start..span.end, "***");
+        }
+        source
+    }
+}
+
+/// Spans of the secret arguments in `source`, in order. They're found by their position
+/// alone, like the parser does, so they can be hidden even when the line doesn't parse.
+pub fn secret_spans(source: &str, registry: &Registry) -> Vec<Span> {
+    // An unterminated quote still hides what follows it
+    let tokens = match tokenize(source) {
+        Ok(tokens) => tokens,
+        Err(_) => tokenize(&format!("{}`", source)).unwrap_or_default(),
+    };
+
+    // Misspelt commands are taken to be the ones they're closest to, as that's what was meant
+    let mut spans = vec![];
+    for step in tokens.split(|token| token.is_pipe()) {
+        let mut words = step
+            .iter()
+            .take_while(|token| !token.is_separator())
+            .peekable();
+        let command = words.next().and_then(|name| {
+            registry.get(&name.text).or_else(|| {
+                suggest(
+                    &name.text,
+                    registry.commands().map(|command| command.name()),
+                )
+                .and_then(|name| registry.get(name))
+            })
+        });
+        let mut schema = match command {
+            Some(command) => command.schema(),
+            None => continue,
+        };
+        if !schema.subcommands.is_empty() {
+            let named = words.peek().and_then(|word| {
+                schema.subcommand(&word.text).or_else(|| {
+                    suggest(&word.text, schema.subcommands.iter().map(|sub| sub.name))
+                        .and_then(|name| schema.subcommand(name))
+                })
+            });
+            schema = match named {
+                Some(subcommand) => {
+                    words.next();
+                    subcommand
+                }
+                None => match schema.default_subcommand() {
+                    Some(default) => default,
+                    None => continue,
+                },
+            };
+        }
+        for (arg, word) in schema.args.iter().zip(words) {
+            if arg.kind == ArgKind::Secret {
+                spans.push(Span::new(word.span.start, word.span.end.min(source.len())));
+            }
+        }
+    }
+    spans
+}
+
+/// Splits `source` on `|` and parses each step against the registry.
+pub fn parse(source: &str, registry: &Registry) -> Result<Pipeline, ParseError> {
+    let mut bounds = vec![];
+    let mut start = 0;
+    for token in tokenize(source)?.iter().filter(|token| token.is_pipe()) {
+        bounds.push((start, token.span.start));
+        start = token.span.end;
+    }
+    bounds.push((start, source.len()));
+
+    // Each step is padded to its offset in `source`, so spans point into the whole line
+    let mut steps = vec![];
+    for (start, end) in bounds {
+        let mut args = registry.parse(&format!("{}{}", " ".repeat(start), &source[start..end]))?;
+        args.source = source[start..end].trim().to_string();
+        steps.push(args);
+    }
+
+    Ok(Pipeline {
+        source: source.to_string(),
+        steps,
+    })
+}
+
+/// Replaces every `-` layer argument with `input`, returning whether there were any.
+fn substitute(
+    args: &mut Arguments,
+    mut schema: &'static CommandSchema,
+    input: Option<&str>,
+) -> Result<bool, String> {
+    schema = match args.subcommand() {
+        Some(subcommand) => schema.subcommand(subcommand).unwrap_or(schema),
+        None => schema.default_subcommand().unwrap_or(schema),
+    };
+
+    let mut substituted = false;
+    for arg in schema.args.iter().filter(|arg| arg.kind == ArgKind::Layer) {
+        let value = match args.values.get_mut(arg.name) {
+            Some(val) if val.value == ArgValue::Text("-".to_string()) => val,
+            _ => continue,
+        };
+
+        match input {
+            Some(input) => value.value = ArgValue::Text(input.to_string()),
+            None => {
+                return Err(format!(
+                    "'-' refers to the output of the previous step, but '{}' is the first one.",
+                    args.command
+                ))
+            }
+        }
+        substituted = true;
+    }
+    Ok(substituted)
+}
+
+/// The name a step's output would get as a table if `-` were the layer the previous step
+/// is named after, rather than the `__step_<n>` CTE holding it.
+fn output_name(
+    command: &dyn Command,
+    args: &Arguments,
+    input_name: Option<&str>,
+) -> Option<String> {
+    let mut named = args.clone();
+    substitute(&mut named, command.schema(), input_name).ok()?;
+    match command.query(&named) {
+        Ok(Some(query)) => Some(query.name),
+        _ => None,
+    }
+}
+
+/// The `WITH` clause defining `ctes`, to prefix the query of the step that reads them.
+fn with_clause(ctes: &[Query]) -> Option<String> {
+    match ctes.is_empty() {
+        true => None,
+        false => {
+            let ctes = ctes
+                .iter()
+                .map(|cte| format!("{} AS ({})", cte.name, cte.sql))
+                .collect::<Vec<String>>();
+            Some(format!("WITH {} ", ctes.join(", ")))
+        }
+    }
+}
+
+/// Runs the steps of a pipeline in order, feeding each step's output to the next as `-`.
+///
+/// Intermediate steps run as CTEs of the final step's query, so only the final result is
+/// materialized. An intermediate step given `out=` is written to that table instead.
+/// CTEs are named `__step_<n>` so they can't shadow a table the query reads, but a
+/// materialized step without `out=` is still named after the layers it was given.
+pub async fn run(pipeline: Pipeline, ctx: &Context) -> Output {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
+
+    let mut ctes: Vec<Query> = vec![];
+    let mut input: Option<String> = None;
+    // What the output of the previous step would be called as a table
+    let mut input_name: Option<String> = None;
+    let last = pipeline.steps.len() - 1;
+
+    for (i, mut args) in pipeline.steps.into_iter().enumerate() {
+        let step = i + 1;
+        let command = match ctx.registry.get(&args.command) {
+            Some(val) => val,
+            None => {
+                output.error(
+                    ErrorCode::Parse,
+                    format!("ERROR! Step {}: Unknown command '{}'.", step, args.command),
+                );
+                return output;
+            }
+        };
+
+        let table_name = output_name(command, &args, input_name.as_deref());
+        let reads_input = match substitute(&mut args, command.schema(), input.as_deref()) {
+            Ok(val) => val,
+            Err(err) => {
+                output.error(ErrorCode::Parse, format!("ERROR! Step {}: {}", step, err));
+                return output;
+            }
+        };
+        if reads_input && !command.reads_pipelines() {
+            output.error(
+                ErrorCode::Parse,
+                format!(
+                    "ERROR! Step {}: Command '{}' can't read the output of a pipeline.",
+                    step, args.command
+                ),
+            );
+            return output;
+        }
+
+        if i < last && args.options.text("out").is_none() {
+            match command.query(&args) {
+                Err(err) => {
+                    output.error(ErrorCode::Args, format!("ERROR! Step {}: {}", step, err));
+                    return output;
+                }
+                Ok(Some(query)) => {
+                    let name = format!("__step_{}", step);
+                    input = Some(name.clone());
+                    input_name = table_name.or(Some(query.name));
+                    ctes.push(Query {
+                        name,
+                        sql: query.sql,
+                    });
+                    continue;
+                }
+                Ok(None) => {
+                    output.error(
+                        ErrorCode::Parse,
+                        format!(
+                            "ERROR! Step {}: '{}' can't pass its output on to the next step.",
+                            step, args.command
+                        ),
+                    );
+                    return output;
+                }
+            }
+        }
+
+        let takes_out = command
+            .schema()
+            .options
+            .iter()
+            .any(|spec| spec.name == "out");
+        if takes_out && !ctes.is_empty() && args.options.text("out").is_none() {
+            if let Some(name) = table_name {
+                args.options
+                    .insert("out", OptionValue::Text(format!("public.{}", name)));
+            }
+        }
+        args.ctes = with_clause(&ctes);
+        let command_name = args.command.value.clone();
+        let step_output = command
+            .execute(ctx, args)
+            .await
+            .unwrap_or_else(Output::from);
+        let failed = step_output.failed();
+
+        // A materialized step becomes a table, so the steps after it read from that instead
+        ctes.clear();
+        input = step_output
+            .results
+            .iter()
+            .rev()
+            .find_map(|result| match result {
+                OutputResult::Layer { schema, name } => Some(format!("{}.{}", schema, name)),
+                _ => None,
+            });
+        input_name = input.clone();
+
+        output.errors.extend(step_output.errors);
+        output.results.extend(step_output.results);
+
+        if failed {
+            output.error(
+                ErrorCode::Query,
+                format!(
+                    "ERROR! Pipeline stopped at step {} ('{}').",
+                    step, command_name
+                ),
+            );
+            return output;
+        }
+        if i < last && input.is_none() {
+            output.error(
+                ErrorCode::Parse,
+                format!(
+                    "ERROR! Step {}: Command '{}' didn't output a layer for the next step.",
+                    step, command_name
+                ),
+            );
+            return output;
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::{BufferCommand, IntersectCommand};
+
+    fn registry() -> Registry {
+        let mut registry = Registry::default();
+        registry.register(BufferCommand);
+        registry.register(IntersectCommand);
+        registry
+    }
+
+    #[test]
+    fn parse_chains_of_steps() {
+        let source = "buffer rivers 100 | buffer - 50 | intersect - us_states";
+        let pipeline = parse(source, &registry()).unwrap();
+        let commands = pipeline
+            .steps
+            .iter()
+            .map(|step| step.command.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(commands, vec!["buffer", "buffer", "intersect"]);
+        assert_eq!(pipeline.steps[1].source, "buffer - 50");
+        // Spans point into the whole line, not the step
+        assert_eq!(pipeline.steps[2].command.span, Span::new(34, 43));
+    }
+
+    #[test]
+    fn parse_errors_point_into_the_whole_line() {
+        let err = parse("buffer rivers 100 | buffer - far", &registry()).unwrap_err();
+        assert_eq!(err.span, Span::new(29, 32));
+    }
+
+    #[test]
+    fn substitute_replaces_dashes() {
+        let registry = registry();
+        let mut pipeline = parse("buffer rivers 1 | intersect - -", &registry).unwrap();
+        let schema = registry.get("intersect").unwrap().schema();
+        let args = &mut pipeline.steps[1];
+        assert_eq!(substitute(args, schema, Some("__step_1")), Ok(true));
+        assert_eq!(args.text("layer_1").unwrap(), "__step_1");
+        assert_eq!(args.text("layer_2").unwrap(), "__step_1");
+
+        let mut args = registry.parse("intersect rivers lakes").unwrap();
+        assert_eq!(substitute(&mut args, schema, Some("__step_1")), Ok(false));
+        assert_eq!(args.text("layer_1").unwrap(), "rivers");
+    }
+
+    #[test]
+    fn substitute_needs_a_previous_step() {
+        let registry = registry();
+        let mut args = registry.parse("buffer - 10").unwrap();
+        let schema = registry.get("buffer").unwrap().schema();
+        assert_eq!(
+            substitute(&mut args, schema, None),
+            Err(
+                "'-' refers to the output of the previous step, but 'buffer' is the first one."
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn outputs_are_named_after_the_layers() {
+        let registry = registry();
+        let buffer = registry.get("buffer").unwrap();
+        let intersect = registry.get("intersect").unwrap();
+
+        let args = registry.parse("buffer public.rivers 100").unwrap();
+        let first = output_name(buffer, &args, None);
+        assert_eq!(first.as_deref(), Some("rivers_buffer"));
+
+        let args = registry.parse("buffer - 50").unwrap();
+        let second = output_name(buffer, &args, first.as_deref());
+        assert_eq!(second.as_deref(), Some("rivers_buffer_buffer"));
+
+        let args = registry.parse("intersect - us_states").unwrap();
+        assert_eq!(
+            output_name(intersect, &args, second.as_deref()).as_deref(),
+            Some("rivers_buffer_buffer_us_states_intersect")
+        );
+    }
+
+    #[test]
+    fn with_clause_defines_every_step() {
+        assert_eq!(with_clause(&[]), None);
+        let ctes = [
+            Query {
+                name: "__step_1".to_string(),
+                sql: "SELECT 1".to_string(),
+            },
+            Query {
+                name: "__step_2".to_string(),
+                sql: "SELECT 2".to_string(),
+            },
+        ];
+        assert_eq!(
+            with_clause(&ctes).as_deref(),
+            Some("WITH __step_1 AS (SELECT 1), __step_2 AS (SELECT 2) ")
+        );
+    }
+}
@@ -0,0 +1,359 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A saved connection. Its password isn't kept with it but in the password file, where
+/// `psql` and other libpq clients find it too.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub username: String,
+    pub host: String,
+    pub port: String,
+    pub database: String,
+    /// Extra parameters of the connection string, e.g. `sslmode=require`.
+    #[serde(default)]
+    pub params: Option<String>,
+}
+
+/// The JSON file connection profiles are saved in, keyed by name.
+#[derive(Debug, Clone)]
+pub struct Profiles {
+    path: PathBuf,
+}
+
+impl Profiles {
+    /// `$TIGRE_PROFILES` if set, otherwise `tigre/profiles.json` in the user's config
+    /// directory.
+    pub fn default_location() -> Profiles {
+        let path = match std::env::var_os("TIGRE_PROFILES") {
+            Some(path) => PathBuf::from(path),
+            None => dirs::config_dir()
+                .map(|dir| dir.join("tigre"))
+                .unwrap_or(PathBuf::from("/tmp/tigre"))
+                .join("profiles.json"),
+        };
+        Profiles { path }
+    }
+
+    pub fn at(path: impl Into<PathBuf>) -> Profiles {
+        Profiles { path: path.into() }
+    }
+
+    /// Every saved profile, by name. A missing file has none.
+    pub fn list(&self) -> Result<BTreeMap<String, Profile>, String> {
+        match fs::read_to_string(&self.path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|err| format!("Couldn't read '{}': {}", self.path.display(), err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(err) => Err(format!("Couldn't read '{}': {}", self.path.display(), err)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Profile>, String> {
+        Ok(self.list()?.remove(name))
+    }
+
+    /// Saves `profile` as `name`, replacing any profile with the same name.
+    pub fn save(&self, name: &str, profile: Profile) -> Result<(), String> {
+        let mut profiles = self.list()?;
+        profiles.insert(name.to_string(), profile);
+        self.write(&profiles)
+    }
+
+    /// Deletes the profile `name`, returning whether there was one.
+    pub fn delete(&self, name: &str) -> Result<bool, String> {
+        let mut profiles = self.list()?;
+        let deleted = profiles.remove(name).is_some();
+        if deleted {
+            self.write(&profiles)?;
+        }
+        Ok(deleted)
+    }
+
+    fn write(&self, profiles: &BTreeMap<String, Profile>) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let json = serde_json::to_string_pretty(profiles).unwrap();
+        write_private(&self.path, &json)
+            .map_err(|err| format!("Couldn't write '{}': {}", self.path.display(), err))
+    }
+}
+
+/// Writes `contents` to `path`, readable only by its owner where the OS supports it. A new
+/// file is created that way, and an existing one is restricted before anything is written.
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents.as_bytes())
+}
+
+/// `$PGPASSFILE` if set, otherwise `~/.pgpass`, or `%APPDATA%\postgresql\pgpass.conf` on
+/// Windows, as libpq looks for it.
+fn pgpass_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("PGPASSFILE") {
+        return Some(PathBuf::from(path));
+    }
+    match cfg!(windows) {
+        true => dirs::config_dir().map(|dir| dir.join("postgresql").join("pgpass.conf")),
+        false => dirs::home_dir().map(|dir| dir.join(".pgpass")),
+    }
+}
+
+/// Splits a line of the password file on the colons that aren't escaped with `\`. Like
+/// libpq, it stops at the fourth, so the password is the rest of the line, colons and all.
+fn pgpass_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    fields.last_mut().unwrap().push(escaped);
+                }
+            }
+            ':' if fields.len() < 5 => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+fn pgpass_escape(field: &str) -> String {
+    field.replace('\\', "\\\\").replace(':', "\\:")
+}
+
+/// The password of a connection in the password file, from the first line matching it like
+/// libpq does, with `*` matching anything. libpq ignores a file others can read, and so does
+/// this.
+pub fn pgpass_password(host: &str, port: &str, database: &str, username: &str) -> Option<String> {
+    let path = pgpass_path()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if fs::metadata(&path).ok()?.permissions().mode() & 0o077 != 0 {
+            return None;
+        }
+    }
+
+    pgpass_lookup(
+        &fs::read_to_string(path).ok()?,
+        [host, port, database, username],
+    )
+}
+
+/// The password of `connection`, given as host, port, database and username, in the
+/// contents of a password file.
+fn pgpass_lookup(contents: &str, connection: [&str; 4]) -> Option<String> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(pgpass_fields)
+        .filter(|fields| fields.len() == 5)
+        .find(|fields| {
+            fields
+                .iter()
+                .zip(connection)
+                .all(|(field, value)| field == "*" || field == value)
+        })
+        .map(|mut fields| fields.remove(4))
+}
+
+/// Stores the password of a connection in the password file, replacing the line for the
+/// same connection, and makes the file readable only by its owner.
+pub fn save_pgpass_password(
+    host: &str,
+    port: &str,
+    database: &str,
+    username: &str,
+    password: &str,
+) -> Result<(), String> {
+    let path = match pgpass_path() {
+        Some(val) => val,
+        None => return Err("Couldn't find the home directory.".to_string()),
+    };
+
+    let connection = [host, port, database, username];
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    let mut lines = existing
+        .lines()
+        .filter(|line| {
+            let fields = pgpass_fields(line);
+            line.starts_with('#')
+                || fields.len() != 5
+                || !fields
+                    .iter()
+                    .zip(connection)
+                    .all(|(field, value)| field == value)
+        })
+        .map(str::to_string)
+        .collect::<Vec<String>>();
+    lines.push(
+        connection
+            .iter()
+            .chain([&password])
+            .map(|field| pgpass_escape(field))
+            .collect::<Vec<String>>()
+            .join(":"),
+    );
+
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    write_private(&path, &format!("{}\n", lines.join("\n")))
+        .map_err(|err| format!("Couldn't write '{}': {}", path.display(), err))
+}
+
+/// The files services are looked up in, in order: `$PGSERVICEFILE` or `~/.pg_service.conf`,
+/// then `pg_service.conf` in `$PGSYSCONFDIR`.
+fn service_files() -> Vec<PathBuf> {
+    let mut files = vec![];
+    match std::env::var_os("PGSERVICEFILE") {
+        Some(path) => files.push(PathBuf::from(path)),
+        None => files.extend(dirs::home_dir().map(|dir| dir.join(".pg_service.conf"))),
+    }
+    if let Some(dir) = std::env::var_os("PGSYSCONFDIR") {
+        files.push(PathBuf::from(dir).join("pg_service.conf"));
+    }
+    files
+}
+
+/// The parameters of the service `name`, e.g. `host`, `dbname` and `user`, from the first
+/// service file that defines it.
+pub fn service(name: &str) -> Option<BTreeMap<String, String>> {
+    for file in service_files() {
+        let contents = match fs::read_to_string(&file) {
+            Ok(val) => val,
+            Err(_) => continue,
+        };
+        if let Some(params) = service_params(&contents, name) {
+            return Some(params);
+        }
+    }
+    None
+}
+
+/// The parameters of the service `name` in the contents of a service file, if it has it.
+fn service_params(contents: &str, name: &str) -> Option<BTreeMap<String, String>> {
+    let mut params = None;
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            if params.is_some() {
+                break;
+            }
+            if line[1..line.len() - 1].trim() == name {
+                params = Some(BTreeMap::new());
+            }
+            continue;
+        }
+        if let (Some(params), Some((key, value))) = (params.as_mut(), line.split_once('=')) {
+            params.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONNECTION: [&str; 4] = ["db.example.com", "5432", "gis", "alice"];
+
+    #[test]
+    fn pgpass_fields_unescape_colons_and_backslashes() {
+        assert_eq!(
+            pgpass_fields(r"host\:1:5432:my\\db:alice:secret"),
+            vec!["host:1", "5432", r"my\db", "alice", "secret"]
+        );
+    }
+
+    #[test]
+    fn pgpass_passwords_can_hold_colons() {
+        assert_eq!(
+            pgpass_fields("h:5432:gis:alice:pa:ss:word"),
+            vec!["h", "5432", "gis", "alice", "pa:ss:word"]
+        );
+        assert_eq!(
+            pgpass_lookup("db.example.com:5432:gis:alice:a:b\\:c", CONNECTION).as_deref(),
+            Some("a:b:c")
+        );
+    }
+
+    #[test]
+    fn pgpass_escape_round_trips() {
+        let fields = ["h:1", r"C:\pg", "gis", "alice", r"p:a\ss"];
+        let line = fields
+            .iter()
+            .map(|field| pgpass_escape(field))
+            .collect::<Vec<String>>()
+            .join(":");
+        assert_eq!(pgpass_fields(&line), fields);
+    }
+
+    #[test]
+    fn pgpass_lookup_takes_the_first_match() {
+        let contents = "\
+# comment
+other.example.com:5432:gis:alice:wrong
+
+db.example.com:*:gis:*:first
+*:*:*:*:fallback
+";
+        assert_eq!(
+            pgpass_lookup(contents, CONNECTION).as_deref(),
+            Some("first")
+        );
+        assert_eq!(
+            pgpass_lookup(contents, ["localhost", "5433", "postgres", "bob"]).as_deref(),
+            Some("fallback")
+        );
+        assert_eq!(
+            pgpass_lookup("db.example.com:5432:gis:alice", CONNECTION),
+            None
+        );
+    }
+
+    #[test]
+    fn service_params_reads_its_section() {
+        let contents = "\
+# Services
+[staging]
+host=staging.example.com
+
+[gis]
+host = db.example.com
+port=5432
+ dbname = gis
+options=-c search_path=public
+[other]
+host=other.example.com
+";
+        let params = service_params(contents, "gis").unwrap();
+        assert_eq!(
+            params,
+            BTreeMap::from([
+                ("host".to_string(), "db.example.com".to_string()),
+                ("port".to_string(), "5432".to_string()),
+                ("dbname".to_string(), "gis".to_string()),
+                ("options".to_string(), "-c search_path=public".to_string()),
+            ])
+        );
+        assert_eq!(service_params(contents, "missing"), None);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::add::AddCommand;
+use crate::appstate::SharedState;
+use crate::command::{parse, Arguments, Command};
+use crate::db::DbCommand;
+use crate::help::HelpCommand;
+use crate::history::{History, HistoryCommand};
+use crate::hytigre::HytigreCommand;
+use crate::metadata::MetadataCommand;
+use crate::parser::ParseError;
+use crate::profiles::Profiles;
+use crate::repl::SaveCommand;
+use crate::script::RunCommand;
+use crate::symbology::SymbologyCommand;
+use crate::tools::{BufferCommand, InspectCommand, IntersectCommand};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// The commands known to the REPL, keyed by name.
+#[derive(Default)]
+pub struct Registry {
+    commands: BTreeMap<&'static str, Box<dyn Command>>,
+}
+
+impl Registry {
+    /// A registry with every command that ships with TIGRE.
+    pub fn builtin() -> Registry {
+        let mut registry = Registry::default();
+        registry.register(AddCommand);
+        registry.register(DbCommand);
+        registry.register(BufferCommand);
+        registry.register(IntersectCommand);
+        registry.register(InspectCommand);
+        registry.register(SymbologyCommand);
+        registry.register(MetadataCommand);
+        registry.register(HytigreCommand);
+        registry.register(RunCommand);
+        registry.register(SaveCommand);
+        registry.register(HelpCommand);
+        registry.register(HistoryCommand);
+        registry
+    }
+
+    /// Adds `command`, replacing any command already registered under the same name.
+    pub fn register(&mut self, command: impl Command + 'static) {
+        self.commands.insert(command.name(), Box::new(command));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Command> {
+        self.commands.get(name).map(|command| command.as_ref())
+    }
+
+    pub fn commands(&self) -> impl Iterator<Item = &dyn Command> {
+        self.commands.values().map(|command| command.as_ref())
+    }
+
+    pub fn parse(&self, source: &str) -> Result<Arguments, ParseError> {
+        parse(source, self)
+    }
+}
+
+/// Everything a command needs to run: the application state and the registry it was
+/// dispatched from.
+#[derive(Clone)]
+pub struct Context {
+    pub state: SharedState,
+    pub registry: Arc<Registry>,
+    pub history: History,
+    pub profiles: Profiles,
+    /// The scripts being run, outermost first, so a script can't end up running itself.
+    pub scripts: Vec<PathBuf>,
+}
+
+impl Context {
+    pub fn new(state: SharedState, registry: Registry) -> Context {
+        Context {
+            state,
+            registry: Arc::new(registry),
+            history: History::default_location(),
+            profiles: Profiles::default_location(),
+            scripts: vec![],
+        }
+    }
+}
@@ -1,128 +1,142 @@
-use crate::add::add;
-use crate::appstate::AppState;
-use crate::db::db;
-use crate::hytigre::hytigre;
-use crate::output::Output;
-use crate::symbology::symbology;
-use crate::tools::{buffer, inspect, intersect};
-use std::collections::HashMap;
-use std::string::String;
-use tauri::{Manager, State};
-use tokio::sync::Mutex;
+use crate::command::{ArgError, Arguments, Command, CommandSchema};
+use crate::db::PGConnection;
+use crate::history::Entry;
+use crate::output::{ErrorCode, Output};
+use crate::pipeline::{self, Pipeline};
+use crate::registry::{Context, Registry};
+use futures::future::{BoxFuture, FutureExt};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-#[tauri::command]
-pub fn read(cmd: &str) -> HashMap<&str, Vec<String>> {
-    let mut ast: HashMap<&str, Vec<String>> = HashMap::new();
-    let mut tokens: Vec<String> = Vec::new();
-    let mut current_token = String::new();
-    let mut in_quotes = false;
+/// Parses a line of REPL input, rendering any error with the offending input underlined and
+/// any secret in it hidden.
+pub fn read(cmd: &str, registry: &Registry) -> Result<Pipeline, String> {
+    pipeline::parse(cmd, registry)
+        .map_err(|err| err.render_redacted(cmd, &pipeline::secret_spans(cmd, registry)))
+}
 
-    for c in cmd.chars() {
-        match c {
-            '`' => {
-                if in_quotes {
-                    tokens.push(current_token.clone());
-                    current_token.clear();
-                }
-                in_quotes = !in_quotes;
-            }
-            ' ' if !in_quotes => {
-                if !current_token.is_empty() {
-                    tokens.push(current_token.clone());
-                    current_token.clear();
-                }
-            }
-            _ => current_token.push(c),
+/// Evaluates a line of REPL input, running every step if it is a pipeline.
+pub async fn eval(mut pipeline: Pipeline, ctx: &Context) -> Output {
+    match pipeline.steps.len() {
+        0 => {
+            let mut output = Output::default();
+            output.error(ErrorCode::Parse, "ERROR! The pipeline has no steps.");
+            output
         }
+        1 => eval_command(pipeline.steps.remove(0), ctx).await,
+        _ => pipeline::run(pipeline, ctx).await,
     }
-    if !current_token.is_empty() {
-        tokens.push(current_token);
+}
+
+async fn eval_command(args: Arguments, ctx: &Context) -> Output {
+    match ctx.registry.get(&args.command) {
+        Some(command) => command
+            .execute(ctx, args)
+            .await
+            .unwrap_or_else(Output::from),
+        None => {
+            let mut output = Output::default();
+            output.error(
+                ErrorCode::Parse,
+                format!("ERROR! Unknown command '{}'.", args.command),
+            );
+            output
+        }
     }
+}
 
-    // Insert command into ast
-    ast.insert("cmd", vec![tokens[0].clone()]);
+/// Evaluates a command entered by the user and records it in the history. `history`
+/// commands aren't recorded themselves, so entry numbers don't shift while browsing.
+pub async fn eval_and_record(pipeline: Pipeline, ctx: &Context) -> Output {
+    if pipeline.command() == "history" {
+        return eval(pipeline, ctx).await;
+    }
 
-    // Collect required arguments
-    let mut optional_args_index = 0;
-    let mut args: Vec<String> = vec![];
-    for i in 1..tokens.len() {
-        if tokens[i].is_empty() {
-            continue;
-        }
+    // Secrets, e.g. the password of `db connect`, are recorded as `***`
+    let command = pipeline.redacted_source(&ctx.registry);
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as i64)
+        .unwrap_or_default();
+    let timer = Instant::now();
 
-        if tokens[i] == "?" {
-            optional_args_index = i;
-            break;
+    let mut output = eval(pipeline, ctx).await;
+
+    let connection = {
+        let state = ctx.state.lock().await;
+        match state.pgsql_connection == PGConnection::default() {
+            true => None,
+            false => Some(state.pgsql_connection.redacted_string()),
         }
-        args.push(tokens[i].clone());
+    };
+
+    let entry = Entry {
+        command,
+        started_at,
+        duration_ms: timer.elapsed().as_millis() as i64,
+        connection,
+        output: output.clone(),
+    };
+    if let Err(err) = ctx.history.record(&entry) {
+        output.warning(
+            ErrorCode::Io,
+            format!("WARNING! Couldn't save the command to the history: {}", err),
+        );
     }
-    ast.insert("args", args);
 
-    // Collect optional arguments
-    let mut optional_args: Vec<String> = vec![];
-    for i in optional_args_index..tokens.len() {
-        if tokens[i] == "?" {
-            continue;
+    output
+}
+
+/// Parses and evaluates a single line of REPL input.
+pub async fn run_line(cmd: &str, ctx: &Context) -> Output {
+    match read(cmd, &ctx.registry) {
+        Ok(pipeline) => eval(pipeline, ctx).await,
+        Err(err) => {
+            let mut output = Output::default();
+            output.error(ErrorCode::Parse, err);
+            output
         }
-        optional_args.push(tokens[i].clone());
     }
-    ast.insert("optional_args", optional_args);
+}
 
-    ast
+/// Like [`run_line`], but records the command in the history.
+pub async fn run_line_and_record(cmd: &str, ctx: &Context) -> Output {
+    match read(cmd, &ctx.registry) {
+        Ok(pipeline) => eval_and_record(pipeline, ctx).await,
+        Err(err) => {
+            let mut output = Output::default();
+            output.error(ErrorCode::Parse, err);
+            output
+        }
+    }
 }
 
-#[tauri::command]
-pub async fn eval(ast: HashMap<&str, Vec<&str>>, app: tauri::AppHandle) -> Result<String, ()> {
-    let state: State<'_, Mutex<AppState>> = app.app_handle().state();
-    let mut output = Output {
-        errors: vec![],
-        results: vec![],
-    };
+static SAVE_SCHEMA: CommandSchema = CommandSchema {
+    name: "save",
+    summary: "Save the current project.",
+    args: &[],
+    options: &[],
+    subcommands: &[],
+    examples: &["save"],
+};
 
-    match ast["cmd"][0] {
-        "add" => {
-            let add_output = add(&ast, &state).await.unwrap();
-            output.errors.extend(add_output.errors);
-            output.results.extend(add_output.results);
-        }
-        "db" => {
-            let db_output = db(&ast, &state).await.unwrap();
-            output.errors.extend(db_output.errors);
-            output.results.extend(db_output.results);
-        }
-        "buffer" => {
-            let buffer_output = buffer(&ast, &state).await.unwrap();
-            output.errors.extend(buffer_output.errors);
-            output.results.extend(buffer_output.results);
-        }
-        "intersect" => {
-            let intersect_output = intersect(&ast, &state).await.unwrap();
-            output.errors.extend(intersect_output.errors);
-            output.results.extend(intersect_output.results);
-        }
-        "inspect" => {
-            let inspect_output = inspect(&ast, &state).await.unwrap();
-            output.errors.extend(inspect_output.errors);
-            output.results.extend(inspect_output.results);
-        },
-        "symbology" => {
-            let symbology_output = symbology(&ast, &state).await.unwrap();
-            output.errors.extend(symbology_output.errors);
-            output.results.extend(symbology_output.results);
-        },
-        "hytigre" => {
-            let hytigre_output = hytigre(&ast, &state).await.unwrap();
-            output.errors.extend(hytigre_output.errors);
-            output.results.extend(hytigre_output.results);
-        }
-        "save" => println!("save"),
-        &_ => {
-            output.errors.push("ERROR! Unknown command.".to_string());
-        }
+pub struct SaveCommand;
+
+impl Command for SaveCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &SAVE_SCHEMA
     }
 
-    Ok(format!(
-        "{{ \"errors\": {:?}, \"results\": {:?} }}",
-        output.errors, output.results
-    ))
+    fn execute<'a>(
+        &'a self,
+        _ctx: &'a Context,
+        _args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            // Projects are kept in the database as they're edited, so there's nothing to write
+            let mut output = Output::default();
+            output.message("Saved.");
+            Ok(output)
+        }
+        .boxed()
+    }
 }
@@ -0,0 +1,397 @@
+use crate::command::{ArgError, ArgKind, ArgSpec, Arguments, Command, CommandSchema};
+use crate::output::{ErrorCode, Output, Severity};
+use crate::registry::Context;
+use crate::repl::run_line;
+use futures::future::{BoxFuture, FutureExt};
+use std::collections::HashMap;
+use std::fs;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OnError {
+    Stop,
+    Continue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Command {
+        line: usize,
+        text: String,
+    },
+    Let {
+        line: usize,
+        name: String,
+        value: String,
+    },
+    OnError {
+        policy: OnError,
+    },
+    For {
+        line: usize,
+        variable: String,
+        pattern: String,
+        body: Vec<Statement>,
+    },
+}
+
+struct Script {
+    path: String,
+    variables: HashMap<String, String>,
+    on_error: OnError,
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Strips one pair of matching quotes (", ' or `) from around `text`.
+fn unquote(text: &str) -> &str {
+    for quote in ['"', '\'', '`'] {
+        if text.len() >= 2 && text.starts_with(quote) && text.ends_with(quote) {
+            return &text[1..text.len() - 1];
+        }
+    }
+    text
+}
+
+/// Builds the statement tree of a script. `lines` yields (line number, text) pairs.
+fn parse_block<'a>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    opened_at: Option<usize>,
+) -> Result<Vec<Statement>, String> {
+    let mut statements: Vec<Statement> = vec![];
+
+    while let Some((line, text)) = lines.next() {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let rest = rest.trim();
+
+        match keyword {
+            "end" if rest.is_empty() => match opened_at {
+                Some(_) => return Ok(statements),
+                None => return Err(format!("Line {}: 'end' without a matching 'for'.", line)),
+            },
+            "let" => {
+                let (name, value) = match rest.split_once('=') {
+                    Some((name, value)) if is_identifier(name.trim()) => {
+                        (name.trim(), value.trim())
+                    }
+                    _ => return Err(format!("Line {}: Expected 'let <name> = <value>'.", line)),
+                };
+                statements.push(Statement::Let {
+                    line,
+                    name: name.to_string(),
+                    value: unquote(value).to_string(),
+                });
+            }
+            "on_error" => {
+                let policy = match rest {
+                    "stop" => OnError::Stop,
+                    "continue" => OnError::Continue,
+                    _ => {
+                        return Err(format!(
+                            "Line {}: Expected 'on_error stop' or 'on_error continue'.",
+                            line
+                        ))
+                    }
+                };
+                statements.push(Statement::OnError { policy });
+            }
+            "for" => {
+                let syntax_error =
+                    format!("Line {}: Expected 'for <name> in glob(<pattern>)'.", line);
+                let (variable, source) = match rest.split_once(" in ") {
+                    Some((variable, source)) if is_identifier(variable.trim()) => {
+                        (variable.trim(), source.trim())
+                    }
+                    _ => return Err(syntax_error),
+                };
+                let pattern = match source
+                    .strip_prefix("glob(")
+                    .and_then(|s| s.strip_suffix(')'))
+                {
+                    Some(pattern) => unquote(pattern.trim()),
+                    None => return Err(syntax_error),
+                };
+
+                statements.push(Statement::For {
+                    line,
+                    variable: variable.to_string(),
+                    pattern: pattern.to_string(),
+                    body: parse_block(lines, Some(line))?,
+                });
+            }
+            _ => statements.push(Statement::Command {
+                line,
+                text: text.to_string(),
+            }),
+        }
+    }
+
+    match opened_at {
+        Some(line) => Err(format!("Line {}: 'for' is missing a matching 'end'.", line)),
+        None => Ok(statements),
+    }
+}
+
+impl Script {
+    /// Replaces `$name` and `${name}` with the value of the variable. `$$` is a literal `$`.
+    fn substitute(&self, text: &str) -> Result<String, String> {
+        let mut result = String::new();
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                result.push(c);
+                continue;
+            }
+
+            let name = match chars.peek() {
+                Some('$') => {
+                    chars.next();
+                    result.push('$');
+                    continue;
+                }
+                Some('{') => {
+                    chars.next();
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err("Unterminated '${'.".to_string()),
+                        }
+                    }
+                    name
+                }
+                _ => {
+                    let mut name = String::new();
+                    while let Some(c) = chars
+                        .peek()
+                        .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+                    {
+                        name.push(*c);
+                        chars.next();
+                    }
+                    name
+                }
+            };
+
+            match self.variables.get(&name) {
+                Some(value) => result.push_str(value),
+                None if name.is_empty() => result.push('$'),
+                None => return Err(format!("Unknown variable '${}'.", name)),
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Records a script error, returning true if the script should keep going.
+    fn fail(&self, line: usize, message: String, output: &mut Output) -> bool {
+        output.error(
+            ErrorCode::Script,
+            format!("{}:{}: {}", self.path, line, message),
+        );
+        self.on_error == OnError::Continue
+    }
+
+    /// Runs `statements`, returning false once the script should stop.
+    fn execute<'a>(
+        &'a mut self,
+        statements: &'a [Statement],
+        ctx: &'a Context,
+        output: &'a mut Output,
+    ) -> BoxFuture<'a, bool> {
+        async move {
+            for statement in statements {
+                match statement {
+                    Statement::Command { line, text } => {
+                        let cmd = match self.substitute(text) {
+                            Ok(val) => val,
+                            Err(err) => match self.fail(*line, format!("ERROR! {}", err), output) {
+                                true => continue,
+                                false => return false,
+                            },
+                        };
+
+                        let cmd_output = run_line(&cmd, ctx).await;
+                        output.results.extend(cmd_output.results);
+                        for mut diagnostic in cmd_output.errors {
+                            diagnostic.message =
+                                format!("{}:{}: {}", self.path, line, diagnostic.message);
+                            let failed = diagnostic.severity == Severity::Error;
+                            output.errors.push(diagnostic);
+                            if failed && self.on_error == OnError::Stop {
+                                return false;
+                            }
+                        }
+                    }
+                    Statement::Let { line, name, value } => match self.substitute(value) {
+                        Ok(value) => {
+                            self.variables.insert(name.clone(), value);
+                        }
+                        Err(err) => {
+                            if !self.fail(*line, format!("ERROR! {}", err), output) {
+                                return false;
+                            }
+                        }
+                    },
+                    Statement::OnError { policy } => self.on_error = *policy,
+                    Statement::For {
+                        line,
+                        variable,
+                        pattern,
+                        body,
+                    } => {
+                        let paths = match self
+                            .substitute(pattern)
+                            .and_then(|pattern| glob::glob(&pattern).map_err(|err| err.to_string()))
+                        {
+                            Ok(paths) => paths,
+                            Err(err) => match self.fail(
+                                *line,
+                                format!("ERROR! Invalid glob pattern: {}", err),
+                                output,
+                            ) {
+                                true => continue,
+                                false => return false,
+                            },
+                        };
+
+                        for path in paths.flatten() {
+                            self.variables
+                                .insert(variable.clone(), path.to_string_lossy().to_string());
+                            if !self.execute(body, ctx, output).await {
+                                return false;
+                            }
+                        }
+                    }
+                }
+            }
+
+            true
+        }
+        .boxed()
+    }
+}
+
+/// Runs a `.tigre` script. Scripts may contain:
+///
+/// ```text
+/// # comments
+/// let name = value
+/// on_error stop|continue
+/// for f in glob(test_data/*.shp)
+///     add layer `$f`
+/// end
+/// ```
+pub async fn run_script(path: &str, ctx: &Context) -> Output {
+    let mut output = Output {
+        errors: vec![],
+        results: vec![],
+    };
+
+    let source = match fs::read_to_string(path) {
+        Ok(val) => val,
+        Err(err) => {
+            output.error(
+                ErrorCode::Io,
+                format!("ERROR! Couldn't read script '{}': {}", path, err),
+            );
+            return output;
+        }
+    };
+
+    // A script that runs itself, directly or through others, would never finish
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.into());
+    if ctx.scripts.contains(&canonical) {
+        let chain = ctx
+            .scripts
+            .iter()
+            .chain([&canonical])
+            .map(|script| script.display().to_string())
+            .collect::<Vec<String>>();
+        output.error(
+            ErrorCode::Script,
+            format!(
+                "ERROR! Script '{}' runs itself: {}.",
+                path,
+                chain.join(" -> ")
+            ),
+        );
+        return output;
+    }
+    let mut scripts = ctx.scripts.clone();
+    scripts.push(canonical);
+    let ctx = &Context {
+        scripts,
+        ..ctx.clone()
+    };
+
+    let statements = match parse_block(
+        &mut source.lines().enumerate().map(|(i, text)| (i + 1, text)),
+        None,
+    ) {
+        Ok(val) => val,
+        Err(err) => {
+            output.error(ErrorCode::Script, format!("ERROR! {}: {}", path, err));
+            return output;
+        }
+    };
+
+    let mut script = Script {
+        path: path.to_string(),
+        variables: HashMap::new(),
+        on_error: OnError::Stop,
+    };
+
+    if !script.execute(&statements, ctx, &mut output).await {
+        output.error(
+            ErrorCode::Script,
+            format!("ERROR! Script '{}' stopped after an error.", path),
+        );
+    }
+
+    output
+}
+
+static RUN_SCHEMA: CommandSchema = CommandSchema {
+    name: "run",
+    summary: "Run the commands in a .tigre script.",
+    args: &[ArgSpec {
+        name: "path",
+        kind: ArgKind::Path,
+        required: true,
+        description: "Path to the script.",
+    }],
+    options: &[],
+    subcommands: &[],
+    examples: &["run `scripts/load_rivers.tigre`"],
+};
+
+pub struct RunCommand;
+
+impl Command for RunCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &RUN_SCHEMA
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move { Ok(run_script(args.text("path")?, ctx).await) }.boxed()
+    }
+}
@@ -1,95 +1,120 @@
 use crate::appstate::AppState;
-use crate::output::Output;
-use postgres::{Client, NoTls};
-use std::collections::HashMap;
-use tauri::{State, Emitter};
+use crate::command::{ArgError, ArgKind, ArgSpec, Arguments, Command, CommandSchema};
+use crate::output::{ErrorCode, Output};
+use crate::registry::Context;
+use futures::future::{BoxFuture, FutureExt};
 use tokio::sync::Mutex;
 
 async fn set_symbology(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
+    layer: &str,
+    symbology_json: &serde_json::Value,
+    state: &Mutex<AppState>,
 ) -> Result<Output, ()> {
     let mut output = Output {
         errors: vec![],
         results: vec![],
     };
 
-    let state = state.lock().await;
-    let _ = state.app_handle.emit("loading", 25);
-
-    if ast["args"].len() < 3 {
-        output
-            .errors
-            .push("ERROR! You must provide a layer and JSON to set symbology.".to_string());
-        
-        let _ = state.app_handle.emit("loading", 0);
-        return Ok(output);
-    }
-
-    let layer = ast["args"][1];
-    let symbology_json = ast["args"][2];
+    let (events, database) = {
+        let state = state.lock().await;
+        (state.events.clone(), state.database())
+    };
+    events.emit("loading", 25);
 
-    let mut client = match Client::connect(&state.pgsql_connection.pg_string(), NoTls) {
+    let client = match database.client().await {
         Ok(client) => client,
         Err(_) => { 
-            let _ = state.app_handle.emit("loading", 0);
-            output
-                .errors
-                .push("ERROR! You must connect to a database before setting the symbology of a layer.".to_string());
+            events.emit("loading", 0);
+            output.error(
+                ErrorCode::NotConnected,
+                "ERROR! You must connect to a database before setting the symbology of a layer.",
+            );
             return Ok(output);
         }
     };
 
-    let _ = state.app_handle.emit("loading", 90);
+    events.emit("loading", 90);
     match client.execute(
-        format!("COMMENT ON TABLE {} IS '{}'", layer, symbology_json).as_str(),
+        format!(
+            "COMMENT ON TABLE {} IS '{}'",
+            layer,
+            symbology_json.to_string().replace('\'', "''")
+        )
+        .as_str(),
         &[],
-    ) {
+    ).await {
         Ok(_) => {
-            output.results.push("Done.".to_string());
+            output.message("Done.");
         }
         Err(_) => {
-            output
-                .errors
-                .push("ERROR! Failed to set symbology.".to_string());
+            output.error(ErrorCode::Query, "ERROR! Failed to set symbology.");
         }
     };
 
     let schema_and_name  = layer.split(".").collect::<Vec<&str>>();
     if schema_and_name.len() == 2 {
-        let _ = state.app_handle.emit("add-vector-layer", [schema_and_name[1].to_string(), schema_and_name[0].to_string()]);
+        events.emit("add-vector-layer", [schema_and_name[1].to_string(), schema_and_name[0].to_string()]);
     } else {
-        let _ = state.app_handle.emit("add-raster-layer", [schema_and_name[0].to_string()]);
+        events.emit("add-raster-layer", [schema_and_name[0].to_string()]);
     }
 
-    let _ = state.app_handle.emit("loading", 0);
+    events.emit("loading", 0);
     Ok(output)
 }
 
-pub async fn symbology(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
-) -> Result<Output, ()> {
-    let mut output = Output {
-        errors: vec![],
-        results: vec![],
-    };
+static SYMBOLOGY_SCHEMA: CommandSchema = CommandSchema {
+    name: "symbology",
+    summary: "Style layers on the map.",
+    args: &[],
+    options: &[],
+    subcommands: &[CommandSchema {
+        name: "set",
+        summary: "Store the symbology of a layer as a comment on its table.",
+        args: &[
+            ArgSpec {
+                name: "layer",
+                kind: ArgKind::Layer,
+                required: true,
+                description: "Layer to style.",
+            },
+            ArgSpec {
+                name: "symbology",
+                kind: ArgKind::Json,
+                required: true,
+                description: "Symbology as JSON.",
+            },
+        ],
+        options: &[],
+        subcommands: &[],
+        examples: &["symbology set public.rivers `{\"stroke\": \"#0000ff\"}`"],
+    }],
+    examples: &[],
+};
 
-    if ast["args"].is_empty() {
-        output
-            .errors
-            .push("ERROR! No arguments provided for command 'symbology'.".to_string())
-    } else {
-        match ast["args"][0] {
-            "set" => {
-                let set_symbology_output = set_symbology(ast, state).await.unwrap();
-                output.errors.extend(set_symbology_output.errors);
-                output.results.extend(set_symbology_output.results);
+pub struct SymbologyCommand;
+
+impl Command for SymbologyCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &SYMBOLOGY_SCHEMA
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            match args.subcommand() {
+                Some("set") => Ok(set_symbology(
+                    args.text("layer")?,
+                    args.json("symbology")?,
+                    &ctx.state,
+                )
+                .await
+                .unwrap()),
+                _ => Err(args.unknown_subcommand()),
             }
-            &_ => output
-                .errors
-                .push("ERROR! Found unknown argument.".to_string()),
         }
+        .boxed()
     }
-    Ok(output)
 }
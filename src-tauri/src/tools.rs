@@ -1,172 +1,467 @@
 use crate::appstate::AppState;
-use crate::output::Output;
+use crate::command::{
+    ArgError, ArgKind, ArgSpec, Arguments, Command, CommandSchema, Location, Query,
+};
+use crate::options::{OptionKind, OptionSpec, Options};
+use crate::output::{ErrorCode, Output, OutputResult};
 use crate::db::{inspect_layer, inspect_layer_at_location};
-use postgres::{Client, NoTls};
-use std::collections::HashMap;
-use tauri::{Emitter, State};
+use crate::registry::Context;
+use futures::future::{BoxFuture, FutureExt};
 use tokio::sync::Mutex;
 
+/// Splits `schema.table` into its schema and table, defaulting to the public schema.
+pub fn split_table_name(table: &str) -> (String, String) {
+    match table.split_once(".") {
+        Some((schema, name)) => (schema.to_string(), name.to_string()),
+        None => ("public".to_string(), table.to_string()),
+    }
+}
+
+/// Prefixes `create` with a `DROP TABLE` of `table` when `overwrite` is set.
+fn create_table_query(table: &str, create: String, overwrite: bool) -> String {
+    match overwrite {
+        true => format!("DROP TABLE IF EXISTS {}; {}", table, create),
+        false => create,
+    }
+}
+
+/// The table name without its schema.
+fn short_table_name(table: &str) -> &str {
+    match table.split_once(".") {
+        Some((_, name)) => name,
+        None => table,
+    }
+}
+
+fn buffer_name(layer: &str) -> String {
+    format!("{}_buffer", short_table_name(layer))
+}
+
+fn buffer_query(layer: &str, buffer_size: f64, quad_segs: i64) -> String {
+    format!(
+        "SELECT ST_Buffer(geom, {}, 'quad_segs={}') AS geom FROM {}",
+        buffer_size, quad_segs, layer
+    )
+}
+
+fn intersect_name(layer_1: &str, layer_2: &str) -> String {
+    format!("{}_{}_intersect", short_table_name(layer_1), short_table_name(layer_2))
+}
+
+fn intersect_query(layer_1: &str, layer_2: &str) -> String {
+    format!(
+        "SELECT ST_Intersection(a.geom, b.geom) AS geom FROM {} AS a, {} AS b",
+        layer_1, layer_2
+    )
+}
+
+/// Turns the `json_agg` row returned by an inspect query into a table result.
+fn inspect_table(layer: &str, json: &str) -> OutputResult {
+    let rows = serde_json::from_str::<serde_json::Value>(json)
+        .ok()
+        .and_then(|row| row.get("json_agg").cloned())
+        .unwrap_or_default();
+    OutputResult::table_from_json(layer, &rows)
+}
+
+/// The `inspect` query result as JSON, so the frontend gets it without a second decode.
+fn table_json(json: &str) -> serde_json::Value {
+    serde_json::from_str(json).unwrap_or_default()
+}
+
+/// `ctes` is a `WITH` clause defining the layer, when it's the output of earlier steps of a
+/// pipeline rather than a table.
 pub async fn inspect(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
+    layer: &str,
+    location: Option<&Location>,
+    ctes: Option<&str>,
+    state: &Mutex<AppState>,
 ) -> Result<Output, ()> {
     let mut output = Output {
         errors: vec![],
         results: vec![],
     };
 
-    let state = state.lock().await;
+    let (events, database) = {
+        let state = state.lock().await;
+        (state.events.clone(), state.database())
+    };
+
+    events.emit("loading", 25);
 
-    if ast["args"].is_empty() {
-        output
-            .errors
-            .push("ERROR! No arguments provided for command 'inspect'.".to_string())
+    events.emit("loading", 70);
+    if let Some(location) = location {
+        let result = inspect_layer_at_location(layer, &database, location, ctes).await;
+        match result {
+            Ok(val) => {
+                events.emit("loading", 90);
+                events.emit("open-table", (layer, table_json(&val)));
+                output.message("Done.");
+                output.results.push(inspect_table(layer, &val));
+            },
+            Err(err) => { 
+                output.error(ErrorCode::Query, format!("ERROR! Couldn't inspect layer: {}", err));
+            }
+        };
     } else {
-        let _ = state.app_handle.emit("loading", 25);
-        let layer = ast["args"][0];
-
-        let _ = state.app_handle.emit("loading", 70);
-        if ast["args"].len() == 2 {
-            let location = ast["args"][1];
-
-            let result = inspect_layer_at_location(layer, &state.pgsql_connection, location).await;
-            match result {
-                Ok(val) => {
-                    let _ = state.app_handle.emit("loading", 90);
-                    let _ = state.app_handle.emit("open-table", [layer, format!("{:?}", val).as_str()]);
-                    output.results.push("Done.".to_string());
-                },
-                Err(err) => { 
-                    output.errors.push(format!("ERROR! Couldn't inspect layer: {}", err));
-                }
-            };
-        } else {
-            let result = inspect_layer(&layer, &state.pgsql_connection).await;
-            match result {
-                Ok(val) => {
-                    let _ = state.app_handle.emit("loading", 90);
-                    let _ = state.app_handle.emit("open-table", [layer, format!("{:?}", val).as_str()]);
-                    output.results.push("Done.".to_string());
-                },
-                Err(err) => { 
-                    output.errors.push(format!("ERROR! Couldn't inspect layer: {}", err));
-                }
-            };
-        }
+        let result = inspect_layer(layer, &database, ctes).await;
+        match result {
+            Ok(val) => {
+                events.emit("loading", 90);
+                events.emit("open-table", (layer, table_json(&val)));
+                output.message("Done.");
+                output.results.push(inspect_table(layer, &val));
+            },
+            Err(err) => { 
+                output.error(ErrorCode::Query, format!("ERROR! Couldn't inspect layer: {}", err));
+            }
+        };
     }
 
-    let _ = state.app_handle.emit("loading", 0);
+    events.emit("loading", 0);
     Ok(output)
 }
 
 pub async fn buffer(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
+    layer: &str,
+    buffer_size: f64,
+    options: &Options,
+    ctes: Option<&str>,
+    state: &Mutex<AppState>,
 ) -> Result<Output, ()> {
     let mut output = Output {
         errors: vec![],
         results: vec![],
     };
 
-    if ast["args"].is_empty() {
-        output
-            .errors
-            .push("ERROR! No arguments provided for command 'buffer'.".to_string())
-    } else if ast["args"].len() != 2 {
-        output
-            .errors
-            .push("ERROR! Not enough arguments provided for command 'buffer'.".to_string())
-    } else {
+    let (events, database) = {
         let state = state.lock().await;
-        let _ = state.app_handle.emit("loading", 25);
-        let layer = ast["args"][0];
-
-        let layer_split = layer.split(".").collect::<Vec<&str>>();
-        let short_layer = match layer_split.len() {
-            2 => layer_split[1],
-            _ => layer_split[0],
-        };
-        let buffer_size = ast["args"][1];
+        (state.events.clone(), state.database())
+    };
+    events.emit("loading", 25);
 
-        let mut pgsql_client = match Client::connect(&state.pgsql_connection.pg_string(), NoTls) {
-            Ok(val) => val,
-            Err(_) => panic!("ERROR! Lost connection to the database."),
-        };
+    let pgsql_client = match database.client().await {
+        Ok(val) => val,
+        Err(err) => {
+            events.emit("loading", 0);
+            output.error(ErrorCode::Connection, format!("ERROR! {}", err));
+            return Ok(output);
+        }
+    };
 
-        let _ = state.app_handle.emit("loading", 70);
-        match pgsql_client.execute(
-            format!("CREATE TABLE IF NOT EXISTS public.{}_buffer AS SELECT ST_Buffer(geom, {}) AS geom FROM {}", short_layer, buffer_size, layer).as_str(),
-            &[]
-        ) {
-            Ok(_) => {
-                let _ = state.app_handle.emit("loading", 90);
-                let _ = state.app_handle.emit("add-vector-layer", [format!("{}_buffer", short_layer), "public".to_string()]);
-                output.results.push("Done.".to_string());
-            },
-            Err(err) => output.errors.push(format!("ERROR! Couldn't create buffer: {}", err))
-        };
+    let (out_schema, out_name) = match options.text("out") {
+        Some(out) => split_table_name(out),
+        None => ("public".to_string(), buffer_name(layer)),
+    };
+    let quad_segs = options.integer("quad_segs").unwrap_or(8);
 
-        let _ = state.app_handle.emit("loading", 0);
-    }
+    events.emit("loading", 70);
+    match pgsql_client.batch_execute(
+        create_table_query(
+            format!("{}.{}", out_schema, out_name).as_str(),
+            format!(
+                "CREATE TABLE {}.{} AS {}{}",
+                out_schema,
+                out_name,
+                ctes.unwrap_or_default(),
+                buffer_query(layer, buffer_size, quad_segs)
+            ),
+            options.boolean("overwrite"),
+        )
+        .as_str(),
+    ).await {
+        Ok(_) => {
+            events.emit("loading", 90);
+            events.emit("add-vector-layer", [&out_name, &out_schema]);
+            output.message("Done.");
+            output.results.push(OutputResult::Layer {
+                schema: out_schema,
+                name: out_name,
+            });
+        },
+        Err(err) => output.error(ErrorCode::Query, format!("ERROR! Couldn't create buffer: {}", err))
+    };
 
+    events.emit("loading", 0);
     Ok(output)
 }
 
 pub async fn intersect(
-    ast: &HashMap<&str, Vec<&str>>,
-    state: &State<'_, Mutex<AppState>>,
+    layer_1: &str,
+    layer_2: &str,
+    options: &Options,
+    ctes: Option<&str>,
+    state: &Mutex<AppState>,
 ) -> Result<Output, ()> {
     let mut output = Output {
         errors: vec![],
         results: vec![],
     };
 
-    if ast["args"].is_empty() {
-        output
-            .errors
-            .push("ERROR! No arguments provided for command 'intersect'.".to_string())
-    } else if ast["args"].len() != 2 {
-        output
-            .errors
-            .push("ERROR! Not enough arguments provided for command 'intersect'.".to_string())
-    } else {
+    let (events, database) = {
         let state = state.lock().await;
-        let _ = state.app_handle.emit("loading", 25);
+        (state.events.clone(), state.database())
+    };
+    events.emit("loading", 25);
 
-        let layer_1 = ast["args"][0];
-        let layer_1_split = layer_1.split(".").collect::<Vec<&str>>();
-        let short_layer_1 = match layer_1_split.len() {
-            2 => layer_1_split[1],
-            _ => layer_1_split[0],
-        };
+    let pgsql_client = match database.client().await {
+        Ok(val) => val,
+        Err(err) => {
+            events.emit("loading", 0);
+            output.error(ErrorCode::Connection, format!("ERROR! {}", err));
+            return Ok(output);
+        }
+    };
 
-        let layer_2 = ast["args"][1];
-        let layer_2_split = layer_2.split(".").collect::<Vec<&str>>();
-        let short_layer_2 = match layer_2_split.len() {
-            2 => layer_2_split[1],
-            _ => layer_2_split[0],
-        };
+    let (out_schema, out_name) = match options.text("out") {
+        Some(out) => split_table_name(out),
+        None => ("public".to_string(), intersect_name(layer_1, layer_2)),
+    };
 
-        let mut pgsql_client = match Client::connect(&state.pgsql_connection.pg_string(), NoTls) {
-            Ok(val) => val,
-            Err(_) => panic!("ERROR! Lost connection to the database."),
-        };
+    events.emit("loading", 70);
+    match pgsql_client.batch_execute(
+        create_table_query(
+            format!("{}.{}", out_schema, out_name).as_str(),
+            format!(
+                "CREATE TABLE {}.{} AS {}{}",
+                out_schema,
+                out_name,
+                ctes.unwrap_or_default(),
+                intersect_query(layer_1, layer_2)
+            ),
+            options.boolean("overwrite"),
+        )
+        .as_str(),
+    ).await {
+        Ok(_) => {
+            events.emit("loading", 90);
+            events.emit("add-vector-layer", [&out_name, &out_schema]);
+            output.message("Done.");
+            output.results.push(OutputResult::Layer {
+                schema: out_schema,
+                name: out_name,
+            });
+        },
+        Err(err) => output.error(ErrorCode::Query, format!("ERROR! Couldn't create intersection: {}", err))
+    };
 
-        let _ = state.app_handle.emit("loading", 70);
-        match pgsql_client.execute(
-            format!("CREATE TABLE IF NOT EXISTS public.{}_{}_intersect AS SELECT ST_Intersection({}.geom, {}.geom) AS geom FROM {}, {}", short_layer_1, short_layer_2, layer_1, layer_2, layer_1, layer_2).as_str(),
-            &[]
-        ) {
-            Ok(_) => {
-                let _ = state.app_handle.emit("loading", 90);
-                let _ = state.app_handle.emit("add-vector-layer", [format!("{}_{}_intersect", short_layer_1, short_layer_2), "public".to_string()]);
-                output.results.push("Done.".to_string());
-            },
-            Err(err) => output.errors.push(format!("ERROR! Couldn't create intersection: {}", err))
-        };
+    events.emit("loading", 0);
+    Ok(output)
+}
+
+pub const BUFFER_OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "out",
+        kind: OptionKind::Text,
+        default: None,
+        description: "Table to write the buffer to. Defaults to public.<layer>_buffer.",
+    },
+    OptionSpec {
+        name: "quad_segs",
+        kind: OptionKind::Integer,
+        default: Some("8"),
+        description: "Number of segments used to approximate a quarter circle.",
+    },
+    OptionSpec {
+        name: "overwrite",
+        kind: OptionKind::Boolean,
+        default: Some("false"),
+        description: "Replace the output table if it already exists.",
+    },
+];
 
-        let _ = state.app_handle.emit("loading", 0);
+pub const INTERSECT_OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "out",
+        kind: OptionKind::Text,
+        default: None,
+        description:
+            "Table to write the intersection to. Defaults to public.<layer>_<layer>_intersect.",
+    },
+    OptionSpec {
+        name: "overwrite",
+        kind: OptionKind::Boolean,
+        default: Some("false"),
+        description: "Replace the output table if it already exists.",
+    },
+];
+
+static BUFFER_SCHEMA: CommandSchema = CommandSchema {
+    name: "buffer",
+    summary: "Buffer every geometry of a layer into a new table.",
+    args: &[
+        ArgSpec {
+            name: "layer",
+            kind: ArgKind::Layer,
+            required: true,
+            description: "Layer to buffer.",
+        },
+        ArgSpec {
+            name: "distance",
+            kind: ArgKind::Number,
+            required: true,
+            description: "Buffer distance, in the units of the layer's SRS.",
+        },
+    ],
+    options: BUFFER_OPTIONS,
+    subcommands: &[],
+    examples: &[
+        "buffer public.rivers 100",
+        "buffer rivers 0.5 ? out=public.floodplain overwrite=true",
+    ],
+};
+
+pub struct BufferCommand;
+
+impl Command for BufferCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &BUFFER_SCHEMA
     }
 
-    Ok(output)
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            Ok(buffer(
+                args.text("layer")?,
+                args.number("distance")?,
+                &args.options,
+                args.ctes.as_deref(),
+                &ctx.state,
+            )
+            .await
+            .unwrap())
+        }
+        .boxed()
+    }
+
+    fn reads_pipelines(&self) -> bool {
+        true
+    }
+
+    fn query(&self, args: &Arguments) -> Result<Option<Query>, ArgError> {
+        Ok(Some(Query {
+            name: buffer_name(args.text("layer")?),
+            sql: buffer_query(
+                args.text("layer")?,
+                args.number("distance")?,
+                args.options.integer("quad_segs").unwrap_or(8),
+            ),
+        }))
+    }
+}
+
+static INTERSECT_SCHEMA: CommandSchema = CommandSchema {
+    name: "intersect",
+    summary: "Intersect two layers into a new table.",
+    args: &[
+        ArgSpec {
+            name: "layer_1",
+            kind: ArgKind::Layer,
+            required: true,
+            description: "First layer.",
+        },
+        ArgSpec {
+            name: "layer_2",
+            kind: ArgKind::Layer,
+            required: true,
+            description: "Second layer.",
+        },
+    ],
+    options: INTERSECT_OPTIONS,
+    subcommands: &[],
+    examples: &["intersect public.rivers public.parcels ? out=public.riverside_parcels"],
+};
+
+pub struct IntersectCommand;
+
+impl Command for IntersectCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &INTERSECT_SCHEMA
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            Ok(intersect(
+                args.text("layer_1")?,
+                args.text("layer_2")?,
+                &args.options,
+                args.ctes.as_deref(),
+                &ctx.state,
+            )
+            .await
+            .unwrap())
+        }
+        .boxed()
+    }
+
+    fn reads_pipelines(&self) -> bool {
+        true
+    }
+
+    fn query(&self, args: &Arguments) -> Result<Option<Query>, ArgError> {
+        let (layer_1, layer_2) = (args.text("layer_1")?, args.text("layer_2")?);
+        Ok(Some(Query {
+            name: intersect_name(layer_1, layer_2),
+            sql: intersect_query(layer_1, layer_2),
+        }))
+    }
+}
+
+static INSPECT_SCHEMA: CommandSchema = CommandSchema {
+    name: "inspect",
+    summary: "Show the attributes of a layer, or only of the features at a location.",
+    args: &[
+        ArgSpec {
+            name: "layer",
+            kind: ArgKind::Layer,
+            required: true,
+            description: "Layer to inspect.",
+        },
+        ArgSpec {
+            name: "location",
+            kind: ArgKind::Location,
+            required: false,
+            description: "Point to inspect, as `x, y`.",
+        },
+    ],
+    options: &[],
+    subcommands: &[],
+    examples: &["inspect public.rivers", "inspect public.rivers `4.35, 50.85`"],
+};
+
+pub struct InspectCommand;
+
+impl Command for InspectCommand {
+    fn schema(&self) -> &'static CommandSchema {
+        &INSPECT_SCHEMA
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a Context,
+        args: Arguments,
+    ) -> BoxFuture<'a, Result<Output, ArgError>> {
+        async move {
+            Ok(inspect(
+                args.text("layer")?,
+                args.location("location"),
+                args.ctes.as_deref(),
+                &ctx.state,
+            )
+            .await
+            .unwrap())
+        }
+        .boxed()
+    }
+
+    fn reads_pipelines(&self) -> bool {
+        true
+    }
 }
@@ -3,8 +3,9 @@ import { useState, useRef } from "react";
 import { invoke } from "@tauri-apps/api/core";
 import { listen } from "@tauri-apps/api/event";
 import { Output } from "./types/Output.type";
-import { addVectorLayer, removeAllVectorLayers } from "./map.slice";
-import { VectorLayer } from "./types/Layer.type";
+import { Candidate, Completions, commonPrefix } from "./types/Completion.type";
+import { addRasterLayer, addVectorLayer, removeAllVectorLayers } from "./map.slice";
+import { RasterLayer, VectorLayer } from "./types/Layer.type";
 import { useDispatch } from "react-redux";
 import Map from "./components/Map";
 import ControlBar from "./components/ControlBar";
@@ -18,9 +19,10 @@ function App() {
         <REPLHistoryItem cmd={"Welcome to TIGRE!"}
             output={{
                 errors: [],
-                results: ["Learn more about TIGRE here: https://geocml.github.io/docs/"]
+                results: [{ type: "message", text: "Learn more about TIGRE here: https://geocml.github.io/docs/" }]
             }} forceMessage={true} />
     ])
+    const [candidates, setCandidates] = useState<Candidate[]>([]);
     let replInput = useRef<HTMLTextAreaElement>(null);
     let replForm = useRef<HTMLFormElement>(null);
 
@@ -42,11 +44,41 @@ function App() {
         }));
     });
    
+    listen<string>('add-raster-layer', (event) => {
+        const layer: RasterLayer = {
+            name: event.payload[0],
+            schema: event.payload[1],
+        }
+
+        dispatch(addRasterLayer({
+            layer
+        }));
+    });
+
     listen<string>('wipe-layers', (event) => {
         if (event.payload)
             dispatch(removeAllVectorLayers());
     });
 
+    const complete = async () => {
+        const input = replInput.current!;
+        const completions = await invoke<Completions>("complete", {
+            partialCmd: input.value,
+            cursor: input.selectionStart,
+        });
+
+        const values = completions.candidates.map((candidate) => candidate.value);
+        let replacement = commonPrefix(values);
+        if (values.length === 1 && !replacement.endsWith("=") && !replacement.endsWith("/") && !replacement.endsWith("\\"))
+            replacement += " ";
+
+        if (replacement.length > 0) {
+            input.value = input.value.slice(0, completions.start) + replacement + input.value.slice(completions.end);
+            input.selectionStart = input.selectionEnd = completions.start + replacement.length;
+        }
+        setCandidates(values.length > 1 ? completions.candidates : []);
+    };
+
     return (
       <main>
         <div className="w-full h-[95vh] grid grid-cols-[14%_86%] grid-rows-1">
@@ -64,19 +96,36 @@ function App() {
             if (cmd === "clear") {
                 setHistory([]);
             } else {
-                const readResult = await invoke<Record<string, string[]>>("read", { cmd });
-                invoke<string>("eval", { ast: readResult }).then((result) => {
-                    const evalResult = JSON.parse(result) as Output;
-                    setHistory([...history, <REPLHistoryItem cmd={cmd} output={evalResult} forceMessage={false}/>]);
+                invoke<unknown>("read", { cmd }).then((readResult) => {
+                    invoke<string>("eval", { ast: readResult }).then((result) => {
+                        const evalResult = JSON.parse(result) as Output;
+                        setHistory([...history, <REPLHistoryItem cmd={cmd} output={evalResult} forceMessage={false}/>]);
+                    });
+                }).catch((err: string) => {
+                    setHistory([...history, <REPLHistoryItem cmd={cmd} output={{ errors: [{ code: "parse", severity: "error", message: err }], results: [] }} forceMessage={false}/>]);
                 });
             }
 
             replInput.current!.value = "";
             replInput.current!.innerText = "";
+            setCandidates([]);
         }}>
+        { candidates.length > 0 &&
+            <div id="repl-completions" className="flex flex-wrap gap-x-4 pl-1 bg-slate-950 text-slate-400 font-mono text-xs">
+                { candidates.map((candidate) =>
+                    <span key={candidate.value} title={candidate.description ?? ""}>{candidate.value}</span>
+                ) }
+            </div>
+        }
         <textarea id="repl-input" autoComplete="off" placeholder="Press Enter/Return to Execute REPL Command" autoFocus onKeyDown={(event) => {
-            if (event && event.key === "Enter")
+            if (event && event.key === "Enter") {
                 replForm.current!.requestSubmit();
+            } else if (event && event.key === "Tab") {
+                event.preventDefault();
+                complete();
+            } else if (candidates.length > 0) {
+                setCandidates([]);
+            }
         }} ref={replInput} className="font-mono border-solid border-t-2 border-slate-800 w-full h-[25px] pl-1 bg-slate-950 text-slate-500 resize-none text-sm focus:outline-none focus:border-2 focus:border-blue-500 rounded-md"></textarea>
             <input type="submit" style={{ display: "none" }} />
         </form>
@@ -6,6 +6,17 @@ import LayerPaneItem from "./LayerPaneItem";
 import L from "leaflet";
 import TableView from "./TableView";
 
+/** A table of the attributes of a cached feature, for its popup. */
+function featurePopup(feature: GeoJSON.Feature): HTMLElement {
+    const table = document.createElement("table");
+    for (const [key, value] of Object.entries(feature.properties ?? {})) {
+        const row = table.insertRow();
+        row.insertCell().textContent = key;
+        row.insertCell().textContent = value === null ? "" : String(value);
+    }
+    return table;
+}
+
 function Map() {
     let map = useRef<L.Map>(undefined);
     const [redrawing, setRedrawing] = useState(false);
@@ -24,7 +35,7 @@ function Map() {
         setFilterToolVisible(!filterToolVisible);
     }
 
-    listen<string>('open-table', (_event) => {
+    listen<[string, any]>('open-table', (_event) => {
         setTableViewVisible(true);
     });
 
@@ -42,7 +53,7 @@ function Map() {
 
         const geomPromises = []
 
-        if (Object.keys(vectorLayers).length === 0) {
+        if (Object.keys(vectorLayers).length === 0 && Object.keys(rasterLayers).length === 0) {
             setRedrawing(false);
             emit("loading", 0);
             return;
@@ -61,14 +72,39 @@ function Map() {
                 table: vectorLayers[lyr].layer.name,
                 schema: vectorLayers[lyr].layer.schema,
             }).then((result) => {
-                result.forEach((geom) => {
-                    L.geoJson(JSON.parse(geom), {
-                        style: vectorLayers[lyr].layer.symbology
+                result.forEach((feature) => {
+                    L.geoJson(JSON.parse(feature), {
+                        style: vectorLayers[lyr].layer.symbology,
+                        onEachFeature: (feature, layer) => {
+                            if (Object.keys(feature.properties ?? {}).length > 0)
+                                layer.bindPopup(featurePopup(feature));
+                        },
                     }).addTo(map.current!);
                 });
             }));
         }
 
+        // Rasters are rendered for the current view only, so they're redrawn whenever it changes
+        const bounds = map.current.getBounds();
+        const size = map.current.getSize();
+        for (const lyr of Object.keys(rasterLayers)) {
+            geomPromises.push(invoke<string>("get_raster_png", {
+                table: rasterLayers[lyr].layer.name,
+                schema: rasterLayers[lyr].layer.schema,
+                bb: [[bounds.getWest(), bounds.getSouth()], [bounds.getEast(), bounds.getNorth()]],
+                width: size.x,
+                height: size.y,
+            }).then((result) => {
+                const tile = JSON.parse(result);
+                if (tile)
+                    L.imageOverlay(`data:image/png;base64,${tile.png}`, tile.bounds).addTo(map.current!);
+            }));
+        }
+
+        map.current.off("moveend");
+        if (Object.keys(rasterLayers).length > 0)
+            map.current.once("moveend", () => setRedrawing(true));
+
         map.current.removeEventListener("click");
         map.current.on("click", (event) => {
             Object.keys(vectorLayers)
@@ -1,5 +1,5 @@
 import { useRef, useEffect, useState } from "react";
-import { Output } from "../types/Output.type";
+import { Output, formatResult } from "../types/Output.type";
 
 type REPLHistoryItemProps = {
     cmd: string,
@@ -12,7 +12,7 @@ function REPLHistoryItem(props: REPLHistoryItemProps) {
     const date = useRef(new Date());
 
     useEffect(() => {
-        if (props.output.errors && props.output.errors.length !== 0)
+        if (props.output.errors && props.output.errors.some((diagnostic) => diagnostic.severity === "error"))
             setError(true);
     })
 
@@ -21,9 +21,9 @@ function REPLHistoryItem(props: REPLHistoryItemProps) {
             <div>
                 <p className="italic text-xs">{props.cmd}</p>
                 { props.forceMessage ? (
-                    <p className="font-bold border-solid border-t-2 border-slate-600 mt-1">{ props.output.results as string[] }</p>
+                    <p className="font-bold border-solid border-t-2 border-slate-600 mt-1">{ props.output.results.map(formatResult) }</p>
                 ) : (
-                    <p className="font-bold border-solid border-t-2 border-slate-600 mt-2">{ error ? props.output.errors.join(" → ") : props.output.results.join(" → ") }</p>
+                    <p className={`font-bold border-solid border-t-2 border-slate-600 mt-2 whitespace-pre-wrap ${error ? "font-mono" : ""}`}>{ error ? props.output.errors.map((diagnostic) => diagnostic.message).join(" → ") : [...props.output.errors.map((diagnostic) => diagnostic.message), ...props.output.results.map(formatResult)].join(" → ") }</p>
                 ) }
             </div>
 
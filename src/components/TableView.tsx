@@ -17,8 +17,8 @@ function TableView({ visible }: TableViewProps) {
     const [y, setY] = useState(100);
     const [drag, setDrag] = useState(false);
 
-    listen<string>('open-table', (event) => {
-        const json = JSON.parse(JSON.parse(event.payload[1])); // event.payload is a double stringified JSON object
+    listen<[string, any]>('open-table', (event) => {
+        const json = event.payload[1];
 
         if (json["json_agg"]) {
             setTabs([{
@@ -0,0 +1,25 @@
+export type CompletionKind = "command" | "subcommand" | "layer" | "path" | "option"
+
+export type Candidate = {
+    value: string,
+    kind: CompletionKind,
+    description: string | null
+}
+
+export type Completions = {
+    start: number,
+    end: number,
+    candidates: Candidate[]
+}
+
+export function commonPrefix(values: string[]): string {
+    if (values.length === 0)
+        return "";
+
+    let prefix = values[0];
+    for (const value of values) {
+        while (!value.startsWith(prefix))
+            prefix = prefix.slice(0, -1);
+    }
+    return prefix;
+}
@@ -1,6 +1,34 @@
-import { VectorLayer } from "./Layer.type"
+export type Severity = "error" | "warning" | "info"
+
+export type Diagnostic = {
+    code: string,
+    severity: Severity,
+    message: string
+}
+
+export type OutputResult =
+    { type: "message", text: string } |
+    { type: "table", name: string, columns: string[], rows: unknown[][] } |
+    { type: "layer", schema: string, name: string } |
+    { type: "geo_json", geojson: unknown } |
+    { type: "stats", values: Record<string, unknown> }
 
 export type Output = {
-    errors: string[],
-    results: VectorLayer[] | string[]
+    errors: Diagnostic[],
+    results: OutputResult[]
+}
+
+export function formatResult(result: OutputResult): string {
+    switch (result.type) {
+        case "message":
+            return result.text;
+        case "table":
+            return `${result.name} (${result.rows.length} rows)`;
+        case "layer":
+            return `${result.schema}.${result.name}`;
+        case "geo_json":
+            return JSON.stringify(result.geojson);
+        case "stats":
+            return Object.entries(result.values).map(([key, value]) => `${key}: ${value}`).join(", ");
+    }
 }